@@ -60,9 +60,23 @@ fn scan_module_dir(dir: &Path, module_names: &mut Vec<String>, registerable: &mu
     }
 }
 
+/// Compiler version for the `proxycache_build_info` metric, e.g. `1.80.0 (051478957 2024-07-21)`
+fn rustc_version() -> String {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    std::process::Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|v| v.trim().trim_start_matches("rustc ").replace('"', ""))
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
+
 fn main() {
     println!("cargo:rerun-if-changed=src/modules");
     println!("cargo:rerun-if-changed=imports");
+    println!("cargo:rustc-env=PROXYCACHE_RUSTC_VERSION={}", rustc_version());
 
     let modules_dir = Path::new("src/modules");
     let imports_dir = Path::new("imports");
@@ -149,6 +163,10 @@ pub trait Module: Send + Sync {
     fn overrides(&self) -> &'static [&'static str] { &[] }
     fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse>;
     fn on_response(&self, _req: &HttpRequest, _resp: &mut HttpResponse, _ctx: &mut Context) {}
+    /// Needs the complete response body in on_response, so backend bodies must be buffered
+    fn reads_body(&self) -> bool { false }
+    /// Resolved settings of a script module (Rust modules read theirs from the module config)
+    fn script_config(&self) -> Option<&HashMap<String, String>> { None }
 }
 
 pub trait RawHandler: Send + Sync {
@@ -157,17 +175,25 @@ pub trait RawHandler: Send + Sync {
 
 fn default_priority(name: &str) -> i32 {
     match name {
+        "capture" => 5,
         "active_health" => 10,
+        "forwarded" => 15,
         "request_id" => 20,
+        "chaos" => 25,
         "rate_limiter" => 30,
         "circuit_breaker" => 40,
         "health_check" => 50,
         "metrics_exporter" => 60,
+        "require_headers" => 62,
+        "auth_request" => 65,
         "admin_api" => 70,
+        "trailing_slash" => 75,
         "cache" => 80,
+        "status_remap" => 85,
         "url_rewriter" => 90,
         "compression" => 100,
         "load_balancer" => 110,
+        "content_route" => 115,
         "proxy_core" => 120,
         "raw_tcp" => 130,
         _ => 75,
@@ -179,11 +205,13 @@ pub struct Pipeline {
     raw: Option<Box<dyn RawHandler>>,
     overridden: HashSet<String>,
     to: u64,
+    // Module config the pipeline was built from, kept for `/config/effective`
+    config: HashMap<String, toml::Value>,
 }
 
 impl Pipeline {
     pub fn new(t: u64) -> Self {
-        Pipeline { mods: Vec::new(), raw: None, overridden: HashSet::new(), to: t }
+        Pipeline { mods: Vec::new(), raw: None, overridden: HashSet::new(), to: t, config: HashMap::new() }
     }
     pub fn add(&mut self, m: Box<dyn Module>) {
         let p = default_priority(m.name());
@@ -221,11 +249,20 @@ impl Pipeline {
         self.mods.iter().any(|(_, m)| m.name() == name)
     }
     /// Get names of all loaded modules
-    #[allow(dead_code)]
     pub fn module_names(&self) -> Vec<String> {
         self.mods.iter().map(|(_, m)| m.name().to_string()).collect()
     }
+    /// Priority and name of each loaded module, in execution order once sorted
+    pub fn order(&self) -> Vec<(i32, String)> {
+        self.mods.iter().map(|(p, m)| (*p, m.name().to_string())).collect()
+    }
     pub fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> HttpResponse {
+        if c.get("_client_fingerprint").is_none() {
+            helpers::set_fingerprint(r, c);
+        }
+        if self.mods.iter().any(|(_, m)| m.reads_body()) {
+            c.set_bool("_body_hooks", true);
+        }
         let mut resp_idx = None;
         let mut resp = HttpResponse::error(500, "No handler");
         for (i, (_, m)) in self.mods.iter().enumerate() {
@@ -242,6 +279,17 @@ impl Pipeline {
         resp
     }
     pub fn timeout(&self) -> u64 { self.to }
+    /// Remember the module config this pipeline was built from
+    pub fn set_config(&mut self, mc: &HashMap<String, toml::Value>) {
+        self.config = mc.clone();
+    }
+    pub fn config(&self) -> &HashMap<String, toml::Value> {
+        &self.config
+    }
+    /// Resolved config of the named script module, if one is loaded
+    pub fn script_config(&self, name: &str) -> Option<&HashMap<String, String>> {
+        self.mods.iter().find(|(_, m)| m.name() == name).and_then(|(_, m)| m.script_config())
+    }
 }
 
 pub struct ModuleContext<'a> {
@@ -256,9 +304,9 @@ pub fn register_all(p: &mut Pipeline, mc: &HashMap<String, toml::Value>, sc: &Sr
 
     // Desired registration order: early middleware first, proxy_core/raw_tcp last
     let priority = [
-        "active_health", "request_id", "rate_limiter", "circuit_breaker",
-        "health_check", "metrics_exporter", "admin_api", "cache",
-        "url_rewriter", "compression", "load_balancer", "proxy_core", "raw_tcp",
+        "capture", "active_health", "forwarded", "request_id", "chaos", "rate_limiter", "circuit_breaker",
+        "health_check", "metrics_exporter", "require_headers", "auth_request", "admin_api", "trailing_slash", "cache",
+        "status_remap", "url_rewriter", "compression", "load_balancer", "content_route", "proxy_core", "raw_tcp",
     ];
 
     let mut ordered: Vec<&String> = Vec::new();
@@ -9,6 +9,8 @@ pub struct Config {
     pub server: Srv,
     #[serde(default)]
     pub modules: HashMap<String, toml::Value>,
+    #[serde(default)]
+    pub upstreams: HashMap<String, toml::Value>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -31,11 +33,36 @@ pub struct Srv {
     pub http2: bool,
     pub http3: bool,
     pub h3_port: u16,
+    pub dns_prefer: String,
+    pub dns_refresh_secs: u64,
+    pub pool_max_conn_lifetime_secs: u64,
+    pub abort_on_panic: bool,
+    pub accept_rate_limit: u64,
+    pub max_script_modules: usize,
+    pub body_timeout_408: bool,
+    pub first_byte_timeout: u64,
+    pub h2_max_inflight_streams: usize,
+    pub alpn_protocols: Vec<String>,
+    pub keep_alive_requests: usize,
+    pub max_request_duration_secs: u64,
+    pub normalize_method_case: bool,
+    pub min_free_memory_mb: u64,
+    pub keep_alive_idle_timeout: u64,
+    pub max_bytes_per_connection: u64,
+    pub log_format: String,
+    pub pre_drain_delay_secs: u64,
+    pub max_header_count: usize,
+    pub pool_idle_timeout_secs: u64,
+    pub pool_max_idle_per_backend: usize,
+    pub pool_max_total_idle: usize,
+    pub max_chunked_body_size: usize,
+    pub chunked_body_timeout_secs: u64,
+    pub strict_config: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Config { server: Srv::default(), modules: HashMap::new() }
+        Config { server: Srv::default(), modules: HashMap::new(), upstreams: HashMap::new() }
     }
 }
 
@@ -59,33 +86,79 @@ impl Default for Srv {
             http2: true,
             http3: false,
             h3_port: 0,
+            dns_prefer: "ipv4".to_string(),
+            dns_refresh_secs: 0,
+            pool_max_conn_lifetime_secs: 0,
+            abort_on_panic: false,
+            accept_rate_limit: 0,
+            max_script_modules: 32,
+            body_timeout_408: false,
+            first_byte_timeout: 0,
+            h2_max_inflight_streams: 100,
+            alpn_protocols: Vec::new(),
+            keep_alive_requests: 100,
+            max_request_duration_secs: 0,
+            normalize_method_case: false,
+            min_free_memory_mb: 0,
+            keep_alive_idle_timeout: 5,
+            max_bytes_per_connection: 0,
+            log_format: "text".to_string(),
+            pre_drain_delay_secs: 0,
+            max_header_count: 100,
+            pool_idle_timeout_secs: 30,
+            pool_max_idle_per_backend: 32,
+            pool_max_total_idle: 1024,
+            max_chunked_body_size: 0,
+            chunked_body_timeout_secs: 0,
+            strict_config: false,
         }
     }
 }
 
 impl Srv {
+    /// ALPN protocols to advertise: `alpn_protocols` if set, else derived from `http2`
+    pub fn alpn_list(&self) -> Vec<Vec<u8>> {
+        if !self.alpn_protocols.is_empty() {
+            return self.alpn_protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        }
+        if self.http2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        }
+    }
+
+    /// Lenient check: true when nothing is fatal (substitutions are applied)
+    #[allow(dead_code)]
     pub fn validate(&mut self) -> bool {
-        let mut valid = true;
+        self.check(false).is_empty()
+    }
+
+    /// Every configuration error found. Out-of-range values are replaced with safe
+    /// settings and logged, except in strict mode where they are errors too.
+    pub fn check(&mut self, strict: bool) -> Vec<String> {
+        let mut errors = Vec::new();
+        let fatal = |errors: &mut Vec<String>, msg: String| {
+            crate::log::error(&msg);
+            errors.push(msg);
+        };
 
         if self.listen_addr.parse::<std::net::SocketAddr>().is_err() {
-            crate::log::error(&format!("listen_addr '{}' is not a valid address (expected ip:port)", self.listen_addr));
-            valid = false;
+            fatal(&mut errors, format!("listen_addr '{}' is not a valid address (expected ip:port)", self.listen_addr));
         }
-        if self.backend_addr.parse::<std::net::SocketAddr>().is_err() {
-            crate::log::error(&format!("backend_addr '{}' is not a valid address (expected ip:port)", self.backend_addr));
-            valid = false;
+        if !crate::resolve::is_valid_addr(&self.backend_addr) {
+            fatal(&mut errors, format!("backend_addr '{}' is not a valid address (expected host:port)", self.backend_addr));
         }
 
-        if self.buffer_size < 1024 {
-            crate::log::warn(&format!("buffer_size too small ({}), using 1024", self.buffer_size));
+        if self.buffer_size < 1024
+            && fix_up(&mut errors, strict, format!("buffer_size too small ({})", self.buffer_size), "using 1024")
+        {
             self.buffer_size = 1024;
         }
-        if self.client_timeout == 0 {
-            crate::log::warn("client_timeout is 0, using 30");
+        if self.client_timeout == 0 && fix_up(&mut errors, strict, "client_timeout is 0".into(), "using 30") {
             self.client_timeout = 30;
         }
-        if self.backend_timeout == 0 {
-            crate::log::warn("backend_timeout is 0, using 30");
+        if self.backend_timeout == 0 && fix_up(&mut errors, strict, "backend_timeout is 0".into(), "using 30") {
             self.backend_timeout = 30;
         }
         if self.client_timeout < self.backend_timeout {
@@ -100,37 +173,79 @@ impl Srv {
         if self.max_header_size == 0 {
             self.max_header_size = 65_536;
         }
+        if self.max_header_count == 0 {
+            self.max_header_count = 100;
+        }
+        if self.pool_idle_timeout_secs == 0
+            && fix_up(&mut errors, strict, "pool_idle_timeout_secs is 0".into(), "using 30")
+        {
+            self.pool_idle_timeout_secs = 30;
+        }
+        if self.pool_max_idle_per_backend == 0 {
+            self.pool_max_idle_per_backend = 32;
+        }
+        if self.pool_max_total_idle < self.pool_max_idle_per_backend
+            && fix_up(
+                &mut errors,
+                strict,
+                format!(
+                    "pool_max_total_idle ({}) < pool_max_idle_per_backend ({})",
+                    self.pool_max_total_idle, self.pool_max_idle_per_backend
+                ),
+                &format!("using {}", self.pool_max_idle_per_backend),
+            )
+        {
+            self.pool_max_total_idle = self.pool_max_idle_per_backend;
+        }
         if self.max_connections == 0 {
             self.max_connections = 10_000;
         }
         if self.shutdown_timeout == 0 {
             self.shutdown_timeout = 15;
         }
+        self.alpn_protocols.retain(|p| {
+            if !p.is_empty() && p.len() <= 255 && p.bytes().all(|b| b.is_ascii_graphic()) {
+                return true;
+            }
+            !fix_up(&mut errors, strict, format!("alpn_protocols entry '{p}' is invalid"), "ignoring it")
+        });
+        if !self.http2 && self.alpn_protocols.iter().any(|p| p == "h2") {
+            crate::log::warn("alpn_protocols lists h2 but http2 is disabled, not advertising it");
+            self.alpn_protocols.retain(|p| p != "h2");
+        }
         if self.max_connections > 100_000 {
             crate::log::warn(&format!("max_connections very high ({}), may exhaust file descriptors", self.max_connections));
         }
 
         if !self.tls_cert.is_empty() || !self.tls_key.is_empty() {
             if self.tls_cert.is_empty() {
-                crate::log::error("tls_key is set but tls_cert is missing");
-                valid = false;
+                fatal(&mut errors, "tls_key is set but tls_cert is missing".into());
             } else if self.tls_key.is_empty() {
-                crate::log::error("tls_cert is set but tls_key is missing");
-                valid = false;
+                fatal(&mut errors, "tls_cert is set but tls_key is missing".into());
             } else {
                 if !std::path::Path::new(&self.tls_cert).exists() {
-                    crate::log::error(&format!("tls_cert file not found: {}", self.tls_cert));
-                    valid = false;
+                    fatal(&mut errors, format!("tls_cert file not found: {}", self.tls_cert));
                 }
                 if !std::path::Path::new(&self.tls_key).exists() {
-                    crate::log::error(&format!("tls_key file not found: {}", self.tls_key));
-                    valid = false;
+                    fatal(&mut errors, format!("tls_key file not found: {}", self.tls_key));
                 }
             }
         }
 
-        valid
+        errors
+    }
+}
+
+/// In strict mode `problem` is recorded as an error and the value is left alone;
+/// otherwise it is logged with `fallback` and true tells the caller to substitute
+fn fix_up(errors: &mut Vec<String>, strict: bool, problem: String, fallback: &str) -> bool {
+    if strict {
+        crate::log::error(&problem);
+        errors.push(problem);
+        return false;
     }
+    crate::log::warn(&format!("{problem}, {fallback}"));
+    true
 }
 
 fn atomic_write(path: &str, content: &str) -> std::io::Result<()> {
@@ -140,14 +255,17 @@ fn atomic_write(path: &str, content: &str) -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn load_config(module_defaults: &HashMap<String, toml::Value>) -> Config {
+/// Load and validate the config. Invalid fields fall back to safe defaults, unless strict
+/// (`--strict` or `strict_config`) where any validation failure is an error instead.
+pub fn load_config(module_defaults: &HashMap<String, toml::Value>) -> Result<Config, String> {
     let p = path();
     let mut cfg = match fs::read_to_string(&p) {
-        Ok(txt) => match toml::from_str(&txt) {
+        Ok(txt) => match toml::from_str::<Config>(&txt) {
             Ok(c) => {
                 crate::log::info(&format!("Loaded {p}"));
                 c
             }
+            Err(e) if strict_flag() => return Err(format!("Parse error {p}: {e}")),
             Err(e) => {
                 crate::log::error(&format!("Parse error {p}: {e}"));
                 crate::log::warn("Using defaults");
@@ -166,28 +284,8 @@ pub fn load_config(module_defaults: &HashMap<String, toml::Value>) -> Config {
             cfg
         }
     };
-    if !cfg.server.validate() {
-        crate::log::error("Fatal configuration errors — falling back to safe defaults for invalid fields");
-        if cfg.server.listen_addr.parse::<std::net::SocketAddr>().is_err() {
-            let fallback = "127.0.0.1:3000";
-            crate::log::warn(&format!("listen_addr invalid, using {fallback}"));
-            cfg.server.listen_addr = fallback.to_string();
-        }
-        if cfg.server.backend_addr.parse::<std::net::SocketAddr>().is_err() {
-            let fallback = "127.0.0.1:8080";
-            crate::log::warn(&format!("backend_addr invalid, using {fallback}"));
-            cfg.server.backend_addr = fallback.to_string();
-        }
-        if !cfg.server.tls_cert.is_empty() || !cfg.server.tls_key.is_empty() {
-            let cert_ok = !cfg.server.tls_cert.is_empty() && std::path::Path::new(&cfg.server.tls_cert).exists();
-            let key_ok = !cfg.server.tls_key.is_empty() && std::path::Path::new(&cfg.server.tls_key).exists();
-            if !cert_ok || !key_ok {
-                crate::log::warn("TLS config invalid, disabling TLS");
-                cfg.server.tls_cert.clear();
-                cfg.server.tls_key.clear();
-            }
-        }
-    }
+    let strict = cfg.server.strict_config || strict_flag();
+    settle(&mut cfg.server, strict)?;
     let mut changed = false;
     for (name, value) in module_defaults {
         cfg.modules.entry(name.clone()).or_insert_with(|| {
@@ -203,7 +301,54 @@ pub fn load_config(module_defaults: &HashMap<String, toml::Value>) -> Config {
             crate::log::info("Config updated with new module defaults");
         }
     }
-    cfg
+    Ok(cfg)
+}
+
+/// Re-read config.toml for a live reload: strict parsing and validation, nothing written back.
+/// Module defaults are only filled in memory, so a typo can never overwrite the file.
+pub fn reload_config(p: &str, module_defaults: &HashMap<String, toml::Value>) -> Result<Config, String> {
+    let txt = fs::read_to_string(p).map_err(|e| format!("Cannot read {p}: {e}"))?;
+    let mut cfg = toml::from_str::<Config>(&txt).map_err(|e| format!("Parse error {p}: {e}"))?;
+    settle(&mut cfg.server, true)?;
+    for (name, value) in module_defaults {
+        cfg.modules.entry(name.clone()).or_insert_with(|| value.clone());
+    }
+    Ok(cfg)
+}
+
+/// Validate `srv`, substituting safe defaults for invalid fields; with `strict`, fail instead
+pub fn settle(srv: &mut Srv, strict: bool) -> Result<(), String> {
+    let errors = srv.check(strict);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    if strict {
+        return Err(format!(
+            "Configuration errors (strict mode, not substituting defaults): {}",
+            errors.join("; ")
+        ));
+    }
+    crate::log::error("Fatal configuration errors — falling back to safe defaults for invalid fields");
+    if srv.listen_addr.parse::<std::net::SocketAddr>().is_err() {
+        let fallback = "127.0.0.1:3000";
+        crate::log::warn(&format!("listen_addr invalid, using {fallback}"));
+        srv.listen_addr = fallback.to_string();
+    }
+    if !crate::resolve::is_valid_addr(&srv.backend_addr) {
+        let fallback = "127.0.0.1:8080";
+        crate::log::warn(&format!("backend_addr invalid, using {fallback}"));
+        srv.backend_addr = fallback.to_string();
+    }
+    if !srv.tls_cert.is_empty() || !srv.tls_key.is_empty() {
+        let cert_ok = !srv.tls_cert.is_empty() && std::path::Path::new(&srv.tls_cert).exists();
+        let key_ok = !srv.tls_key.is_empty() && std::path::Path::new(&srv.tls_key).exists();
+        if !cert_ok || !key_ok {
+            crate::log::warn("TLS config invalid, disabling TLS");
+            srv.tls_cert.clear();
+            srv.tls_key.clear();
+        }
+    }
+    Ok(())
 }
 
 fn generate_config(cfg: &Config) -> String {
@@ -226,12 +371,43 @@ fn generate_config(cfg: &Config) -> String {
     srv.insert("http2".into(), toml::Value::Boolean(cfg.server.http2));
     srv.insert("http3".into(), toml::Value::Boolean(cfg.server.http3));
     srv.insert("h3_port".into(), toml::Value::Integer(cfg.server.h3_port as i64));
+    srv.insert("dns_prefer".into(), toml::Value::String(cfg.server.dns_prefer.clone()));
+    srv.insert("dns_refresh_secs".into(), toml::Value::Integer(cfg.server.dns_refresh_secs as i64));
+    srv.insert("pool_max_conn_lifetime_secs".into(), toml::Value::Integer(cfg.server.pool_max_conn_lifetime_secs as i64));
+    srv.insert("abort_on_panic".into(), toml::Value::Boolean(cfg.server.abort_on_panic));
+    srv.insert("accept_rate_limit".into(), toml::Value::Integer(cfg.server.accept_rate_limit as i64));
+    srv.insert("max_script_modules".into(), toml::Value::Integer(cfg.server.max_script_modules as i64));
+    srv.insert("body_timeout_408".into(), toml::Value::Boolean(cfg.server.body_timeout_408));
+    srv.insert("first_byte_timeout".into(), toml::Value::Integer(cfg.server.first_byte_timeout as i64));
+    srv.insert("h2_max_inflight_streams".into(), toml::Value::Integer(cfg.server.h2_max_inflight_streams as i64));
+    srv.insert("alpn_protocols".into(), toml::Value::Array(
+        cfg.server.alpn_protocols.iter().map(|p| toml::Value::String(p.clone())).collect()
+    ));
+    srv.insert("keep_alive_requests".into(), toml::Value::Integer(cfg.server.keep_alive_requests as i64));
+    srv.insert("max_request_duration_secs".into(), toml::Value::Integer(cfg.server.max_request_duration_secs as i64));
+    srv.insert("normalize_method_case".into(), toml::Value::Boolean(cfg.server.normalize_method_case));
+    srv.insert("min_free_memory_mb".into(), toml::Value::Integer(cfg.server.min_free_memory_mb as i64));
+    srv.insert("keep_alive_idle_timeout".into(), toml::Value::Integer(cfg.server.keep_alive_idle_timeout as i64));
+    srv.insert("max_bytes_per_connection".into(), toml::Value::Integer(cfg.server.max_bytes_per_connection as i64));
+    srv.insert("log_format".into(), toml::Value::String(cfg.server.log_format.clone()));
+    srv.insert("pre_drain_delay_secs".into(), toml::Value::Integer(cfg.server.pre_drain_delay_secs as i64));
+    srv.insert("max_header_count".into(), toml::Value::Integer(cfg.server.max_header_count as i64));
+    srv.insert("pool_idle_timeout_secs".into(), toml::Value::Integer(cfg.server.pool_idle_timeout_secs as i64));
+    srv.insert("pool_max_idle_per_backend".into(), toml::Value::Integer(cfg.server.pool_max_idle_per_backend as i64));
+    srv.insert("pool_max_total_idle".into(), toml::Value::Integer(cfg.server.pool_max_total_idle as i64));
+    srv.insert("max_chunked_body_size".into(), toml::Value::Integer(cfg.server.max_chunked_body_size as i64));
+    srv.insert("chunked_body_timeout_secs".into(), toml::Value::Integer(cfg.server.chunked_body_timeout_secs as i64));
+    srv.insert("strict_config".into(), toml::Value::Boolean(cfg.server.strict_config));
     doc.insert("server".into(), toml::Value::Table(srv));
     let mut mods = toml::Table::new();
     for (name, value) in &cfg.modules {
         mods.insert(name.clone(), value.clone());
     }
     doc.insert("modules".into(), toml::Value::Table(mods));
+    if !cfg.upstreams.is_empty() {
+        let ups: toml::Table = cfg.upstreams.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        doc.insert("upstreams".into(), toml::Value::Table(ups));
+    }
     match toml::to_string_pretty(&doc) {
         Ok(s) => s,
         Err(e) => {
@@ -241,7 +417,45 @@ fn generate_config(cfg: &Config) -> String {
     }
 }
 
-fn path() -> String {
+/// Re-read a config file and list structural problems. Err if it cannot be read or parsed.
+pub fn verify_file(path: &str) -> Result<Vec<String>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Cannot read {path}: {e}"))?;
+    let table = content.parse::<toml::Table>().map_err(|e| format!("Parse error: {e}"))?;
+    let mut issues: Vec<String> = Vec::new();
+
+    // Check [server] section
+    if !table.contains_key("server") {
+        issues.push("missing [server] section".to_string());
+    } else if let Some(srv) = table.get("server").and_then(|v| v.as_table()) {
+        for key in &["listen_addr", "backend_addr"] {
+            if !srv.contains_key(*key) {
+                issues.push(format!("server.{} missing", key));
+            }
+        }
+    }
+
+    // Check [modules] section exists
+    if !table.contains_key("modules") {
+        issues.push("missing [modules] section".to_string());
+    }
+
+    // Check script module configs
+    let script_mods = crate::script::stdlib::list_loaded_mods();
+    if let Some(modules) = table.get("modules").and_then(|v| v.as_table()) {
+        for (name, _, _) in &script_mods {
+            if !modules.contains_key(name) {
+                issues.push(format!("missing config for script module '{}'", name));
+            }
+        }
+    }
+    Ok(issues)
+}
+
+fn strict_flag() -> bool {
+    std::env::args().any(|a| a == "--strict")
+}
+
+pub(crate) fn path() -> String {
     let args: Vec<String> = std::env::args().collect();
     args.windows(2)
         .find(|w| w[0] == "--config")
@@ -36,6 +36,33 @@ impl Context {
         self.state.get(k).and_then(|v| v.downcast_ref::<T>())
     }
 
+    /// Independent copy for a mirrored or hedged request: string values only, with a
+    /// fresh start time. Typed state (backend streams, flags) stays with the original.
+    pub fn clone_for_subrequest(&self) -> Context {
+        Context {
+            strings: self.strings.clone(),
+            state: HashMap::new(),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Flags read as false when unset
+    pub fn set_bool(&mut self, k: &str, v: bool) {
+        self.put(k, v);
+    }
+
+    pub fn get_bool(&self, k: &str) -> bool {
+        self.take::<bool>(k).copied().unwrap_or(false)
+    }
+
+    pub fn set_u64(&mut self, k: &str, v: u64) {
+        self.put(k, v);
+    }
+
+    pub fn get_u64(&self, k: &str) -> Option<u64> {
+        self.take::<u64>(k).copied()
+    }
+
     pub fn elapsed_ms(&self) -> u128 {
         self.started_at.elapsed().as_millis()
     }
@@ -2,76 +2,108 @@
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
 use crate::modules::Pipeline;
+use crate::server::TlsInfo;
 use bytes::Bytes;
 use h2::server;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-/// Handle one HTTP/2 connection (may carry many streams).
+/// Handle one HTTP/2 connection (may carry many streams). At most `max_inflight`
+/// streams are processed at once; excess streams are refused (0 = unlimited).
+/// `normalize_method` uppercases request methods, as `normalize_method_case` does for h1;
+/// `abort_on_panic` aborts the process after a handler panic is logged.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_connection<S>(
     io: S,
     pipeline: Arc<Pipeline>,
     peer_ip: String,
     alt_svc: Option<String>,
+    max_inflight: usize,
+    normalize_method: bool,
+    abort_on_panic: bool,
+    tls: Option<TlsInfo>,
 ) where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     let mut conn = match server::handshake(io).await {
         Ok(c) => c,
         Err(e) => {
-            crate::log::error(&format!("h2: handshake failed: {e}"));
+            crate::log::error(&format!("h2: handshake with {peer_ip} failed: {e}"));
             return;
         }
     };
 
+    // Each stream holds a blocking worker, so bound them per connection
+    let inflight = Arc::new(AtomicUsize::new(0));
     while let Some(result) = conn.accept().await {
-        let (request, respond) = match result {
+        let (request, mut respond) = match result {
             Ok(pair) => pair,
             Err(e) => {
                 if !e.is_go_away() {
-                    crate::log::warn(&format!("h2: stream error: {e}"));
+                    crate::log::warn(&format!("h2: connection error from {peer_ip}: {e}"));
                 }
                 break;
             }
         };
 
+        if max_inflight > 0 && inflight.load(Ordering::Acquire) >= max_inflight {
+            crate::log::debug(&format!("h2: {peer_ip} exceeded {max_inflight} in-flight streams, refusing"));
+            respond.send_reset(h2::Reason::REFUSED_STREAM);
+            continue;
+        }
+        inflight.fetch_add(1, Ordering::AcqRel);
+
         let pipe = Arc::clone(&pipeline);
         let ip = peer_ip.clone();
         let alt = alt_svc.clone();
+        let count = Arc::clone(&inflight);
+        let tls = tls.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_stream(request, respond, pipe, ip, alt).await {
+            if let Err(e) = handle_stream(request, respond, pipe, ip, alt, normalize_method, abort_on_panic, tls).await {
                 crate::log::debug(&format!("h2: stream error: {e}"));
             }
+            count.fetch_sub(1, Ordering::AcqRel);
         });
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_stream(
     request: http::Request<h2::RecvStream>,
     mut respond: server::SendResponse<Bytes>,
     pipeline: Arc<Pipeline>,
     peer_ip: String,
     alt_svc: Option<String>,
+    normalize_method: bool,
+    abort_on_panic: bool,
+    tls: Option<TlsInfo>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let (parts, mut body_stream) = request.into_parts();
 
+    let mut headers = Vec::new();
+    for (name, value) in parts.headers.iter() {
+        if let Ok(v) = value.to_str() {
+            headers.push((name.to_string(), v.to_string()));
+        }
+    }
+    // :authority stands in for Host; HTTP/1.1 backends need the header
+    if let Some(auth) = parts.uri.authority() {
+        if !headers.iter().any(|(k, _)| k == "host") {
+            headers.push(("host".to_string(), auth.to_string()));
+        }
+    }
+    if let Some(resp) = crate::http::check_request_limits(&headers) {
+        return reject(&mut respond, resp);
+    }
+
     let mut body = Vec::new();
     while let Some(chunk) = body_stream.data().await {
         let data = chunk?;
         let _ = body_stream.flow_control().release_capacity(data.len());
         body.extend_from_slice(&data);
-        if body.len() > crate::http::MAX_BODY_SIZE {
-            let resp = http::Response::builder().status(413).body(()).unwrap();
-            let mut send = respond.send_response(resp, false)?;
-            send.send_data(Bytes::from_static(b"Payload Too Large"), true)?;
-            return Ok(());
-        }
-    }
-
-    let mut headers = Vec::new();
-    for (name, value) in parts.headers.iter() {
-        if let Ok(v) = value.to_str() {
-            headers.push((name.to_string(), v.to_string()));
+        if body.len() > crate::http::max_body_size() {
+            return reject(&mut respond, HttpResponse::error(413, "Payload Too Large"));
         }
     }
     let path = parts
@@ -79,7 +111,8 @@ async fn handle_stream(
         .path_and_query()
         .map(|pq| pq.to_string())
         .unwrap_or_else(|| "/".to_string());
-    let method = parts.method.to_string();
+    let mut method = parts.method.to_string();
+    if normalize_method { method.make_ascii_uppercase(); }
 
     crate::metrics::inc_requests();
     crate::metrics::add_bytes_in(body.len() as u64);
@@ -98,10 +131,18 @@ async fn handle_stream(
         let mut ctx = Context::new();
         ctx.set("_client_ip", peer_ip);
         ctx.set("_protocol", "h2".to_string());
+        if let Some(info) = &tls {
+            info.apply(&mut ctx);
+        }
         pipeline.handle(&mut r, &mut ctx)
     })
     .await
-    .unwrap_or_else(|_| HttpResponse::error(500, "Internal error"));
+    .unwrap_or_else(|e| {
+        if e.is_panic() {
+            crate::server::report_panic(e.into_panic(), abort_on_panic);
+        }
+        HttpResponse::error(500, "Internal error")
+    });
 
     crate::log::response(resp.status_code, 0, false);
     if resp.status_code < 400 {
@@ -120,6 +161,7 @@ async fn handle_stream(
     let h2_resp = builder.body(()).unwrap();
 
     let is_empty = resp.body.is_empty();
+    crate::metrics::record_response_size(resp.body.len() as u64);
     let mut send = respond.send_response(h2_resp, is_empty)?;
     if !is_empty {
         crate::metrics::add_bytes_out(resp.body.len() as u64);
@@ -127,3 +169,20 @@ async fn handle_stream(
     }
     Ok(())
 }
+
+/// Answer a request refused by the size limits; only the status and text body carry over,
+/// since h2 forbids connection-specific headers like `Connection: close`
+fn reject(
+    respond: &mut server::SendResponse<Bytes>,
+    resp: HttpResponse,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    crate::metrics::inc_requests_err();
+    let head = http::Response::builder()
+        .status(resp.status_code)
+        .header("content-type", "text/plain")
+        .body(())
+        .unwrap();
+    let mut send = respond.send_response(head, false)?;
+    send.send_data(Bytes::from(resp.body), true)?;
+    Ok(())
+}
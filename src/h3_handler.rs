@@ -2,11 +2,18 @@
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
 use crate::modules::Pipeline;
+use crate::server::PipelineHandle;
 use bytes::{Buf, Bytes};
 use std::sync::Arc;
 
-/// Run the HTTP/3 QUIC endpoint accept loop.
-pub async fn run_h3_server(endpoint: quinn::Endpoint, pipeline: Arc<Pipeline>) {
+/// Run the HTTP/3 QUIC endpoint accept loop. `normalize_method` uppercases request methods;
+/// `abort_on_panic` aborts the process after a handler panic is logged.
+pub async fn run_h3_server(
+    endpoint: quinn::Endpoint,
+    pipeline: PipelineHandle,
+    normalize_method: bool,
+    abort_on_panic: bool,
+) {
     let local = endpoint.local_addr().map(|a| a.to_string()).unwrap_or_default();
     crate::log::info(&format!("HTTP/3 (QUIC) listening on {local}"));
 
@@ -18,9 +25,9 @@ pub async fn run_h3_server(endpoint: quinn::Endpoint, pipeline: Arc<Pipeline>) {
             Some(conn) => conn,
             None => break,
         };
-        let pipe = Arc::clone(&pipeline);
+        let pipe = pipeline.load();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(incoming, pipe).await {
+            if let Err(e) = handle_connection(incoming, pipe, normalize_method, abort_on_panic).await {
                 crate::log::debug(&format!("h3: connection error: {e}"));
             }
         });
@@ -32,6 +39,8 @@ pub async fn run_h3_server(endpoint: quinn::Endpoint, pipeline: Arc<Pipeline>) {
 async fn handle_connection(
     incoming: quinn::Incoming,
     pipeline: Arc<Pipeline>,
+    normalize_method: bool,
+    abort_on_panic: bool,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let conn = incoming.await?;
     let peer_ip = conn.remote_address().ip().to_string();
@@ -47,7 +56,7 @@ async fn handle_connection(
                 tokio::spawn(async move {
                     match resolver.resolve_request().await {
                         Ok((req, stream)) => {
-                            if let Err(e) = handle_request(req, stream, pipe, ip).await {
+                            if let Err(e) = handle_request(req, stream, pipe, ip, normalize_method, abort_on_panic).await {
                                 crate::log::debug(&format!("h3: request error: {e}"));
                             }
                         }
@@ -72,19 +81,9 @@ async fn handle_request(
     mut stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
     pipeline: Arc<Pipeline>,
     peer_ip: String,
+    normalize_method: bool,
+    abort_on_panic: bool,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut body = Vec::new();
-    while let Some(data) = stream.recv_data().await? {
-        body.extend_from_slice(data.chunk());
-        if body.len() > crate::http::MAX_BODY_SIZE {
-            let resp = http::Response::builder().status(413).body(()).unwrap();
-            stream.send_response(resp).await?;
-            stream.send_data(Bytes::from_static(b"Payload Too Large")).await?;
-            stream.finish().await?;
-            return Ok(());
-        }
-    }
-
     let parts = request.into_parts().0;
     let mut headers = Vec::new();
     for (name, value) in parts.headers.iter() {
@@ -92,12 +91,30 @@ async fn handle_request(
             headers.push((name.to_string(), v.to_string()));
         }
     }
+    // :authority stands in for Host; HTTP/1.1 backends need the header
+    if let Some(auth) = parts.uri.authority() {
+        if !headers.iter().any(|(k, _)| k == "host") {
+            headers.push(("host".to_string(), auth.to_string()));
+        }
+    }
+    if let Some(resp) = crate::http::check_request_limits(&headers) {
+        return reject(&mut stream, resp).await;
+    }
+
+    let mut body = Vec::new();
+    while let Some(data) = stream.recv_data().await? {
+        body.extend_from_slice(data.chunk());
+        if body.len() > crate::http::max_body_size() {
+            return reject(&mut stream, HttpResponse::error(413, "Payload Too Large")).await;
+        }
+    }
     let path = parts
         .uri
         .path_and_query()
         .map(|pq| pq.to_string())
         .unwrap_or_else(|| "/".to_string());
-    let method = parts.method.to_string();
+    let mut method = parts.method.to_string();
+    if normalize_method { method.make_ascii_uppercase(); }
 
     crate::metrics::inc_requests();
     crate::metrics::add_bytes_in(body.len() as u64);
@@ -116,10 +133,17 @@ async fn handle_request(
         let mut ctx = Context::new();
         ctx.set("_client_ip", peer_ip);
         ctx.set("_protocol", "h3".to_string());
+        // QUIC always runs TLS 1.3
+        ctx.set("_tls_version", "TLSv1.3".to_string());
         pipeline.handle(&mut r, &mut ctx)
     })
     .await
-    .unwrap_or_else(|_| HttpResponse::error(500, "Internal error"));
+    .unwrap_or_else(|e| {
+        if e.is_panic() {
+            crate::server::report_panic(e.into_panic(), abort_on_panic);
+        }
+        HttpResponse::error(500, "Internal error")
+    });
 
     crate::log::response(resp.status_code, 0, false);
     if resp.status_code < 400 {
@@ -135,6 +159,7 @@ async fn handle_request(
     let h3_resp = builder.body(()).unwrap();
 
     stream.send_response(h3_resp).await?;
+    crate::metrics::record_response_size(resp.body.len() as u64);
     if !resp.body.is_empty() {
         crate::metrics::add_bytes_out(resp.body.len() as u64);
         stream.send_data(Bytes::from(resp.body)).await?;
@@ -142,3 +167,20 @@ async fn handle_request(
     stream.finish().await?;
     Ok(())
 }
+
+/// Answer a request refused by the size limits with its status and text body
+async fn reject(
+    stream: &mut h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    resp: HttpResponse,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    crate::metrics::inc_requests_err();
+    let head = http::Response::builder()
+        .status(resp.status_code)
+        .header("content-type", "text/plain")
+        .body(())
+        .unwrap();
+    stream.send_response(head).await?;
+    stream.send_data(Bytes::from(resp.body)).await?;
+    stream.finish().await?;
+    Ok(())
+}
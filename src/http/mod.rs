@@ -2,11 +2,54 @@
 mod request;
 mod response;
 pub use request::HttpRequest;
-pub use response::HttpResponse;
-use std::io::Read;
+pub use response::{reason_phrase, HttpResponse};
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub const MAX_HEADER_SIZE: usize = 65_536;
 pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+pub const MAX_HEADER_COUNT: usize = 100;
+
+// Request limits from [server], applied alike by the h1, h2 and h3 handlers
+static HEADER_SIZE_LIMIT: AtomicUsize = AtomicUsize::new(MAX_HEADER_SIZE);
+static BODY_SIZE_LIMIT: AtomicUsize = AtomicUsize::new(MAX_BODY_SIZE);
+static HEADER_COUNT_LIMIT: AtomicUsize = AtomicUsize::new(MAX_HEADER_COUNT);
+
+/// Error for a chunked body still unfinished after `chunked_body_timeout_secs`
+pub const CHUNKED_TOO_SLOW: &str = "chunked body too slow";
+
+pub fn set_limits(header_size: usize, body_size: usize, header_count: usize) {
+    HEADER_SIZE_LIMIT.store(header_size, Ordering::Release);
+    BODY_SIZE_LIMIT.store(body_size, Ordering::Release);
+    HEADER_COUNT_LIMIT.store(header_count, Ordering::Release);
+}
+
+pub fn max_header_size() -> usize {
+    HEADER_SIZE_LIMIT.load(Ordering::Acquire)
+}
+
+pub fn max_body_size() -> usize {
+    BODY_SIZE_LIMIT.load(Ordering::Acquire)
+}
+
+/// Rejection for request header fields over the limits (431) or a declared body over the
+/// limit (413), `None` when within them. Fields are sized as `name: value\r\n` whatever the
+/// wire format, so h1, h2 and h3 requests are judged alike.
+pub fn check_request_limits(headers: &[(String, String)]) -> Option<HttpResponse> {
+    let bytes: usize = headers.iter().map(|(k, v)| k.len() + v.len() + 4).sum();
+    if headers.len() > HEADER_COUNT_LIMIT.load(Ordering::Acquire) || bytes > max_header_size() {
+        return Some(HttpResponse::error(431, "Request Header Fields Too Large"));
+    }
+    let declared = headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok());
+    if declared.is_some_and(|cl| cl > max_body_size()) {
+        return Some(HttpResponse::error(413, "Payload Too Large"));
+    }
+    None
+}
 
 pub fn find_hdr_end(d: &[u8]) -> Option<usize> {
     if d.len() < 4 { return None; }
@@ -16,6 +59,79 @@ pub fn find_hdr_end(d: &[u8]) -> Option<usize> {
     None
 }
 
+/// Header value with CR, LF and other control characters removed (tab is allowed)
+pub fn clean_header_value(v: &str) -> Cow<'_, str> {
+    if v.chars().any(|c| c.is_control() && c != '\t') {
+        Cow::Owned(v.chars().filter(|c| !c.is_control() || *c == '\t').collect())
+    } else {
+        Cow::Borrowed(v)
+    }
+}
+
+/// Header name with control characters, whitespace and ':' removed
+pub fn clean_header_name(n: &str) -> Cow<'_, str> {
+    let bad = |c: char| c.is_control() || c.is_whitespace() || c == ':';
+    if n.chars().any(bad) {
+        Cow::Owned(n.chars().filter(|c| !bad(*c)).collect())
+    } else {
+        Cow::Borrowed(n)
+    }
+}
+
+fn write_headers(o: &mut String, headers: &[(String, String)]) {
+    for (k, v) in headers {
+        let k = clean_header_name(k);
+        if k.is_empty() { continue; }
+        o.push_str(&k);
+        o.push_str(": ");
+        o.push_str(&clean_header_value(v));
+        o.push_str("\r\n");
+    }
+}
+
+/// Decode `%XX` escapes and `+` as space; malformed escapes are kept literally
+pub fn url_decode(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < b.len() => {
+                let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+                match (hex(b[i + 1]), hex(b[i + 2])) {
+                    (Some(hi), Some(lo)) => { out.push(hi << 4 | lo); i += 2; }
+                    _ => out.push(b'%'),
+                }
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Decoded value of query parameter `name` in a request path; a bare `?flag` yields ""
+pub fn query_param(path: &str, name: &str) -> Option<String> {
+    let query = path.split_once('?')?.1;
+    let query = query.split('#').next().unwrap_or("");
+    query.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (url_decode(k) == name).then(|| url_decode(v))
+    })
+}
+
+/// Whether a message's connection stays open: HTTP/1.0 closes unless it sends
+/// `Connection: keep-alive`, later versions stay open unless they send `Connection: close`
+pub fn keep_alive(version: &str, connection: Option<&str>) -> bool {
+    let conn = connection.unwrap_or("").trim();
+    if version == "HTTP/1.0" {
+        conn.eq_ignore_ascii_case("keep-alive")
+    } else {
+        !conn.eq_ignore_ascii_case("close")
+    }
+}
+
 pub fn get_hdr<'a>(h: &'a [(String, String)], n: &str) -> Option<&'a str> {
     for (k, v) in h {
         if k.eq_ignore_ascii_case(n) { return Some(v.as_str()); }
@@ -35,11 +151,49 @@ fn raw_hdr<'a>(t: &'a str, n: &str) -> Option<&'a str> {
 pub enum ReadResult {
     Ok(Vec<u8>),
     TimedOut,
+    /// Headers arrived but the Content-Length body stalled
+    BodyTimedOut,
     Error(String),
 }
 
+/// Frames everything written through it as HTTP/1.1 chunks; `finish` writes the last chunk
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        self.inner.write_all(format!("{:x}\r\n", buf.len()).as_bytes())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub fn find_zero_chunk(d: &[u8]) -> bool {
-    if d.len() < 5 { return false; }
+    trailer_start(d).is_some()
+}
+
+/// For a complete chunked body, the offset just past the `0\r\n` line where the trailer section begins
+pub fn trailer_start(d: &[u8]) -> Option<usize> {
+    if d.len() < 5 { return None; }
     let mut i = 0;
     while i < d.len() {
         let chunk_start = i;
@@ -48,37 +202,281 @@ pub fn find_zero_chunk(d: &[u8]) -> bool {
             size_end += 1;
         }
         if size_end + 1 >= d.len() || d[size_end + 1] != b'\n' {
-            return false;
+            return None;
         }
         let size_str = match std::str::from_utf8(&d[chunk_start..size_end]) {
             Ok(s) => s.split(';').next().unwrap_or("").trim(),
-            Err(_) => return false,
+            Err(_) => return None,
         };
         let chunk_size = match usize::from_str_radix(size_str, 16) {
             Ok(s) => s,
-            Err(_) => return false,
+            Err(_) => return None,
         };
         if chunk_size == 0 {
             let after = size_end + 2;
-            return (after <= d.len() && d[after..].starts_with(b"\r\n"))
-                || after == d.len();
+            if after == d.len() { return Some(after); }
+            let rest = &d[after..];
+            // Either no trailers, or trailer lines ending in a blank line
+            let done = rest.starts_with(b"\r\n") || rest.windows(4).any(|w| w == b"\r\n\r\n");
+            return if done { Some(after) } else { None };
         }
-        i = match (size_end + 2).checked_add(chunk_size) {
-            Some(v) => v,
-            None => return false,
-        };
-        if i + 1 >= d.len() { return false; }
-        if d[i] != b'\r' || d[i + 1] != b'\n' { return false; }
+        i = (size_end + 2).checked_add(chunk_size)?;
+        if i + 1 >= d.len() { return None; }
+        if d[i] != b'\r' || d[i + 1] != b'\n' { return None; }
         i += 2;
     }
-    false
+    None
+}
+
+/// Length of the first complete request in `d`; anything past it is the start of a
+/// pipelined request sent in the same write
+pub fn message_len(d: &[u8]) -> Option<usize> {
+    let start = find_hdr_end(d)? + 4;
+    let hdr_text = std::str::from_utf8(&d[..start]).ok()?;
+    let chunked = raw_hdr(hdr_text, "Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        let body = &d[start..];
+        let t = trailer_start(body)?;
+        let rest = &body[t..];
+        let end = if rest.starts_with(b"\r\n") {
+            t + 2
+        } else {
+            rest.windows(4).position(|w| w == b"\r\n\r\n").map_or(body.len(), |p| t + p + 4)
+        };
+        return Some(start + end);
+    }
+    match raw_hdr(hdr_text, "Content-Length").and_then(|v| v.parse::<usize>().ok()) {
+        Some(cl) => Some((start + cl).min(d.len())),
+        None => Some(start),
+    }
+}
+
+/// Drop the trailer section from a complete chunked body, leaving a bare `0\r\n\r\n`
+pub fn strip_trailers(d: &mut Vec<u8>) {
+    if let Some(t) = trailer_start(d) {
+        d.truncate(t);
+        d.extend_from_slice(b"\r\n");
+    }
+}
+
+/// How the end of a response body relayed straight from the backend is found
+#[derive(Clone)]
+pub enum BodyFraming {
+    /// Body bytes still to come
+    Length(u64),
+    Chunked(ChunkScan),
+    /// Runs until the backend closes the connection
+    Close,
 }
 
+impl BodyFraming {
+    /// Framing of the response `resp` to a `method` request, counting the body bytes
+    /// that arrived with its head
+    pub fn of(method: &str, resp: &HttpResponse) -> Self {
+        let no_body = method == "HEAD" || resp.status_code < 200 || matches!(resp.status_code, 204 | 304);
+        let mut framing = if no_body {
+            BodyFraming::Length(0)
+        } else if resp.get_header("Transfer-Encoding").is_some_and(|v| v.to_ascii_lowercase().contains("chunked")) {
+            BodyFraming::Chunked(ChunkScan::default())
+        } else if let Some(n) = resp.get_header("Content-Length").and_then(|v| v.trim().parse().ok()) {
+            BodyFraming::Length(n)
+        } else {
+            BodyFraming::Close
+        };
+        framing.advance(&resp.body);
+        framing
+    }
+
+    /// Account for `d` read from the backend; returns how many of its bytes belong to the body
+    pub fn advance(&mut self, d: &[u8]) -> usize {
+        match self {
+            BodyFraming::Length(n) => {
+                let k = (*n).min(d.len() as u64);
+                *n -= k;
+                k as usize
+            }
+            BodyFraming::Chunked(scan) => scan.feed(d),
+            BodyFraming::Close => d.len(),
+        }
+    }
+
+    /// The whole body has been seen
+    pub fn complete(&self) -> bool {
+        match self {
+            BodyFraming::Length(n) => *n == 0,
+            BodyFraming::Chunked(scan) => scan.state == ChunkState::Done,
+            BodyFraming::Close => false,
+        }
+    }
+
+    /// Nothing more should be relayed: the body is complete or its chunking is malformed
+    pub fn ended(&self) -> bool {
+        self.complete() || matches!(self, BodyFraming::Chunked(s) if s.state == ChunkState::Invalid)
+    }
+}
+
+/// Incremental scan for the end of a chunked body, including any trailer section
+#[derive(Clone, Default)]
+pub struct ChunkScan {
+    state: ChunkState,
+    size: u64,
+    // Size digits, or trailer line bytes, seen on the current line
+    line: usize,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum ChunkState {
+    #[default]
+    Size,
+    Extension,
+    Data,
+    DataEnd,
+    Trailer,
+    Done,
+    Invalid,
+}
+
+impl ChunkScan {
+    /// Scan `d`, returning how many bytes precede the end of the body (all of them if it continues)
+    fn feed(&mut self, d: &[u8]) -> usize {
+        let mut i = 0;
+        while i < d.len() {
+            let b = d[i];
+            match self.state {
+                ChunkState::Done | ChunkState::Invalid => return i,
+                ChunkState::Data => {
+                    let k = self.size.min((d.len() - i) as u64);
+                    self.size -= k;
+                    i += k as usize;
+                    if self.size == 0 {
+                        self.state = ChunkState::DataEnd;
+                    }
+                    continue;
+                }
+                ChunkState::Size | ChunkState::Extension if b == b'\n' => {
+                    self.state = match (self.line, self.size) {
+                        (0, _) => ChunkState::Invalid,
+                        (_, 0) => ChunkState::Trailer,
+                        _ => ChunkState::Data,
+                    };
+                    self.line = 0;
+                }
+                ChunkState::Size => match (b as char).to_digit(16) {
+                    Some(v) => {
+                        self.line += 1;
+                        match self.size.checked_mul(16).and_then(|s| s.checked_add(v as u64)) {
+                            Some(s) => self.size = s,
+                            None => { self.state = ChunkState::Invalid; continue; }
+                        }
+                    }
+                    None if b == b'\r' => {}
+                    None if matches!(b, b';' | b' ' | b'\t') => self.state = ChunkState::Extension,
+                    None => { self.state = ChunkState::Invalid; continue; }
+                },
+                ChunkState::Extension => {}
+                ChunkState::DataEnd => match b {
+                    b'\r' => {}
+                    b'\n' => self.state = ChunkState::Size,
+                    _ => { self.state = ChunkState::Invalid; continue; }
+                },
+                ChunkState::Trailer => match b {
+                    b'\r' => {}
+                    b'\n' if self.line == 0 => self.state = ChunkState::Done,
+                    b'\n' => self.line = 0,
+                    _ => self.line += 1,
+                },
+            }
+            i += 1;
+        }
+        i
+    }
+}
+
+// Cap on interim responses accepted ahead of the final one
+const MAX_INFORMATIONAL: usize = 8;
+
+/// Length of a leading 1xx interim response head (101 is final, so excluded)
+pub fn informational_len(d: &[u8]) -> Option<usize> {
+    let end = find_hdr_end(d)? + 4;
+    let line = d.split(|&b| b == b'\n').next()?;
+    let code = std::str::from_utf8(line).ok()?.split_whitespace().nth(1)?.parse::<u16>().ok()?;
+    ((100..200).contains(&code) && code != 101).then_some(end)
+}
+
+/// Read a backend response, skipping 1xx interim responses.
+/// Returns the interim heads alongside the final response (head only when `head_only`).
+pub fn read_final_response(r: &mut impl Read, buf_size: usize, head_only: bool) -> (Vec<Vec<u8>>, ReadResult) {
+    let mut interim = Vec::new();
+    let mut pending = Vec::new();
+    loop {
+        let mut src = std::io::Cursor::new(std::mem::take(&mut pending)).chain(&mut *r);
+        let d = match if head_only { read_http_head(&mut src, buf_size) } else { read_http_message(&mut src, buf_size) } {
+            ReadResult::Ok(d) => d,
+            other => return (interim, other),
+        };
+        match informational_len(&d) {
+            Some(end) if interim.len() < MAX_INFORMATIONAL => {
+                interim.push(d[..end].to_vec());
+                pending = d[end..].to_vec();
+            }
+            Some(_) => return (interim, ReadResult::Error("too many informational responses".into())),
+            None => return (interim, ReadResult::Ok(d)),
+        }
+    }
+}
+
+/// Read only up to the end of the headers; any body bytes already received are kept
+pub fn read_http_head(r: &mut impl Read, buf_size: usize) -> ReadResult {
+    let mut d = Vec::with_capacity(buf_size);
+    let mut b = vec![0u8; buf_size];
+    loop {
+        match r.read(&mut b) {
+            Ok(0) => return ReadResult::Error("connection closed".into()),
+            Ok(n) => {
+                d.extend_from_slice(&b[..n]);
+                if find_hdr_end(&d).is_some() { return ReadResult::Ok(d); }
+                if d.len() > max_header_size() {
+                    return ReadResult::Error("headers too large".into());
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut
+                       || e.kind() == std::io::ErrorKind::WouldBlock => return ReadResult::TimedOut,
+            Err(e) => return ReadResult::Error(e.to_string()),
+        }
+    }
+}
+
+/// A complete first line of the form `METHOD /path` with no version token. Such a client
+/// never sends a blank line, so without this check the read would only end on timeout.
+fn is_http09_request_line(d: &[u8]) -> bool {
+    let end = match d.windows(2).position(|w| w == b"\r\n") {
+        Some(p) => p,
+        None => return false,
+    };
+    match std::str::from_utf8(&d[..end]) {
+        Ok(line) => !line.starts_with("HTTP/") && line.split_whitespace().count() == 2,
+        Err(_) => false,
+    }
+}
+
+/// Read one HTTP message; chunked bodies are only bounded by `max_body_size`
 pub fn read_http_message(r: &mut impl Read, buf_size: usize) -> ReadResult {
+    read_message_capped(r, buf_size, max_body_size(), None)
+}
+
+/// `read_http_message` with explicit caps on a chunked body's size and time to complete.
+/// Client requests are read this way; backend responses are not.
+pub(crate) fn read_message_capped(
+    r: &mut impl Read,
+    buf_size: usize,
+    chunked_cap: usize,
+    chunked_timeout: Option<std::time::Duration>,
+) -> ReadResult {
     let mut d = Vec::with_capacity(buf_size);
     let mut b = vec![0u8; buf_size];
     let (mut hdr_done, mut body_start, mut content_len) = (false, 0usize, None::<usize>);
     let mut is_chunked = false;
+    let mut chunked_since = Instant::now();
     let mut timed_out = false;
     let mut read_calls: u32 = 0;
     const MAX_READ_CALLS: u32 = 500;
@@ -94,9 +492,12 @@ pub fn read_http_message(r: &mut impl Read, buf_size: usize) -> ReadResult {
                 d.extend_from_slice(&b[..n]);
 
                 if !hdr_done {
-                    if d.len() > MAX_HEADER_SIZE {
+                    if d.len() > max_header_size() {
                         return ReadResult::Error("headers too large".into());
                     }
+                    if is_http09_request_line(&d) {
+                        return ReadResult::Error("HTTP/0.9 request".into());
+                    }
                     if let Some(p) = find_hdr_end(&d) {
                         hdr_done = true;
                         body_start = p + 4;
@@ -107,13 +508,14 @@ pub fn read_http_message(r: &mut impl Read, buf_size: usize) -> ReadResult {
                         content_len = raw_hdr(hdr_text, "Content-Length")
                             .and_then(|v| v.parse::<usize>().ok());
                         if let Some(cl) = content_len {
-                            if cl > MAX_BODY_SIZE {
+                            if cl > max_body_size() {
                                 return ReadResult::Error("body too large".into());
                             }
                         }
                         is_chunked = raw_hdr(hdr_text, "Transfer-Encoding")
                             .map(|v| v.eq_ignore_ascii_case("chunked"))
                             .unwrap_or(false);
+                        chunked_since = Instant::now();
                         if content_len.is_none() && !is_chunked {
                             break;
                         }
@@ -122,7 +524,7 @@ pub fn read_http_message(r: &mut impl Read, buf_size: usize) -> ReadResult {
 
                 if hdr_done {
                     let body_len = d.len() - body_start;
-                    if body_len > MAX_BODY_SIZE {
+                    if body_len > max_body_size() {
                         return ReadResult::Error("body too large".into());
                     }
                     if let Some(cl) = content_len {
@@ -130,6 +532,13 @@ pub fn read_http_message(r: &mut impl Read, buf_size: usize) -> ReadResult {
                     } else if is_chunked {
                         let body = &d[body_start..];
                         if find_zero_chunk(body) { break; }
+                        // Never-ending chunk streams are cut off here rather than at max_body_size or the read timeout
+                        if body_len > chunked_cap {
+                            return ReadResult::Error("body too large".into());
+                        }
+                        if chunked_timeout.is_some_and(|t| chunked_since.elapsed() > t) {
+                            return ReadResult::Error(CHUNKED_TOO_SLOW.into());
+                        }
                     }
                 }
             }
@@ -150,7 +559,7 @@ pub fn read_http_message(r: &mut impl Read, buf_size: usize) -> ReadResult {
     }
     if timed_out {
         if let Some(cl) = content_len {
-            if d.len() - body_start < cl { return ReadResult::TimedOut; }
+            if d.len() - body_start < cl { return ReadResult::BodyTimedOut; }
         }
     }
     ReadResult::Ok(d)
@@ -1,5 +1,5 @@
 // HTTP request parsing and serialization
-use super::{find_hdr_end, get_hdr};
+use super::{clean_header_name, clean_header_value, find_hdr_end, get_hdr, write_headers};
 
 #[derive(Clone)]
 pub struct HttpRequest {
@@ -12,30 +12,43 @@ pub struct HttpRequest {
 
 impl HttpRequest {
     /// Parse a raw HTTP request. Returns None for malformed or suspicious input.
+    #[allow(dead_code)]
     pub fn parse(r: &[u8]) -> Option<Self> {
-        let e = find_hdr_end(r)?;
-        let t = std::str::from_utf8(&r[..e]).ok()?;
+        Self::parse_checked(r).ok()
+    }
+
+    /// Like `parse`, but says why a request was rejected
+    pub fn parse_checked(r: &[u8]) -> Result<Self, &'static str> {
+        Self::parse_checked_with(r, false)
+    }
+
+    /// Like `parse_checked`; with `upcase_method` a lowercase method such as `get` is
+    /// uppercased instead of rejected
+    pub fn parse_checked_with(r: &[u8], upcase_method: bool) -> Result<Self, &'static str> {
+        let e = find_hdr_end(r).ok_or("incomplete headers")?;
+        let t = std::str::from_utf8(&r[..e]).map_err(|_| "invalid header encoding")?;
         let mut l = t.lines();
-        let rl = l.next()?;
+        let rl = l.next().ok_or("empty request")?;
         let mut p = rl.split_whitespace();
-        let m = p.next()?.to_string();
-        let path = p.next()?.to_string();
-        let v = p.next()?.to_string();
+        let mut m = p.next().ok_or("empty request line")?.to_string();
+        let path = p.next().ok_or("missing request target")?.to_string();
+        let v = p.next().ok_or("missing HTTP version (HTTP/0.9 is not supported)")?.to_string();
 
-        if p.next().is_some() { return None; }
+        if p.next().is_some() { return Err("malformed request line"); }
+        if upcase_method { m.make_ascii_uppercase(); }
 
         if !matches!(m.as_str(),
             "GET" | "POST" | "PUT" | "DELETE" | "PATCH" |
             "HEAD" | "OPTIONS" | "CONNECT" | "TRACE") {
-            return None;
+            return Err("unsupported method");
         }
 
         if path.bytes().any(|b| b < 0x20 || b == 0x7F) {
-            return None;
+            return Err("invalid characters in request target");
         }
 
         if v != "HTTP/1.0" && v != "HTTP/1.1" {
-            return None;
+            return Err(if is_version_token(&v) { "unsupported HTTP version" } else { "malformed HTTP version" });
         }
 
         let mut h = Vec::new();
@@ -51,17 +64,34 @@ impl HttpRequest {
             Some(len) if s < r.len() => r[s..r.len().min(s + len)].to_vec(),
             _ => Vec::new(),
         };
-        Some(HttpRequest { method: m, path, version: v, headers: h, body: b })
+        Ok(HttpRequest { method: m, path, version: v, headers: h, body: b })
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut o = format!("{} {} {}\r\n", self.method, self.path, self.version);
-        for (k, v) in &self.headers {
-            o.push_str(k);
-            o.push_str(": ");
-            o.push_str(v);
-            o.push_str("\r\n");
+        write_headers(&mut o, &self.headers);
+        o.push_str("\r\n");
+        let mut b = o.into_bytes();
+        b.extend_from_slice(&self.body);
+        b
+    }
+
+    /// Serialize for an HTTP/1.1 backend. HTTP/2 and HTTP/3 requests go out as HTTP/1.1,
+    /// without pseudo-headers and with the body length an h2/h3 client need not send.
+    pub fn to_backend_bytes(&self) -> Vec<u8> {
+        if self.version == "HTTP/1.0" || self.version == "HTTP/1.1" {
+            return self.to_bytes();
         }
+        let mut headers: Vec<(String, String)> = self.headers.iter()
+            .filter(|(k, _)| !k.starts_with(':'))
+            .cloned()
+            .collect();
+        let framed = get_hdr(&headers, "Content-Length").is_some() || get_hdr(&headers, "Transfer-Encoding").is_some();
+        if !framed && !self.body.is_empty() {
+            headers.push(("Content-Length".into(), self.body.len().to_string()));
+        }
+        let mut o = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        write_headers(&mut o, &headers);
         o.push_str("\r\n");
         let mut b = o.into_bytes();
         b.extend_from_slice(&self.body);
@@ -73,12 +103,19 @@ impl HttpRequest {
     }
 
     pub fn set_header(&mut self, n: &str, val: &str) {
+        let n = clean_header_name(n);
+        let val = clean_header_value(val).into_owned();
         for (k, v) in self.headers.iter_mut() {
-            if k.eq_ignore_ascii_case(n) {
-                *v = val.to_string();
+            if k.eq_ignore_ascii_case(&n) {
+                *v = val;
                 return;
             }
         }
-        self.headers.push((n.to_string(), val.to_string()));
+        self.headers.push((n.into_owned(), val));
     }
 }
+
+/// `HTTP/<digit>.<digit>`
+fn is_version_token(v: &str) -> bool {
+    matches!(v.strip_prefix("HTTP/").map(|n| n.as_bytes()), Some([a, b'.', b]) if a.is_ascii_digit() && b.is_ascii_digit())
+}
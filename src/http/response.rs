@@ -1,5 +1,33 @@
 // HTTP response parsing and serialization
-use super::{find_hdr_end, get_hdr};
+use super::{clean_header_name, clean_header_value, find_hdr_end, get_hdr, write_headers};
+
+/// Standard reason phrase for the status codes this proxy produces or remaps to
+pub fn reason_phrase(code: u16) -> Option<&'static str> {
+    Some(match code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        410 => "Gone",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => return None,
+    })
+}
 
 #[derive(Clone)]
 pub struct HttpResponse {
@@ -31,33 +59,22 @@ impl HttpResponse {
         Some(HttpResponse { version: v.to_string(), status_code: c, status_text: txt.to_string(), headers: h, body: b })
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Status line and headers, without the body
+    pub fn head_bytes(&self) -> Vec<u8> {
         let mut o = format!("{} {} {}\r\n", self.version, self.status_code, self.status_text);
-        for (k, v) in &self.headers {
-            o.push_str(k);
-            o.push_str(": ");
-            o.push_str(v);
-            o.push_str("\r\n");
-        }
+        write_headers(&mut o, &self.headers);
         o.push_str("\r\n");
-        let mut b = o.into_bytes();
+        o.into_bytes()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = self.head_bytes();
         b.extend_from_slice(&self.body);
         b
     }
 
     pub fn error(c: u16, m: &str) -> Self {
-        let t = match c {
-            400 => "Bad Request",
-            403 => "Forbidden",
-            411 => "Length Required",
-            413 => "Payload Too Large",
-            429 => "Too Many Requests",
-            431 => "Request Header Fields Too Large",
-            502 => "Bad Gateway",
-            503 => "Service Unavailable",
-            504 => "Gateway Timeout",
-            _ => "Error",
-        };
+        let t = reason_phrase(c).unwrap_or("Error");
         HttpResponse {
             version: "HTTP/1.1".to_string(),
             status_code: c,
@@ -76,12 +93,14 @@ impl HttpResponse {
     }
 
     pub fn set_header(&mut self, n: &str, val: &str) {
+        let n = clean_header_name(n);
+        let val = clean_header_value(val).into_owned();
         for (k, v) in self.headers.iter_mut() {
-            if k.eq_ignore_ascii_case(n) {
-                *v = val.to_string();
+            if k.eq_ignore_ascii_case(&n) {
+                *v = val;
                 return;
             }
         }
-        self.headers.push((n.to_string(), val.to_string()));
+        self.headers.push((n.into_owned(), val));
     }
 }
@@ -5,11 +5,14 @@ mod h2_handler;
 mod h3_handler;
 mod http;
 mod log;
+mod memory;
 mod metrics;
 mod modules;
 mod pool;
+mod resolve;
 mod script;
 mod server;
+mod upstream;
 #[cfg(test)]
 mod tests;
 
@@ -20,15 +23,31 @@ fn main() {
     for (k, v) in script_defaults {
         defaults.entry(k).or_insert(v);
     }
-    let c = config::load_config(&defaults);
+    let c = match config::load_config(&defaults) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error(&format!("{e}; refusing to start"));
+            std::process::exit(1);
+        }
+    };
     log::init(c.server.logging);
     log::set_level(&c.server.log_level);
+    resolve::set_prefer(&c.server.dns_prefer);
+    if c.server.dns_refresh_secs > 0 {
+        resolve::start_refresh(c.server.dns_refresh_secs);
+    }
+    upstream::configure(&c.upstreams);
+    pool::global_pool().set_max_lifetime(std::time::Duration::from_secs(c.server.pool_max_conn_lifetime_secs));
+    pool::global_pool().set_idle_timeout(std::time::Duration::from_secs(c.server.pool_idle_timeout_secs));
+    pool::global_pool().set_idle_limits(c.server.pool_max_idle_per_backend, c.server.pool_max_total_idle);
+    pool::start_sweeper(std::time::Duration::from_secs(c.server.pool_idle_timeout_secs));
     log::separator();
     log::info("Loading modules...");
-    let mut p = modules::Pipeline::new(c.server.client_timeout);
-    modules::register_all(&mut p, &c.modules, &c.server);
-    script::load_script_modules(&mut p, &c.modules, &c.server);
-    p.sort();
+    let p = server::build_pipeline(&c.modules, &c.server);
+    log::separator();
+    for line in server::startup_summary(&p, &c.modules, &c.server).lines() {
+        log::info(line);
+    }
     log::separator();
     if let Err(e) = server::Server::new(c.server, p).run() {
         log::error(&format!("Server failed: {e}"));
@@ -0,0 +1,49 @@
+// Load shedding while available memory is below `min_free_memory_mb`
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+pub static SHEDDING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn shedding() -> bool {
+    SHEDDING.load(Ordering::Acquire)
+}
+
+/// Sample /proc/meminfo in the background and raise `SHEDDING` while MemAvailable
+/// is under `min_free_mb`
+#[cfg(target_os = "linux")]
+pub fn start_sampler(min_free_mb: u64) {
+    std::thread::spawn(move || {
+        loop {
+            if crate::server::SHUTDOWN.load(Ordering::Acquire) { break; }
+            let free = std::fs::read_to_string("/proc/meminfo").ok().and_then(|m| available_mb(&m));
+            if let Some(free) = free {
+                let low = free < min_free_mb;
+                if SHEDDING.swap(low, Ordering::AcqRel) != low {
+                    if low {
+                        crate::log::warn(&format!("Available memory {free} MB below {min_free_mb} MB, shedding requests"));
+                    } else {
+                        crate::log::info(&format!("Available memory back to {free} MB, accepting requests"));
+                    }
+                }
+            }
+            std::thread::sleep(SAMPLE_INTERVAL);
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn start_sampler(_min_free_mb: u64) {
+    crate::log::warn("min_free_memory_mb is only supported on Linux, ignoring");
+}
+
+/// MemAvailable from /proc/meminfo contents, in MB
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub fn available_mb(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
@@ -1,6 +1,7 @@
 // Lock-free metrics using atomic counters
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock, RwLock};
 use std::time::Instant;
 
 static START_TIME: OnceLock<Instant> = OnceLock::new();
@@ -17,6 +18,25 @@ static POOL_HITS: AtomicU64 = AtomicU64::new(0);
 static POOL_MISSES: AtomicU64 = AtomicU64::new(0);
 static CB_TRIPS: AtomicU64 = AtomicU64::new(0);
 static CB_REJECTS: AtomicU64 = AtomicU64::new(0);
+static COALESCED: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+static ACCEPT_DROPPED: AtomicU64 = AtomicU64::new(0);
+static TLS_FAILURES: AtomicU64 = AtomicU64::new(0);
+static TLS12: AtomicU64 = AtomicU64::new(0);
+static TLS13: AtomicU64 = AtomicU64::new(0);
+static DURATION_ABORTS: AtomicU64 = AtomicU64::new(0);
+static CONN_BYTES_EXCEEDED: AtomicU64 = AtomicU64::new(0);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static POOL_EVICTED: AtomicU64 = AtomicU64::new(0);
+
+/// Upper bounds (inclusive) of the response size histogram; a final bucket catches the rest
+pub const RESPONSE_SIZE_BOUNDS: [u64; 4] = [1024, 10_240, 102_400, 1_048_576];
+static RESPONSE_SIZES: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+static RESPONSE_SIZE_SUM: AtomicU64 = AtomicU64::new(0);
+
+/// Upper bounds (inclusive, ms) of the request latency histogram; a final bucket catches the rest
+pub const LATENCY_BOUNDS_MS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+static LATENCY_BUCKETS: [AtomicU64; 13] = [const { AtomicU64::new(0) }; 13];
 
 pub fn init() {
     START_TIME.get_or_init(Instant::now);
@@ -32,11 +52,116 @@ pub fn init() {
 #[inline] pub fn inc_pool_misses() { POOL_MISSES.fetch_add(1, Ordering::Relaxed); }
 #[inline] pub fn inc_cb_trips() { CB_TRIPS.fetch_add(1, Ordering::Relaxed); }
 #[inline] pub fn inc_cb_rejects() { CB_REJECTS.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn inc_coalesced() { COALESCED.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn inc_rate_limited() { RATE_LIMITED.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn inc_accept_dropped() { ACCEPT_DROPPED.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn inc_tls_failures() { TLS_FAILURES.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn inc_duration_aborts() { DURATION_ABORTS.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn inc_conn_bytes_exceeded() { CONN_BYTES_EXCEEDED.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn inc_retries() { RETRIES.fetch_add(1, Ordering::Relaxed); }
+#[inline] pub fn add_pool_evicted(n: u64) { POOL_EVICTED.fetch_add(n, Ordering::Relaxed); }
+
+/// Count a completed handshake under its negotiated version ("TLSv1.2" / "TLSv1.3")
+pub fn inc_tls_handshakes(version: &str) {
+    match version {
+        "TLSv1.2" => TLS12.fetch_add(1, Ordering::Relaxed),
+        "TLSv1.3" => TLS13.fetch_add(1, Ordering::Relaxed),
+        _ => return,
+    };
+}
+
+/// Index of the histogram bucket a body of `bytes` falls in
+pub fn response_size_bucket(bytes: u64) -> usize {
+    RESPONSE_SIZE_BOUNDS.iter().position(|b| bytes <= *b).unwrap_or(RESPONSE_SIZE_BOUNDS.len())
+}
+
+pub fn record_response_size(bytes: u64) {
+    RESPONSE_SIZES[response_size_bucket(bytes)].fetch_add(1, Ordering::Relaxed);
+    RESPONSE_SIZE_SUM.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Counters for one backend address, as set in `_backend_addr`
+#[derive(Default)]
+struct BackendStats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency_sum_ms: AtomicU64,
+}
+
+// Past this many addresses, new ones are not tracked, bounding the label set
+const MAX_BACKEND_SERIES: usize = 1024;
+static BACKENDS: OnceLock<RwLock<HashMap<String, Arc<BackendStats>>>> = OnceLock::new();
+
+fn backends() -> &'static RwLock<HashMap<String, Arc<BackendStats>>> {
+    BACKENDS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Count one backend request; `ok` is false for a connection failure, timeout or 5xx
+pub fn record_backend(addr: &str, ok: bool, ms: u64) {
+    let known = match backends().read() {
+        Ok(m) => m.get(addr).cloned(),
+        Err(poisoned) => poisoned.into_inner().get(addr).cloned(),
+    };
+    let stats = match known {
+        Some(s) => s,
+        None => {
+            let mut m = match backends().write() {
+                Ok(m) => m,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if m.len() >= MAX_BACKEND_SERIES && !m.contains_key(addr) {
+                return;
+            }
+            Arc::clone(m.entry(addr.to_string()).or_default())
+        }
+    };
+    stats.requests.fetch_add(1, Ordering::Relaxed);
+    if !ok {
+        stats.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    stats.latency_sum_ms.fetch_add(ms.min(600_000), Ordering::Relaxed);
+}
+
+/// (address, requests, errors, latency sum in ms) per backend, sorted by address
+pub fn backend_snapshot() -> Vec<(String, u64, u64, u64)> {
+    let m = match backends().read() {
+        Ok(m) => m,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut out: Vec<_> = m.iter().map(|(addr, s)| (
+        addr.clone(),
+        s.requests.load(Ordering::Relaxed),
+        s.errors.load(Ordering::Relaxed),
+        s.latency_sum_ms.load(Ordering::Relaxed),
+    )).collect();
+    out.sort();
+    out
+}
+
+fn label_escape(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Per-backend counters as JSON, for the admin API
+pub fn backends_json() -> String {
+    let entries: Vec<String> = backend_snapshot().into_iter().map(|(addr, requests, errors, latency)| {
+        let avg = latency.checked_div(requests).unwrap_or(0);
+        format!(r#"{{"addr":"{}","requests_total":{requests},"errors_total":{errors},"latency_sum_ms":{latency},"latency_avg_ms":{avg}}}"#,
+            label_escape(&addr))
+    }).collect();
+    format!(r#"{{"backends":[{}]}}"#, entries.join(","))
+}
+
+/// Index of the histogram bucket a request taking `ms` falls in
+pub fn latency_bucket(ms: u64) -> usize {
+    LATENCY_BOUNDS_MS.iter().position(|b| ms <= *b).unwrap_or(LATENCY_BOUNDS_MS.len())
+}
 
 #[inline]
 pub fn record_latency(ms: u64) {
     let capped = ms.min(600_000);
     LATENCY_SUM_MS.fetch_add(capped, Ordering::Relaxed);
+    LATENCY_BUCKETS[latency_bucket(capped)].fetch_add(1, Ordering::Relaxed);
     let mut current = LATENCY_MAX_MS.load(Ordering::Relaxed);
     while capped > current {
         match LATENCY_MAX_MS.compare_exchange_weak(current, capped, Ordering::Relaxed, Ordering::Relaxed) {
@@ -60,6 +185,24 @@ pub struct Snapshot {
     pub pool_misses: u64,
     pub cb_trips: u64,
     pub cb_rejects: u64,
+    pub coalesced: u64,
+    pub rate_limited: u64,
+    pub accept_dropped: u64,
+    pub tls_failures: u64,
+    pub tls12: u64,
+    pub tls13: u64,
+    pub duration_aborts: u64,
+    pub conn_bytes_exceeded: u64,
+    pub retries: u64,
+    pub pool_idle: usize,
+    pub pool_evicted: u64,
+    pub cache_entries: usize,
+    pub cache_bytes: usize,
+    // Per-bucket counts, not cumulative
+    pub response_sizes: [u64; 5],
+    pub response_size_sum: u64,
+    // Per-bucket counts, not cumulative
+    pub latency_buckets: [u64; 13],
     pub uptime_secs: u64,
 }
 
@@ -71,9 +214,32 @@ impl Snapshot {
             0
         }
     }
+
+    /// Approximate latency at quantile `q` (0.0-1.0), interpolated within its bucket;
+    /// the open-ended last bucket reports the max seen
+    pub fn latency_percentile_ms(&self, q: f64) -> u64 {
+        let total: u64 = self.latency_buckets.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank = ((q * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (i, &n) in self.latency_buckets.iter().enumerate() {
+            if n == 0 || seen + n < rank {
+                seen += n;
+                continue;
+            }
+            let Some(&upper) = LATENCY_BOUNDS_MS.get(i) else { return self.latency_max_ms };
+            let lower = if i == 0 { 0 } else { LATENCY_BOUNDS_MS[i - 1] };
+            let within = (rank - seen) as f64 / n as f64;
+            return lower + ((upper - lower) as f64 * within).round() as u64;
+        }
+        self.latency_max_ms
+    }
 }
 
 pub fn snapshot() -> Snapshot {
+    let (cache_entries, cache_bytes) = crate::modules::helpers::cache_usage();
     Snapshot {
         requests_total: REQUESTS_TOTAL.load(Ordering::Relaxed),
         requests_ok: REQUESTS_OK.load(Ordering::Relaxed),
@@ -88,6 +254,22 @@ pub fn snapshot() -> Snapshot {
         pool_misses: POOL_MISSES.load(Ordering::Relaxed),
         cb_trips: CB_TRIPS.load(Ordering::Relaxed),
         cb_rejects: CB_REJECTS.load(Ordering::Relaxed),
+        coalesced: COALESCED.load(Ordering::Relaxed),
+        rate_limited: RATE_LIMITED.load(Ordering::Relaxed),
+        accept_dropped: ACCEPT_DROPPED.load(Ordering::Relaxed),
+        tls_failures: TLS_FAILURES.load(Ordering::Relaxed),
+        tls12: TLS12.load(Ordering::Relaxed),
+        tls13: TLS13.load(Ordering::Relaxed),
+        duration_aborts: DURATION_ABORTS.load(Ordering::Relaxed),
+        conn_bytes_exceeded: CONN_BYTES_EXCEEDED.load(Ordering::Relaxed),
+        retries: RETRIES.load(Ordering::Relaxed),
+        pool_idle: crate::pool::global_pool().idle_count(),
+        pool_evicted: POOL_EVICTED.load(Ordering::Relaxed),
+        cache_entries,
+        cache_bytes,
+        response_sizes: std::array::from_fn(|i| RESPONSE_SIZES[i].load(Ordering::Relaxed)),
+        response_size_sum: RESPONSE_SIZE_SUM.load(Ordering::Relaxed),
+        latency_buckets: std::array::from_fn(|i| LATENCY_BUCKETS[i].load(Ordering::Relaxed)),
         uptime_secs: START_TIME.get().map(|t| t.elapsed().as_secs()).unwrap_or(0),
     }
 }
@@ -95,8 +277,11 @@ pub fn snapshot() -> Snapshot {
 pub fn snapshot_prometheus() -> String {
     let s = snapshot();
 
-    format!(
-        "# HELP proxycache_uptime_seconds Server uptime\n\
+    let mut out = format!(
+        "# HELP proxycache_build_info Build version and compiler; always 1\n\
+         # TYPE proxycache_build_info gauge\n\
+         proxycache_build_info{{version=\"{}\",rustc=\"{}\"}} 1\n\
+         # HELP proxycache_uptime_seconds Server uptime\n\
          # TYPE proxycache_uptime_seconds gauge\n\
          proxycache_uptime_seconds {}\n\
          # HELP proxycache_requests_total Total requests\n\
@@ -126,12 +311,106 @@ pub fn snapshot_prometheus() -> String {
          # TYPE proxycache_circuit_breaker_trips counter\n\
          proxycache_circuit_breaker_trips {}\n\
          # TYPE proxycache_circuit_breaker_rejects counter\n\
-         proxycache_circuit_breaker_rejects {}\n",
+         proxycache_circuit_breaker_rejects {}\n\
+         # HELP proxycache_coalesced_requests_total Requests served by waiting on an in-flight fetch\n\
+         # TYPE proxycache_coalesced_requests_total counter\n\
+         proxycache_coalesced_requests_total {}\n\
+         # HELP proxycache_rate_limited_total Requests rejected by the rate limiter\n\
+         # TYPE proxycache_rate_limited_total counter\n\
+         proxycache_rate_limited_total {}\n\
+         # HELP proxycache_accept_dropped_total Connections closed by the accept rate limit\n\
+         # TYPE proxycache_accept_dropped_total counter\n\
+         proxycache_accept_dropped_total {}\n\
+         # HELP proxycache_tls_handshake_failures_total TLS handshakes that failed or timed out\n\
+         # TYPE proxycache_tls_handshake_failures_total counter\n\
+         proxycache_tls_handshake_failures_total {}\n\
+         # HELP proxycache_tls_handshakes_total Completed TLS handshakes by protocol version\n\
+         # TYPE proxycache_tls_handshakes_total counter\n\
+         proxycache_tls_handshakes_total{{version=\"1.2\"}} {}\n\
+         proxycache_tls_handshakes_total{{version=\"1.3\"}} {}\n\
+         # HELP proxycache_request_duration_aborts_total Requests cut off by max_request_duration\n\
+         # TYPE proxycache_request_duration_aborts_total counter\n\
+         proxycache_request_duration_aborts_total {}\n\
+         # HELP proxycache_connection_byte_limit_total Connections closed by max_bytes_per_connection\n\
+         # TYPE proxycache_connection_byte_limit_total counter\n\
+         proxycache_connection_byte_limit_total {}\n\
+         # HELP proxycache_retries_total Backend requests retried by proxy_core\n\
+         # TYPE proxycache_retries_total counter\n\
+         proxycache_retries_total {}\n\
+         # HELP proxycache_pool_idle Idle backend connections held in the pool\n\
+         # TYPE proxycache_pool_idle gauge\n\
+         proxycache_pool_idle {}\n\
+         # HELP proxycache_pool_evicted_total Idle backend connections closed past pool_idle_timeout_secs\n\
+         # TYPE proxycache_pool_evicted_total counter\n\
+         proxycache_pool_evicted_total {}\n\
+         # HELP proxycache_cache_entries Responses held in the cache\n\
+         # TYPE proxycache_cache_entries gauge\n\
+         proxycache_cache_entries {}\n\
+         # HELP proxycache_cache_bytes Approximate bytes held in the cache\n\
+         # TYPE proxycache_cache_bytes gauge\n\
+         proxycache_cache_bytes {}\n",
+        env!("CARGO_PKG_VERSION"), env!("PROXYCACHE_RUSTC_VERSION"),
         s.uptime_secs, s.requests_total, s.requests_ok, s.requests_err,
         s.active_connections, s.connections_total, s.bytes_in, s.bytes_out,
         s.latency_sum_ms, s.latency_max_ms, s.pool_hits, s.pool_misses,
-        s.cb_trips, s.cb_rejects,
-    )
+        s.cb_trips, s.cb_rejects, s.coalesced, s.rate_limited, s.accept_dropped,
+        s.tls_failures, s.tls12, s.tls13, s.duration_aborts, s.conn_bytes_exceeded, s.retries, s.pool_idle, s.pool_evicted, s.cache_entries, s.cache_bytes,
+    );
+    out.push_str("# HELP proxycache_response_size_bytes Response body sizes\n\
+                  # TYPE proxycache_response_size_bytes histogram\n");
+    let mut cumulative = 0;
+    for (i, n) in s.response_sizes.iter().enumerate() {
+        cumulative += n;
+        let le = RESPONSE_SIZE_BOUNDS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+        out.push_str(&format!("proxycache_response_size_bytes_bucket{{le=\"{le}\"}} {cumulative}\n"));
+    }
+    out.push_str(&format!("proxycache_response_size_bytes_sum {}\nproxycache_response_size_bytes_count {cumulative}\n",
+        s.response_size_sum));
+    out.push_str("# HELP proxycache_latency_ms Request latency in milliseconds\n\
+                  # TYPE proxycache_latency_ms histogram\n");
+    let mut cumulative = 0;
+    for (i, n) in s.latency_buckets.iter().enumerate() {
+        cumulative += n;
+        let le = LATENCY_BOUNDS_MS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+        out.push_str(&format!("proxycache_latency_ms_bucket{{le=\"{le}\"}} {cumulative}\n"));
+    }
+    out.push_str(&format!("proxycache_latency_ms_sum {}\nproxycache_latency_ms_count {cumulative}\n", s.latency_sum_ms));
+    let backends = backend_snapshot();
+    if !backends.is_empty() {
+        let series = |name: &str, help: &str, pick: fn(&(String, u64, u64, u64)) -> u64| {
+            let mut s = format!("# HELP {name} {help}\n# TYPE {name} counter\n");
+            for b in &backends {
+                s.push_str(&format!("{name}{{backend=\"{}\"}} {}\n", label_escape(&b.0), pick(b)));
+            }
+            s
+        };
+        out.push_str(&series("proxycache_backend_requests_total", "Requests forwarded per backend", |b| b.1));
+        out.push_str(&series("proxycache_backend_errors_total", "Failed, timed out or 5xx backend requests", |b| b.2));
+        out.push_str(&series("proxycache_backend_latency_ms_sum", "Backend request time per backend", |b| b.3));
+    }
+    out
+}
+
+/// Zero every counter and histogram bucket, keeping uptime; returns the pre-reset JSON
+pub fn reset() -> String {
+    let before = snapshot_json();
+    for c in [
+        &REQUESTS_TOTAL, &REQUESTS_OK, &REQUESTS_ERR, &BYTES_IN, &BYTES_OUT,
+        &LATENCY_SUM_MS, &LATENCY_MAX_MS, &CONNECTIONS_TOTAL, &POOL_HITS, &POOL_MISSES,
+        &CB_TRIPS, &CB_REJECTS, &COALESCED, &RATE_LIMITED, &ACCEPT_DROPPED,
+        &TLS_FAILURES, &TLS12, &TLS13, &DURATION_ABORTS, &CONN_BYTES_EXCEEDED,
+        &RETRIES, &POOL_EVICTED, &RESPONSE_SIZE_SUM,
+    ] {
+        c.store(0, Ordering::Relaxed);
+    }
+    for b in RESPONSE_SIZES.iter().chain(LATENCY_BUCKETS.iter()) {
+        b.store(0, Ordering::Relaxed);
+    }
+    match backends().write() {
+        Ok(mut m) => m.clear(),
+        Err(poisoned) => poisoned.into_inner().clear(),
+    }
+    before
 }
 
 pub fn snapshot_json() -> String {
@@ -139,10 +418,13 @@ pub fn snapshot_json() -> String {
     let avg_lat = if s.requests_total > 0 { s.latency_sum_ms / s.requests_total } else { 0 };
 
     format!(
-        r#"{{"uptime_seconds":{},"requests_total":{},"requests_ok":{},"requests_err":{},"active_connections":{},"connections_total":{},"bytes_in":{},"bytes_out":{},"latency_avg_ms":{},"latency_max_ms":{},"pool_hits":{},"pool_misses":{},"circuit_breaker_trips":{},"circuit_breaker_rejects":{}}}"#,
+        r#"{{"uptime_seconds":{},"requests_total":{},"requests_ok":{},"requests_err":{},"active_connections":{},"connections_total":{},"bytes_in":{},"bytes_out":{},"latency_avg_ms":{},"latency_max_ms":{},"pool_hits":{},"pool_misses":{},"circuit_breaker_trips":{},"circuit_breaker_rejects":{},"coalesced_requests_total":{},"rate_limited_total":{},"accept_dropped_total":{},"tls_handshake_failures_total":{},"tls_handshakes_total":{{"1.2":{},"1.3":{}}},"request_duration_aborts_total":{},"connection_byte_limit_total":{},"retries_total":{},"pool_idle":{},"pool_evicted_total":{},"latency_p50_ms":{},"latency_p95_ms":{},"latency_p99_ms":{}}}"#,
         s.uptime_secs, s.requests_total, s.requests_ok, s.requests_err,
         s.active_connections, s.connections_total, s.bytes_in, s.bytes_out,
         avg_lat, s.latency_max_ms, s.pool_hits, s.pool_misses,
-        s.cb_trips, s.cb_rejects,
+        s.cb_trips, s.cb_rejects, s.coalesced, s.rate_limited, s.accept_dropped,
+        s.tls_failures, s.tls12, s.tls13, s.duration_aborts, s.conn_bytes_exceeded, s.retries,
+        s.pool_idle, s.pool_evicted,
+        s.latency_percentile_ms(0.50), s.latency_percentile_ms(0.95), s.latency_percentile_ms(0.99),
     )
 }
@@ -1,42 +1,121 @@
 // Active health checking for backends
-use super::helpers as h;
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
 use std::collections::HashMap;
-use std::net::{TcpStream, SocketAddr};
-use std::sync::{Arc, RwLock, OnceLock};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock, OnceLock, Weak};
 use std::time::Duration;
 
-static HEALTH: OnceLock<Arc<RwLock<HashMap<String, bool>>>> = OnceLock::new();
+static HEALTH: OnceLock<Arc<RwLock<HashMap<String, h::BackendHealth>>>> = OnceLock::new();
 
 pub fn is_healthy(addr: &str) -> bool {
     HEALTH.get()
         .and_then(|m| m.read().ok())
-        .and_then(|m| m.get(addr).copied())
+        .and_then(|m| m.get(addr).map(|s| s.up))
         .unwrap_or(true)
 }
 
+/// Every monitored backend with its last probe result
+pub fn backends() -> Vec<(String, h::BackendHealth)> {
+    HEALTH.get()
+        .and_then(|m| m.read().ok())
+        .map(|m| m.iter().map(|(addr, s)| (addr.clone(), *s)).collect())
+        .unwrap_or_default()
+}
+
 pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(false));
     t.insert("interval".into(), toml::Value::Integer(10));
     t.insert("timeout".into(), toml::Value::Integer(3));
+    t.insert("health_path".into(), toml::Value::String(String::new()));
+    t.insert("health_expect_body".into(), toml::Value::String(String::new()));
     t
 }
 
+// Probe settings; an empty path means a plain TCP connect check
+struct Probe {
+    timeout: Duration,
+    path: String,
+    expect_body: String,
+}
+
+impl Probe {
+    fn check(&self, addr: &str) -> bool {
+        let sa = match crate::resolve::resolve(addr) {
+            Ok(sa) => sa,
+            Err(_) => return false,
+        };
+        let stream = match TcpStream::connect_timeout(&sa, self.timeout) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        if self.path.is_empty() { return true; }
+        self.http_check(stream, addr)
+    }
+
+    fn http_check(&self, mut s: TcpStream, host: &str) -> bool {
+        let _ = s.set_read_timeout(Some(self.timeout));
+        let _ = s.set_write_timeout(Some(self.timeout));
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: proxycache-health\r\nConnection: close\r\n\r\n",
+            self.path, host
+        );
+        if s.write_all(req.as_bytes()).is_err() { return false; }
+        let mut buf = Vec::new();
+        if s.take(MAX_PROBE_BYTES).read_to_end(&mut buf).is_err() && buf.is_empty() {
+            return false;
+        }
+        let status = buf.split(|&b| b == b'\n').next()
+            .and_then(|l| std::str::from_utf8(l).ok())
+            .and_then(|l| l.split_whitespace().nth(1))
+            .and_then(|c| c.parse::<u16>().ok())
+            .unwrap_or(0);
+        if !(200..300).contains(&status) { return false; }
+        if self.expect_body.is_empty() { return true; }
+        let body = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(p) => &buf[p + 4..],
+            None => return false,
+        };
+        let found = body.windows(self.expect_body.len()).any(|w| w == self.expect_body.as_bytes());
+        if !found {
+            crate::log::debug(&format!("active_health: {host} body missing '{}'", self.expect_body));
+        }
+        found
+    }
+}
+
+const MAX_PROBE_BYTES: u64 = 64 * 1024;
+
 pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "active_health") { return; }
     let interval = h::config_u64(ctx.config, "active_health", "interval", 10);
     let timeout = h::config_u64(ctx.config, "active_health", "timeout", 3);
+    let probe = Probe {
+        timeout: Duration::from_secs(timeout),
+        path: h::config_str(ctx.config, "active_health", "health_path", ""),
+        expect_body: h::config_str(ctx.config, "active_health", "health_expect_body", ""),
+    };
+    if !probe.expect_body.is_empty() && probe.path.is_empty() {
+        crate::log::warn("active_health: health_expect_body needs health_path, using TCP checks");
+    }
 
-    let mut backends = h::config_vec_str(ctx.config, "load_balancer", "backends");
+    let mut backends: Vec<String> = h::config_vec_str(ctx.config, "load_balancer", "backends")
+        .iter()
+        .map(|b| h::parse_backend(b).0)
+        .collect();
     if backends.is_empty() {
         backends.push(ctx.server.backend_addr.clone());
     }
 
     let mut valid_backends = Vec::new();
     for b in &backends {
-        match b.parse::<SocketAddr>() {
-            Ok(_) => valid_backends.push(b.clone()),
-            Err(_) => crate::log::warn(&format!("active_health: invalid backend address '{}', skipping", b)),
+        if crate::resolve::is_valid_addr(b) {
+            valid_backends.push(b.clone());
+        } else {
+            crate::log::warn(&format!("active_health: invalid backend address '{}', skipping", b));
         }
     }
     if valid_backends.is_empty() {
@@ -44,35 +123,34 @@ pub fn register(ctx: &mut super::ModuleContext) {
         return;
     }
 
-    let map: HashMap<String, bool> = valid_backends.iter().map(|b| (b.clone(), true)).collect();
-    let health = HEALTH.get_or_init(|| Arc::new(RwLock::new(map)));
+    let health = HEALTH.get_or_init(|| Arc::new(RwLock::new(HashMap::new())));
+    if let Ok(mut m) = health.write() {
+        for b in &valid_backends {
+            m.entry(b.clone()).or_insert(h::BackendHealth::UNCHECKED);
+        }
+    }
     let health = Arc::clone(health);
+    let probing = Arc::new(());
+    let alive = Arc::downgrade(&probing);
 
     let handle = std::thread::spawn(move || {
         loop {
             std::thread::sleep(Duration::from_secs(interval));
-            if crate::server::SHUTDOWN.load(std::sync::atomic::Ordering::Acquire) {
+            if crate::server::SHUTDOWN.load(std::sync::atomic::Ordering::Acquire) || gone(&alive) {
                 break;
             }
-            let addrs: Vec<String> = match health.read() {
-                Ok(m) => m.keys().cloned().collect(),
-                Err(_) => continue,
-            };
-            let results: Vec<(String, bool)> = addrs.into_iter().map(|addr| {
-                let ok = addr.parse::<SocketAddr>().ok().map(|sa| {
-                    TcpStream::connect_timeout(&sa, Duration::from_secs(timeout)).is_ok()
-                }).unwrap_or(false);
-                (addr, ok)
+            let results: Vec<(String, bool)> = valid_backends.iter().map(|addr| {
+                (addr.clone(), probe.check(addr))
             }).collect();
             if let Ok(mut m) = health.write() {
                 for (addr, ok) in results {
-                    if let Some(up) = m.get_mut(&addr) {
-                        if *up && !ok {
+                    if let Some(state) = m.get_mut(&addr) {
+                        let was_up = state.record(ok);
+                        if was_up && !ok {
                             crate::log::warn(&format!("active_health: {addr} DOWN"));
-                        } else if !*up && ok {
+                        } else if !was_up && ok {
                             crate::log::info(&format!("active_health: {addr} UP"));
                         }
-                        *up = ok;
                     }
                 }
             }
@@ -84,4 +162,23 @@ pub fn register(ctx: &mut super::ModuleContext) {
             crate::log::error(&format!("active_health: thread panicked: {:?}", e));
         }
     });
+    ctx.pipeline.add(Box::new(ActiveHealth { _probing: probing }));
+}
+
+fn gone(alive: &Weak<()>) -> bool {
+    alive.strong_count() == 0
+}
+
+// Keeps the probe thread running for as long as its pipeline is live; a graceful
+// reload drops the old pipeline, which stops the old prober
+struct ActiveHealth {
+    _probing: Arc<()>,
+}
+
+impl Module for ActiveHealth {
+    fn name(&self) -> &str { "active_health" }
+
+    fn handle(&self, _: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        None
+    }
 }
@@ -1,12 +1,12 @@
 // Admin API for proxy management
-use super::helpers as h;
+use super::helpers::{self as h, json_str};
 use crate::server;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpStream;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 const MAX_ADMIN_CONNECTIONS: usize = 16;
 
@@ -15,6 +15,8 @@ pub fn default_config() -> toml::Table {
     t.insert("enabled".into(), toml::Value::Boolean(true));
     t.insert("listen_addr".into(), toml::Value::String("127.0.0.1:9090".into()));
     t.insert("api_key".into(), toml::Value::String("".into()));
+    t.insert("max_body".into(), toml::Value::Integer(16_384));
+    t.insert("request_timeout".into(), toml::Value::Integer(10));
     t
 }
 
@@ -22,12 +24,18 @@ pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "admin_api") { return; }
     let addr = h::config_str(ctx.config, "admin_api", "listen_addr", "127.0.0.1:9090");
     let api_key = h::config_str(ctx.config, "admin_api", "api_key", "");
-    let listener = match TcpListener::bind(&addr) {
-        Ok(l) => l,
-        Err(e) => {
+    let max_body = h::config_usize(ctx.config, "admin_api", "max_body", 16_384);
+    let request_timeout = Duration::from_secs(h::config_u64(ctx.config, "admin_api", "request_timeout", 10).max(1));
+    let listener = match h::bind_once(&addr) {
+        Some(Ok(l)) => l,
+        Some(Err(e)) => {
             crate::log::error(&format!("admin_api: {e}"));
             return;
         }
+        None => {
+            crate::log::debug(&format!("admin_api: already listening on {addr}"));
+            return;
+        }
     };
     if api_key.is_empty() {
         crate::log::warn("admin_api: no api_key set, endpoints are unprotected");
@@ -39,6 +47,8 @@ pub fn register(ctx: &mut super::ModuleContext) {
         backend: ctx.server.backend_addr.clone(),
         max_conns: ctx.server.max_connections,
         api_key,
+        max_body,
+        request_timeout,
         tls_enabled: !ctx.server.tls_cert.is_empty() && !ctx.server.tls_key.is_empty(),
         tls_cert: ctx.server.tls_cert.clone(),
         tls_key: ctx.server.tls_key.clone(),
@@ -82,6 +92,9 @@ struct Info {
     backend: String,
     max_conns: usize,
     api_key: String,
+    max_body: usize,
+    // Whole-request deadline so a trickling client can't hold an admin thread
+    request_timeout: Duration,
     tls_enabled: bool,
     tls_cert: String,
     tls_key: String,
@@ -113,7 +126,7 @@ fn extract_header<'a>(raw: &'a str, name: &str) -> Option<&'a str> {
 const MAX_ADMIN_REQUEST: usize = 65_536;
 
 fn handle(mut s: TcpStream, info: &Info) {
-    let _ = s.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+    let deadline = Instant::now() + info.request_timeout;
     let mut buf = vec![0u8; 4096];
     let mut total = 0usize;
     loop {
@@ -121,11 +134,23 @@ fn handle(mut s: TcpStream, info: &Info) {
             respond(&mut s, 413, r#"{"error":"request too large"}"#);
             return;
         }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            respond(&mut s, 408, r#"{"error":"request timeout"}"#);
+            return;
+        }
+        let _ = s.set_read_timeout(Some(remaining.min(Duration::from_secs(5))));
         let n = match s.read(&mut buf[total..]) {
             Ok(0) => break,
             Ok(n) => n,
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock
-                       || e.kind() == std::io::ErrorKind::TimedOut => break,
+                       || e.kind() == std::io::ErrorKind::TimedOut => {
+                if Instant::now() >= deadline {
+                    respond(&mut s, 408, r#"{"error":"request timeout"}"#);
+                    return;
+                }
+                break;
+            }
             Err(_) => return,
         };
         total += n;
@@ -138,6 +163,10 @@ fn handle(mut s: TcpStream, info: &Info) {
                 } else { None }
             }).unwrap_or(0);
             let body_start = hdr_end + 4;
+            if content_len > info.max_body {
+                respond(&mut s, 413, r#"{"error":"body too large"}"#);
+                return;
+            }
             let body_needed = body_start + content_len;
             if body_needed > MAX_ADMIN_REQUEST {
                 respond(&mut s, 413, r#"{"error":"request too large"}"#);
@@ -167,7 +196,7 @@ fn handle(mut s: TcpStream, info: &Info) {
         return;
     }
     let method = parts[0];
-    let path = parts[1];
+    let (path, query) = parts[1].split_once('?').unwrap_or((parts[1], ""));
 
     if !matches!(method, "GET" | "POST" | "PUT" | "DELETE" | "PATCH" | "HEAD" | "OPTIONS") {
         respond(&mut s, 405, r#"{"error":"method not allowed"}"#);
@@ -193,7 +222,7 @@ fn handle(mut s: TcpStream, info: &Info) {
 
     match (method, path) {
         ("GET", "/") => {
-            respond(&mut s, 200, r#"{"endpoints":["/ping","/status","/config","/server","/stop","/reload","/connections","/metrics","/mods","/protocols","/tls","/config/verify","/config/repair"]}"#);
+            respond(&mut s, 200, r#"{"endpoints":["/ping","/status","/health/detail","/health/backends","/pipeline","/config","/config/effective","/server","/stop","/reload","/reload/graceful","/connections","/metrics","/mods","/protocols","/tls","/config/verify","/config/repair","/pool","/pool/clear","/backends","/metrics/reset"]}"#);
         }
         ("GET", "/ping") => {
             respond(&mut s, 200, r#"{"ping":"pong"}"#);
@@ -209,14 +238,29 @@ fn handle(mut s: TcpStream, info: &Info) {
             if info.tls_enabled && info.http2 { protocols.push("HTTP/2"); }
             if info.tls_enabled && info.http3 { protocols.push("HTTP/3"); }
             let body = format!(
-                r#"{{"status":"running","uptime_seconds":{up},"uptime":"{d}d {h}h {m}m {sec}s","listen":"{l}","backend":"{b}","scheme":"{scheme}","protocols":"{protos}","pid":{pid},"active_connections":{active},"max_connections":{mc},"requests_total":{rt},"requests_ok":{ro},"requests_err":{re},"bytes_in":{bi},"bytes_out":{bo},"avg_latency_ms":{lat}}}"#,
+                r#"{{"status":"running","uptime_seconds":{up},"uptime":"{d}d {h}h {m}m {sec}s","listen":"{l}","backend":"{b}","scheme":"{scheme}","protocols":"{protos}","pid":{pid},"active_connections":{active},"max_connections":{mc},"requests_total":{rt},"requests_ok":{ro},"requests_err":{re},"bytes_in":{bi},"bytes_out":{bo},"avg_latency_ms":{lat},"circuit_breaker":"{cb}","rate_limited_total":{rl},"cache_entries":{ce},"cache_bytes":{cbytes}}}"#,
                 l = info.listen, b = info.backend, mc = info.max_conns,
                 protos = protocols.join(", "),
                 rt = snap.requests_total, ro = snap.requests_ok, re = snap.requests_err,
                 bi = snap.bytes_in, bo = snap.bytes_out, lat = snap.avg_latency_ms(),
+                cb = super::circuit_breaker::state().unwrap_or("disabled"), rl = snap.rate_limited,
+                ce = snap.cache_entries, cbytes = snap.cache_bytes,
             );
             respond(&mut s, 200, &body);
         }
+        ("GET", "/health/detail") => {
+            let (code, body) = health_detail();
+            respond(&mut s, code, &body);
+        }
+        ("GET", "/health/backends") => {
+            respond(&mut s, 200, &backends_json());
+        }
+        ("GET", "/pipeline") => {
+            match server::live_pipeline() {
+                Some(live) => respond(&mut s, 200, &pipeline_json(&live.load())),
+                None => respond(&mut s, 503, r#"{"error":"server not running"}"#),
+            }
+        }
         ("GET", "/connections") => {
             let active = server::active_connections();
             let snap = crate::metrics::snapshot();
@@ -228,9 +272,41 @@ fn handle(mut s: TcpStream, info: &Info) {
         ("GET", "/metrics") => {
             respond(&mut s, 200, &crate::metrics::snapshot_json());
         }
+        ("POST", "/metrics/reset") => {
+            if info.api_key.is_empty() {
+                respond(&mut s, 403, r#"{"error":"metrics reset requires api_key"}"#);
+                return;
+            }
+            respond(&mut s, 200, &crate::metrics::reset());
+        }
+        ("GET", "/backends") => {
+            respond(&mut s, 200, &crate::metrics::backends_json());
+        }
+        ("GET", "/pool") => {
+            respond(&mut s, 200, &pool_json());
+        }
+        ("POST", "/pool/clear") => {
+            let pool = crate::pool::global_pool();
+            let backend = query.split('&').find_map(|kv| kv.strip_prefix("backend="));
+            let closed = match backend.map(|b| b.parse::<std::net::SocketAddr>()) {
+                None => pool.clear(),
+                Some(Ok(addr)) => pool.evict(&addr),
+                Some(Err(_)) => {
+                    respond(&mut s, 400, r#"{"error":"backend must be ip:port"}"#);
+                    return;
+                }
+            };
+            respond(&mut s, 200, &format!(r#"{{"action":"cleared","closed":{closed}}}"#));
+        }
         ("GET", "/config") => {
             respond(&mut s, 200, &full_config_json(info));
         }
+        ("GET", "/config/effective") => {
+            match server::live_pipeline() {
+                Some(live) => respond(&mut s, 200, &effective_config_json(&live.load())),
+                None => respond(&mut s, 503, r#"{"error":"server not running"}"#),
+            }
+        }
         ("GET", "/server") => {
             respond(&mut s, 200, &server_config_json(info));
         }
@@ -260,6 +336,19 @@ fn handle(mut s: TcpStream, info: &Info) {
             let _ = std::fs::write(".proxycache-reload", "");
             server::request_shutdown();
         }
+        ("POST", "/reload/graceful") => {
+            match server::live_pipeline() {
+                Some(live) => match crate::config::reload_config(&crate::config::path(), &all_defaults()) {
+                    Ok(c) => {
+                        crate::upstream::configure(&c.upstreams);
+                        respond(&mut s, 200, &server::reload_pipeline(live, &c.modules, &c.server));
+                    }
+                    // Strict config: keep serving with the running pipeline
+                    Err(e) => respond(&mut s, 400, &format!(r#"{{"error":{}}}"#, json_str(&e))),
+                },
+                None => respond(&mut s, 503, r#"{"error":"server not running"}"#),
+            }
+        }
         _ => {
             respond(&mut s, 404, r#"{"error":"not found"}"#);
         }
@@ -290,7 +379,9 @@ fn respond(s: &mut TcpStream, code: u16, body: &str) {
         403 => "Forbidden",
         404 => "Not Found",
         405 => "Method Not Allowed",
+        408 => "Request Timeout",
         413 => "Payload Too Large",
+        503 => "Service Unavailable",
         _ => "Error",
     };
     let r = format!(
@@ -300,6 +391,128 @@ fn respond(s: &mut TcpStream, code: u16, body: &str) {
     let _ = s.write_all(r.as_bytes());
 }
 
+fn pipeline_json(p: &super::Pipeline) -> String {
+    let entries: Vec<String> = p.order().iter()
+        .map(|(priority, name)| format!(r#"{{"name":"{name}","priority":{priority}}}"#))
+        .collect();
+    format!(r#"{{"modules":[{}]}}"#, entries.join(","))
+}
+
+fn toml_json(v: &toml::Value) -> String {
+    match v {
+        toml::Value::String(s) => json_str(s),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Datetime(d) => json_str(&d.to_string()),
+        toml::Value::Array(a) => format!("[{}]", a.iter().map(toml_json).collect::<Vec<_>>().join(",")),
+        toml::Value::Table(t) => {
+            let mut keys: Vec<&String> = t.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys.iter().map(|k| format!("{}:{}", json_str(k), toml_json(&t[*k]))).collect();
+            format!("{{{}}}", fields.join(","))
+        }
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    let k = key.to_ascii_lowercase();
+    ["key", "secret", "password", "token"].iter().any(|s| k.ends_with(s))
+}
+
+/// Config each loaded module actually runs with: Rust defaults overlaid with the config
+/// the live pipeline was built from, and script modules as resolved when they loaded
+fn effective_config_json(p: &super::Pipeline) -> String {
+    let defaults = super::collect_defaults();
+    let mut names = p.module_names();
+    names.sort();
+    names.dedup();
+    let entries: Vec<String> = names.iter().map(|name| {
+        let body = match p.script_config(name) {
+            Some(resolved) => {
+                let mut keys: Vec<&String> = resolved.keys().collect();
+                keys.sort();
+                let fields: Vec<String> = keys.iter().map(|k| {
+                    let v = if is_secret(k) { "<redacted>" } else { resolved[*k].as_str() };
+                    format!("{}:{}", json_str(k), json_str(v))
+                }).collect();
+                format!(r#"{{"source":"script","config":{{{}}}}}"#, fields.join(","))
+            }
+            None => {
+                let mut merged = defaults.get(name).and_then(|v| v.as_table()).cloned().unwrap_or_default();
+                if let Some(t) = p.config().get(name).and_then(|v| v.as_table()) {
+                    merged.extend(t.clone());
+                }
+                for (k, v) in merged.iter_mut() {
+                    if is_secret(k) && v.is_str() {
+                        *v = toml::Value::String("<redacted>".into());
+                    }
+                }
+                format!(r#"{{"source":"rust","config":{}}}"#, toml_json(&toml::Value::Table(merged)))
+            }
+        };
+        format!("{}:{body}", json_str(name))
+    }).collect();
+    format!(r#"{{"modules":{{{}}}}}"#, entries.join(","))
+}
+
+fn all_defaults() -> std::collections::HashMap<String, toml::Value> {
+    let mut defaults = super::collect_defaults();
+    for (k, v) in crate::script::collect_script_defaults() {
+        defaults.entry(k).or_insert(v);
+    }
+    defaults
+}
+
+/// Readiness: 503 once shutdown begins (including the pre-drain window) or while the
+/// circuit breaker is open, so probes can act on the status code alone
+fn health_detail() -> (u16, String) {
+    let cb = super::circuit_breaker::state();
+    let (code, status) = if server::draining() {
+        (503, "draining")
+    } else if cb == Some("open") {
+        (503, "degraded")
+    } else {
+        (200, "ok")
+    };
+    (code, format!(
+        r#"{{"status":"{status}","circuit_breaker":"{}","rate_limited_total":{},"active_connections":{}}}"#,
+        cb.unwrap_or("disabled"), crate::metrics::snapshot().rate_limited, server::active_connections(),
+    ))
+}
+
+/// Backends probed by the active_health module or std.active_health
+fn backends_json() -> String {
+    let mut list = super::active_health::backends();
+    for (addr, state) in crate::script::stdlib::backend_health() {
+        if !list.iter().any(|(a, _)| *a == addr) {
+            list.push((addr, state));
+        }
+    }
+    list.sort_by(|a, b| a.0.cmp(&b.0));
+    let entries: Vec<String> = list.iter().map(|(addr, state)| {
+        let ago = state.checked.map_or("null".to_string(), |t| t.elapsed().as_secs().to_string());
+        format!(r#"{{"addr":{},"up":{},"last_checked_secs_ago":{ago}}}"#, json_str(addr), state.up)
+    }).collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// Backend connection pool: per-backend connections in use and idle, plus totals
+fn pool_json() -> String {
+    let pool = crate::pool::global_pool();
+    let stats = pool.stats();
+    let (per_backend, total) = pool.idle_limits();
+    let entries: Vec<String> = stats.iter().map(|(addr, in_use, idle)| {
+        format!(r#"{{"addr":"{addr}","in_use":{in_use},"idle":{idle}}}"#)
+    }).collect();
+    format!(
+        r#"{{"in_use":{},"idle":{},"max_idle_per_backend":{per_backend},"max_total_idle":{total},"backends":[{}]}}"#,
+        stats.iter().map(|s| s.1).sum::<usize>(),
+        stats.iter().map(|s| s.2).sum::<usize>(),
+        entries.join(","),
+    )
+}
+
 fn server_config_json(info: &Info) -> String {
     format!(
         r#"{{"listen_addr":"{la}","backend_addr":"{ba}","buffer_size":{bs},"client_timeout":{ct},"backend_timeout":{bt},"max_header_size":{mh},"max_body_size":{mb},"max_connections":{mc},"worker_threads":{wt},"shutdown_timeout":{st},"log_level":"{ll}","logging":{lo},"tls_cert":"{tc}","tls_key":"{tk}","http2":{h2},"http3":{h3},"h3_port":{hp}}}"#,
@@ -385,51 +598,14 @@ fn mods_list() -> String {
 }
 
 fn config_verify() -> String {
-    let path = std::path::Path::new("config.toml");
-    let content = match std::fs::read_to_string(path) {
-        Ok(c) => c,
-        Err(e) => return format!(r#"{{"ok":false,"error":"Cannot read config.toml: {}"}}"#, e),
-    };
-
-    match content.parse::<toml::Table>() {
-        Err(e) => format!(r#"{{"ok":false,"error":"Parse error: {}"}}"#, e),
-        Ok(table) => {
-            let mut issues: Vec<String> = Vec::new();
-
-            // Check [server] section
-            if !table.contains_key("server") {
-                issues.push("missing [server] section".to_string());
-            } else if let Some(srv) = table.get("server").and_then(|v| v.as_table()) {
-                for key in &["listen_addr", "backend_addr"] {
-                    if !srv.contains_key(*key) {
-                        issues.push(format!("server.{} missing", key));
-                    }
-                }
-            }
-
-            // Check [modules] section exists
-            if !table.contains_key("modules") {
-                issues.push("missing [modules] section".to_string());
-            }
-
-            // Check script module configs
-            let script_mods = crate::script::stdlib::list_loaded_mods();
-            if let Some(modules) = table.get("modules").and_then(|v| v.as_table()) {
-                for (name, _, _) in &script_mods {
-                    if !modules.contains_key(name) {
-                        issues.push(format!("missing config for script module '{}'", name));
-                    }
-                }
-            }
-
-            if issues.is_empty() {
-                r#"{"ok":true,"issues":[]}"#.to_string()
-            } else {
-                let issues_json: Vec<String> = issues.iter()
-                    .map(|i| format!(r#""{}""#, i))
-                    .collect();
-                format!(r#"{{"ok":false,"issues":[{}]}}"#, issues_json.join(","))
-            }
+    match crate::config::verify_file("config.toml") {
+        Err(e) => format!(r#"{{"ok":false,"error":"{}"}}"#, e),
+        Ok(issues) if issues.is_empty() => r#"{"ok":true,"issues":[]}"#.to_string(),
+        Ok(issues) => {
+            let issues_json: Vec<String> = issues.iter()
+                .map(|i| format!(r#""{}""#, i))
+                .collect();
+            format!(r#"{{"ok":false,"issues":[{}]}}"#, issues_json.join(","))
         }
     }
 }
@@ -0,0 +1,131 @@
+// Subrequest-based authorization (like nginx auth_request)
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+const DEFAULT_FORWARD: &[&str] = &["Authorization", "Cookie"];
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("auth_url".into(), toml::Value::String(String::new()));
+    t.insert("timeout".into(), toml::Value::Integer(5));
+    t.insert("forward_headers".into(), toml::Value::Array(
+        DEFAULT_FORWARD.iter().map(|h| toml::Value::String(h.to_string())).collect()
+    ));
+    t.insert("copy_headers".into(), toml::Value::Array(vec![]));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    if !h::is_enabled(ctx.config, "auth_request") { return; }
+    let url = h::config_str(ctx.config, "auth_request", "auth_url", "");
+    let (addr, path) = match parse_url(&url) {
+        Some(p) => p,
+        None => {
+            crate::log::warn(&format!("auth_request: invalid auth_url '{url}', module disabled"));
+            return;
+        }
+    };
+    let forward = match ctx.config.get("auth_request").and_then(|v| v.get("forward_headers")) {
+        Some(_) => h::config_vec_str(ctx.config, "auth_request", "forward_headers"),
+        None => DEFAULT_FORWARD.iter().map(|h| h.to_string()).collect(),
+    };
+    ctx.pipeline.add(Box::new(AuthRequest {
+        addr,
+        path,
+        timeout: Duration::from_secs(h::config_u64(ctx.config, "auth_request", "timeout", 5)),
+        forward,
+        copy: h::config_vec_str(ctx.config, "auth_request", "copy_headers"),
+    }));
+}
+
+/// Split `http://host:port/path` into a backend address and a path
+fn parse_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') { host.to_string() } else { format!("{host}:80") };
+    crate::resolve::is_valid_addr(&addr).then(|| (addr, path.to_string()))
+}
+
+struct AuthRequest {
+    addr: String,
+    path: String,
+    timeout: Duration,
+    // Request headers passed on to the auth service
+    forward: Vec<String>,
+    // Auth response headers copied into the proxied request
+    copy: Vec<String>,
+}
+
+impl AuthRequest {
+    fn subrequest(&self, r: &HttpRequest) -> Option<HttpResponse> {
+        let sa = crate::resolve::resolve(&self.addr).ok()?;
+        let mut s = TcpStream::connect_timeout(&sa, self.timeout).ok()?;
+        let _ = s.set_read_timeout(Some(self.timeout));
+        let _ = s.set_write_timeout(Some(self.timeout));
+        let mut sub = HttpRequest {
+            method: "GET".into(),
+            path: self.path.clone(),
+            version: "HTTP/1.1".into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        sub.set_header("Host", &self.addr);
+        sub.set_header("X-Original-URI", &r.path);
+        sub.set_header("X-Original-Method", &r.method);
+        for name in &self.forward {
+            if let Some(v) = r.get_header(name) {
+                sub.set_header(name, v);
+            }
+        }
+        sub.set_header("Connection", "close");
+        s.write_all(&sub.to_bytes()).ok()?;
+        match crate::http::read_final_response(&mut s, 8192, false).1 {
+            crate::http::ReadResult::Ok(d) => HttpResponse::parse(&d),
+            _ => None,
+        }
+    }
+}
+
+impl Module for AuthRequest {
+    fn name(&self) -> &str { "auth_request" }
+    fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        let auth = match self.subrequest(r) {
+            Some(a) => a,
+            None => {
+                crate::log::warn(&format!("auth_request: auth service {} unreachable", self.addr));
+                return Some(HttpResponse::error(502, "Auth service unavailable"));
+            }
+        };
+        match auth.status_code {
+            200..=299 => {
+                for name in &self.copy {
+                    match auth.get_header(name) {
+                        Some(v) => r.set_header(name, v),
+                        // Never let the client supply an identity header itself
+                        None => r.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name)),
+                    }
+                }
+                None
+            }
+            401 | 403 => {
+                let mut resp = HttpResponse::error(auth.status_code, "Access denied");
+                if let Some(v) = auth.get_header("WWW-Authenticate") {
+                    resp.set_header("WWW-Authenticate", v);
+                }
+                Some(resp)
+            }
+            code => {
+                crate::log::warn(&format!("auth_request: unexpected status {code} from auth service"));
+                Some(HttpResponse::error(500, "Auth service error"))
+            }
+        }
+    }
+}
@@ -3,7 +3,7 @@ use super::{helpers as h, Module};
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -11,32 +11,105 @@ pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(false));
     t.insert("ttl_seconds".into(), toml::Value::Integer(300));
+    t.insert("ttl_jitter_seconds".into(), toml::Value::Integer(0));
     t.insert("max_size".into(), toml::Value::Integer(100));
+    t.insert("max_bytes".into(), toml::Value::Integer(64 * 1024 * 1024));
+    t.insert("eviction_policy".into(), toml::Value::String("lru".into()));
     t.insert("warm_urls".into(), toml::Value::Array(vec![]));
+    t.insert("coalesce".into(), toml::Value::Boolean(false));
+    t.insert("cache_key".into(), toml::Value::String("path".into()));
+    t.insert("cache_key_template".into(), toml::Value::String("${path}".into()));
+    t.insert("age_header".into(), toml::Value::Boolean(true));
     t
 }
 
 pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "cache") { return; }
     let ttl = h::config_u64(ctx.config, "cache", "ttl_seconds", 300);
+    let jitter = h::config_u64(ctx.config, "cache", "ttl_jitter_seconds", 0);
     let max = h::config_usize(ctx.config, "cache", "max_size", 100);
+    let max_bytes = h::config_usize(ctx.config, "cache", "max_bytes", 64 * 1024 * 1024);
     let urls = h::config_vec_str(ctx.config, "cache", "warm_urls");
-    let cache = Arc::new(Mutex::new(HashMap::new()));
+    let coalesce = h::config_bool(ctx.config, "cache", "coalesce", false);
+    let age_header = h::config_bool(ctx.config, "cache", "age_header", true);
+    let mut key = h::config_str(ctx.config, "cache", "cache_key_template", "${path}");
+    let strategy = match h::config_str(ctx.config, "cache", "cache_key", "path").as_str() {
+        "path" => KeyStrategy::Template,
+        "path_query" => KeyStrategy::PathQuery,
+        "path_host" => {
+            key = "${header.Host}${path}".into();
+            KeyStrategy::Template
+        }
+        "path_vary" => KeyStrategy::PathVary,
+        other => {
+            crate::log::warn(&format!("cache: cache_key '{other}' unknown, using path"));
+            KeyStrategy::Template
+        }
+    };
+    let policy = match h::config_str(ctx.config, "cache", "eviction_policy", "lru").as_str() {
+        "lru" => Eviction::Lru,
+        "lfu" => Eviction::Lfu,
+        "ttl" => Eviction::Ttl,
+        other => {
+            crate::log::warn(&format!("cache: eviction_policy '{other}' unknown, using lru"));
+            Eviction::Lru
+        }
+    };
+    let cache = Arc::new(Mutex::new(Store { entries: HashMap::new(), bytes: 0, max, max_bytes, policy }));
+    match STORES.lock() {
+        Ok(mut g) => g.push(Arc::downgrade(&cache)),
+        Err(poisoned) => poisoned.into_inner().push(Arc::downgrade(&cache)),
+    }
     let backend = ctx.server.backend_addr.clone();
     if !urls.is_empty() {
-        warm_cache(Arc::clone(&cache), urls, backend);
+        warm_cache(Arc::clone(&cache), urls, backend, jitter, key.clone());
+    }
+    start_eviction_thread(Arc::downgrade(&cache));
+    ctx.pipeline.add(Box::new(Cache {
+        cache,
+        ttl,
+        jitter,
+        coalesce,
+        age_header,
+        key,
+        strategy,
+        vary: Mutex::new(HashMap::new()),
+        wait: Duration::from_secs(ctx.server.backend_timeout),
+        inflight: Arc::new(Mutex::new(HashMap::new())),
+    }));
+}
+
+static STORES: Mutex<Vec<Weak<Mutex<Store>>>> = Mutex::new(Vec::new());
+
+/// Entry count and bytes held across live caches, or `None` if the module is not loaded
+pub fn usage() -> Option<(usize, usize)> {
+    let mut list = match STORES.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    list.retain(|w| w.strong_count() > 0);
+    let stores: Vec<Arc<Mutex<Store>>> = list.iter().filter_map(|w| w.upgrade()).collect();
+    if stores.is_empty() {
+        return None;
     }
-    start_eviction_thread(Arc::clone(&cache));
-    ctx.pipeline.add(Box::new(Cache { cache, ttl, max }));
+    Some(stores.iter().fold((0, 0), |(n, b), s| {
+        let (en, eb) = match s.lock() {
+            Ok(g) => (g.entries.len(), g.bytes),
+            Err(poisoned) => { let g = poisoned.into_inner(); (g.entries.len(), g.bytes) }
+        };
+        (n + en, b + eb)
+    }))
 }
 
-fn start_eviction_thread(cache: Arc<Mutex<HashMap<String, Entry>>>) {
+// Holds only a weak reference, so the thread ends once a reload drops the old pipeline
+fn start_eviction_thread(cache: Weak<Mutex<Store>>) {
     let handle = thread::spawn(move || {
         loop {
             thread::sleep(Duration::from_secs(30));
             if crate::server::SHUTDOWN.load(std::sync::atomic::Ordering::Acquire) {
                 break;
             }
+            let Some(cache) = cache.upgrade() else { break };
             let mut m = match cache.lock() {
                 Ok(guard) => guard,
                 Err(poisoned) => {
@@ -46,12 +119,11 @@ fn start_eviction_thread(cache: Arc<Mutex<HashMap<String, Entry>>>) {
                     inner
                 }
             };
-            let before = m.len();
-            let now = Instant::now();
-            m.retain(|_, e| now < e.exp);
-            let evicted = before - m.len();
+            let before = m.entries.len();
+            m.remove_expired(Instant::now());
+            let evicted = before - m.entries.len();
             if evicted > 0 {
-                crate::log::info(&format!("cache: evicted {evicted} expired ({} left)", m.len()));
+                crate::log::info(&format!("cache: evicted {evicted} expired ({} left)", m.entries.len()));
             }
         }
         crate::log::info("cache: eviction thread stopped");
@@ -63,7 +135,7 @@ fn start_eviction_thread(cache: Arc<Mutex<HashMap<String, Entry>>>) {
     });
 }
 
-fn warm_cache(c: Arc<Mutex<HashMap<String, Entry>>>, urls: Vec<String>, backend: String) {
+fn warm_cache(c: Arc<Mutex<Store>>, urls: Vec<String>, backend: String, jitter: u64, key: String) {
     std::thread::spawn(move || {
         std::thread::sleep(Duration::from_secs(2));
         for u in urls {
@@ -77,7 +149,15 @@ fn warm_cache(c: Arc<Mutex<HashMap<String, Entry>>>, urls: Vec<String>, backend:
                         inner
                     }
                 };
-                m.insert(u, Entry { resp, exp: Instant::now() + Duration::from_secs(300) });
+                let req = HttpRequest {
+                    method: "GET".into(),
+                    path: u,
+                    version: "HTTP/1.1".into(),
+                    headers: Vec::new(),
+                    body: Vec::new(),
+                };
+                let k = crate::script::runtime::interpolate(&key, &req, &Context::new());
+                m.insert(k, resp, Instant::now() + h::ttl_with_jitter(300, jitter));
             }
         }
     });
@@ -98,21 +178,287 @@ fn fetch(u: &str, backend: &str) -> Result<HttpResponse, ()> {
 }
 
 struct Cache {
-    cache: Arc<Mutex<HashMap<String, Entry>>>,
+    cache: Arc<Mutex<Store>>,
     ttl: u64,
-    max: usize,
+    // Up to this many extra seconds per entry, spreading out expiry
+    jitter: u64,
+    coalesce: bool,
+    // Send `Age` on hits: seconds since the entry was stored, plus any Age it arrived with
+    age_header: bool,
+    // Rendered per request into the map key, e.g. `${method}:${path}:${header.Accept-Language}`
+    key: String,
+    strategy: KeyStrategy,
+    // Header names from the last stored response's Vary, per base key (path_vary only)
+    vary: Mutex<HashMap<String, Vec<String>>>,
+    wait: Duration,
+    inflight: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+/// A backend fetch in progress; concurrent misses for the same key wait on it
+struct Flight {
+    done: Mutex<bool>,
+    cv: Condvar,
+}
+
+impl Flight {
+    fn wait(&self, timeout: Duration) {
+        let guard = match self.done.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let _ = self.cv.wait_timeout_while(guard, timeout, |done| !*done);
+    }
+
+    fn finish(&self) {
+        match self.done.lock() {
+            Ok(mut g) => *g = true,
+            Err(poisoned) => *poisoned.into_inner() = true,
+        }
+        self.cv.notify_all();
+    }
+}
+
+/// The leader's claim on a key, kept in the request `Context`. Dropping it wakes the
+/// followers, so a panic or a skipped `on_response` cannot leave the key blocked.
+struct Leader {
+    key: String,
+    flight: Arc<Flight>,
+    inflight: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+impl Leader {
+    fn release(&self) {
+        let mut inflight = match self.inflight.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // A later leader may own the key by now
+        if inflight.get(&self.key).is_some_and(|f| Arc::ptr_eq(f, &self.flight)) {
+            inflight.remove(&self.key);
+        }
+        drop(inflight);
+        self.flight.finish();
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.release();
+    }
 }
 
 struct Entry {
     resp: HttpResponse,
     exp: Instant,
+    // Last hit (or the insert), for least-recently-used eviction
+    used: Instant,
+    // Hits since the insert, for least-frequently-used eviction
+    hits: u64,
+    stored: Instant,
+    size: usize,
+}
+
+impl Entry {
+    fn age(&self, now: Instant) -> u64 {
+        let upstream = self.resp.get_header("Age").and_then(|v| v.trim().parse::<u64>().ok()).unwrap_or(0);
+        upstream.saturating_add(now.duration_since(self.stored).as_secs())
+    }
+}
+
+/// Which entry makes room when the cache is full
+#[derive(Clone, Copy)]
+enum Eviction {
+    // Least recently used
+    Lru,
+    // Fewest hits, the least recently used of those
+    Lfu,
+    // Closest to expiry, regardless of use
+    Ttl,
+}
+
+/// Cached entries bounded by `max` entries and `max_bytes` (0 for no byte limit)
+struct Store {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    max: usize,
+    max_bytes: usize,
+    policy: Eviction,
+}
+
+impl Store {
+    /// Store `resp` under `key`, evicting entries by the eviction policy to stay within bounds
+    fn insert(&mut self, key: String, resp: HttpResponse, exp: Instant) {
+        let size = key.len() + resp.body.len() + resp.headers.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+        self.remove(&key);
+        if self.max == 0 || (self.max_bytes > 0 && size > self.max_bytes) {
+            crate::log::debug(&format!("cache: {key} ({size} bytes) exceeds the cache bounds, not stored"));
+            return;
+        }
+        while self.entries.len() >= self.max || (self.max_bytes > 0 && self.bytes + size > self.max_bytes) {
+            let entries = self.entries.iter();
+            let victim = match self.policy {
+                Eviction::Lru => entries.min_by_key(|(_, e)| e.used),
+                Eviction::Lfu => entries.min_by_key(|(_, e)| (e.hits, e.used)),
+                Eviction::Ttl => entries.min_by_key(|(_, e)| e.exp),
+            };
+            match victim.map(|(k, _)| k.clone()) {
+                Some(k) => self.remove(&k),
+                None => break,
+            }
+        }
+        let now = Instant::now();
+        self.bytes += size;
+        self.entries.insert(key, Entry { resp, exp, used: now, hits: 0, stored: now, size });
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(e) = self.entries.remove(key) {
+            self.bytes -= e.size;
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        self.entries.retain(|_, e| now < e.exp);
+        self.bytes = self.entries.values().map(|e| e.size).sum();
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+/// How `cache_key` builds the map key on top of the key template
+enum KeyStrategy {
+    // The rendered template as is (`path` and `path_host`)
+    Template,
+    // Query parameters sorted, so their order does not split entries
+    PathQuery,
+    // Plus the request headers named by the cached response's Vary
+    PathVary,
+}
+
+fn sort_query(key: &str) -> String {
+    match key.split_once('?') {
+        Some((base, query)) => {
+            let mut pairs: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+            pairs.sort_unstable();
+            format!("{base}?{}", pairs.join("&"))
+        }
+        None => key.to_string(),
+    }
+}
+
+fn vary_key(base: &str, names: &[String], r: &HttpRequest) -> String {
+    let mut k = base.to_string();
+    for name in names {
+        k.push_str(&format!("|{name}={}", r.get_header(name).unwrap_or("")));
+    }
+    k
+}
+
+/// Key suffixed with the coding negotiated from Accept-Encoding. Compression encodes a
+/// response before it is stored, so clients with different codings need separate entries.
+fn with_encoding(k: String, r: &HttpRequest) -> String {
+    let enc = r.get_header("Accept-Encoding").and_then(h::negotiate_encoding).unwrap_or("identity");
+    format!("{k}|enc={enc}")
+}
+
+/// Lowercased, sorted header names from a Vary value; None for `Vary: *`
+fn vary_names(v: &str) -> Option<Vec<String>> {
+    let mut names: Vec<String> = v.split(',').map(|n| n.trim().to_ascii_lowercase()).filter(|n| !n.is_empty()).collect();
+    if names.iter().any(|n| n == "*") {
+        return None;
+    }
+    names.sort_unstable();
+    names.dedup();
+    Some(names)
 }
 
 impl Module for Cache {
     fn name(&self) -> &str { "cache" }
-    fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+    fn reads_body(&self) -> bool { true }
+    fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
         if r.method != "GET" { return None; }
-        let k = r.path.clone();
+        let base = crate::script::runtime::interpolate(&self.key, r, c);
+        let k = match self.strategy {
+            KeyStrategy::Template => base,
+            KeyStrategy::PathQuery => sort_query(&base),
+            KeyStrategy::PathVary => {
+                let names = match self.vary.lock() {
+                    Ok(g) => g.get(&base).cloned(),
+                    Err(poisoned) => poisoned.into_inner().get(&base).cloned(),
+                };
+                let k = vary_key(&base, &names.unwrap_or_default(), r);
+                c.set("_cache_base", base);
+                k
+            }
+        };
+        let k = with_encoding(k, r);
+        c.set("_cache_key", k.clone());
+        if let Some(resp) = self.lookup(r, &k) {
+            return Some(resp);
+        }
+        if !self.coalesce { return None; }
+
+        let flight = {
+            let mut inflight = match self.inflight.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            match inflight.get(&k) {
+                Some(f) => Arc::clone(f),
+                None => {
+                    let flight = Arc::new(Flight { done: Mutex::new(false), cv: Condvar::new() });
+                    inflight.insert(k.clone(), Arc::clone(&flight));
+                    c.put("_cache_leader", Leader { key: k, flight, inflight: Arc::clone(&self.inflight) });
+                    return None;
+                }
+            }
+        };
+
+        flight.wait(self.wait);
+        let resp = self.lookup(r, &k)?;
+        crate::metrics::inc_coalesced();
+        Some(resp)
+    }
+
+    fn on_response(&self, req: &HttpRequest, resp: &mut HttpResponse, ctx: &mut Context) {
+        // Keyed as in `handle`; later modules may have rewritten the request since
+        let cacheable = resp.get_header("X-Cache").is_none() && resp.status_code == 200 && !ctx.get_bool("_no_buffer");
+        let key = match (&self.strategy, ctx.get("_cache_base")) {
+            // The response says which request headers it varies on; remember them for lookups
+            (KeyStrategy::PathVary, Some(base)) => vary_names(resp.get_header("Vary").unwrap_or("")).map(|names| {
+                let k = with_encoding(vary_key(base, &names, req), req);
+                match self.vary.lock() {
+                    Ok(mut g) => g.insert(base.to_string(), names),
+                    Err(poisoned) => poisoned.into_inner().insert(base.to_string(), names),
+                };
+                k
+            }),
+            _ => ctx.get("_cache_key").map(|k| k.to_string()),
+        };
+        if let (true, Some(key)) = (cacheable, key) {
+            let mut m = match self.cache.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => {
+                    crate::log::warn("cache: mutex recovered after panic, clearing cache");
+                    let mut inner = poisoned.into_inner();
+                    inner.clear();
+                    inner
+                }
+            };
+            m.insert(key, resp.clone(), Instant::now() + h::ttl_with_jitter(self.ttl, self.jitter));
+        }
+        // Wake followers now rather than when the context is dropped after the write
+        if let Some(leader) = ctx.take::<Leader>("_cache_leader") {
+            leader.release();
+        }
+    }
+}
+
+impl Cache {
+    fn lookup(&self, r: &HttpRequest, k: &str) -> Option<HttpResponse> {
         let mut m = match self.cache.lock() {
             Ok(guard) => guard,
             Err(poisoned) => {
@@ -121,59 +467,39 @@ impl Module for Cache {
             }
         };
 
-        if let Some(e) = m.get(&k) {
-            if Instant::now() < e.exp {
+        let now = Instant::now();
+        if let Some(e) = m.entries.get_mut(k) {
+            if now < e.exp {
+                e.used = now;
+                e.hits += 1;
                 if let Some(tag) = r.get_header("If-None-Match") {
                     if let Some(etag) = e.resp.get_header("ETag") {
                         if tag == etag {
-                            let resp = HttpResponse {
+                            let mut resp = HttpResponse {
                                 version: "HTTP/1.1".to_string(),
                                 status_code: 304,
                                 status_text: "Not Modified".to_string(),
                                 headers: vec![("X-Cache".to_string(), "HIT".to_string())],
                                 body: Vec::new(),
                             };
+                            if self.age_header {
+                                resp.set_header("Age", &e.age(now).to_string());
+                            }
                             return Some(resp);
                         }
                     }
                 }
                 let mut cached = e.resp.clone();
                 cached.headers.push(("X-Cache".to_string(), "HIT".to_string()));
+                if self.age_header {
+                    cached.set_header("Age", &e.age(now).to_string());
+                }
                 return Some(cached);
             } else {
-                m.remove(&k);
+                m.remove(k);
             }
         }
 
         None
     }
-
-    fn on_response(&self, _req: &HttpRequest, resp: &mut HttpResponse, _ctx: &mut Context) {
-        if resp.get_header("X-Cache").is_none() && resp.status_code == 200 {
-            let key = _req.path.clone();
-            let mut m = match self.cache.lock() {
-                Ok(guard) => guard,
-                Err(poisoned) => {
-                    crate::log::warn("cache: mutex recovered after panic, clearing cache");
-                    let mut inner = poisoned.into_inner();
-                    inner.clear();
-                    inner
-                }
-            };
-            while m.len() >= self.max {
-                let oldest = m.iter()
-                    .min_by_key(|(_, e)| e.exp)
-                    .map(|(k, _)| k.clone());
-                match oldest {
-                    Some(k) => { m.remove(&k); }
-                    None => break,
-                }
-            }
-            let entry = Entry {
-                resp: resp.clone(),
-                exp: Instant::now() + Duration::from_secs(self.ttl),
-            };
-            m.insert(key, entry);
-        }
-    }
 }
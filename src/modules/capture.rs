@@ -0,0 +1,92 @@
+// Sampled request/response capture to disk for debugging
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("dir".into(), toml::Value::String("captures".into()));
+    t.insert("capture_sample_rate".into(), toml::Value::Integer(100));
+    t.insert("max_body_bytes".into(), toml::Value::Integer(4096));
+    t.insert("max_files".into(), toml::Value::Integer(1000));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    // Writes to disk, so a missing section must not switch it on
+    if !h::config_bool(ctx.config, "capture", "enabled", false) { return; }
+    let dir = PathBuf::from(h::config_str(ctx.config, "capture", "dir", "captures"));
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        crate::log::error(&format!("capture: cannot create {}: {e}", dir.display()));
+        return;
+    }
+    ctx.pipeline.add(Box::new(Capture {
+        dir,
+        rate: h::config_u64(ctx.config, "capture", "capture_sample_rate", 100).max(1),
+        max_body: h::config_usize(ctx.config, "capture", "max_body_bytes", 4096),
+        max_files: h::config_u64(ctx.config, "capture", "max_files", 1000),
+        seen: AtomicU64::new(0),
+        written: AtomicU64::new(0),
+    }));
+}
+
+struct Capture {
+    dir: PathBuf,
+    // Write one in every `rate` exchanges
+    rate: u64,
+    // Bodies are truncated to this many bytes
+    max_body: usize,
+    // Stop capturing once this many files have been written
+    max_files: u64,
+    seen: AtomicU64,
+    written: AtomicU64,
+}
+
+// Credential headers whose values never reach the capture files
+const SECRET_HEADERS: &[&str] = &["Authorization", "Proxy-Authorization", "Cookie", "Set-Cookie"];
+
+fn redact(headers: &mut [(String, String)]) {
+    for (k, v) in headers.iter_mut() {
+        if SECRET_HEADERS.iter().any(|s| k.eq_ignore_ascii_case(s)) {
+            *v = "<redacted>".into();
+        }
+    }
+}
+
+impl Capture {
+    fn record(&self, req: &HttpRequest, resp: &HttpResponse, seq: u64) -> std::io::Result<()> {
+        let mut req = req.clone();
+        redact(&mut req.headers);
+        let mut resp = resp.clone();
+        redact(&mut resp.headers);
+        let raw = req.to_bytes();
+        let head = raw.len() - req.body.len();
+        let mut out = raw[..head + req.body.len().min(self.max_body)].to_vec();
+        out.extend_from_slice(b"\r\n\r\n");
+        out.extend_from_slice(&resp.head_bytes());
+        out.extend_from_slice(&resp.body[..resp.body.len().min(self.max_body)]);
+        let ts = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_micros();
+        std::fs::write(self.dir.join(format!("{ts}-{seq}.http")), out)
+    }
+}
+
+impl Module for Capture {
+    fn name(&self) -> &str { "capture" }
+    fn reads_body(&self) -> bool { true }
+
+    fn handle(&self, _: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        None
+    }
+
+    fn on_response(&self, req: &HttpRequest, resp: &mut HttpResponse, _: &mut Context) {
+        if !self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.rate) { return; }
+        let seq = self.written.fetch_add(1, Ordering::Relaxed);
+        if seq >= self.max_files { return; }
+        if let Err(e) = self.record(req, resp, seq) {
+            crate::log::warn(&format!("capture: write failed: {e}"));
+        }
+    }
+}
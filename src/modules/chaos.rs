@@ -0,0 +1,68 @@
+// Fault injection for resilience testing: added latency and synthetic error responses
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+use std::time::Duration;
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("delay_ms".into(), toml::Value::Integer(0));
+    t.insert("delay_rate".into(), toml::Value::Float(1.0));
+    t.insert("error_rate".into(), toml::Value::Float(0.0));
+    t.insert("error_status".into(), toml::Value::Integer(503));
+    t.insert("paths".into(), toml::Value::Array(vec![]));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    if !h::is_enabled(ctx.config, "chaos") { return; }
+    let error_status = match h::config_u64(ctx.config, "chaos", "error_status", 503) {
+        code @ 100..=599 => code as u16,
+        other => {
+            crate::log::warn(&format!("chaos: error_status {other} is not an HTTP status, using 503"));
+            503
+        }
+    };
+    let chaos = Chaos {
+        delay: Duration::from_millis(h::config_u64(ctx.config, "chaos", "delay_ms", 0)),
+        delay_rate: h::config_f64(ctx.config, "chaos", "delay_rate", 1.0),
+        error_rate: h::config_f64(ctx.config, "chaos", "error_rate", 0.0),
+        error_status,
+        paths: h::config_vec_str(ctx.config, "chaos", "paths"),
+    };
+    if (chaos.delay.is_zero() || chaos.delay_rate <= 0.0) && chaos.error_rate <= 0.0 { return; }
+    let scope = if chaos.paths.is_empty() { "all paths".to_string() } else { chaos.paths.join(", ") };
+    crate::log::warn(&format!(
+        "chaos: ENABLED - injecting {}ms delay on {:.0}% and {} errors on {:.0}% of requests ({scope}); do not run in production",
+        chaos.delay.as_millis(), chaos.delay_rate * 100.0, chaos.error_status, chaos.error_rate * 100.0,
+    ));
+    ctx.pipeline.add(Box::new(chaos));
+}
+
+struct Chaos {
+    delay: Duration,
+    // Fractions of matching requests, 0.0 to 1.0
+    delay_rate: f64,
+    error_rate: f64,
+    error_status: u16,
+    // Path prefixes to affect; empty means every request
+    paths: Vec<String>,
+}
+
+impl Module for Chaos {
+    fn name(&self) -> &str { "chaos" }
+    fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        if !self.paths.is_empty() && !self.paths.iter().any(|p| r.path.starts_with(p.as_str())) {
+            return None;
+        }
+        if !self.delay.is_zero() && h::chance(self.delay_rate) {
+            std::thread::sleep(self.delay);
+        }
+        if h::chance(self.error_rate) {
+            crate::log::debug(&format!("chaos: injected {} for {} {}", self.error_status, r.method, r.path));
+            return Some(HttpResponse::error(self.error_status, "Injected fault"));
+        }
+        None
+    }
+}
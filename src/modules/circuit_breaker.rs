@@ -3,7 +3,7 @@ use super::{helpers as h, Module};
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
 use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 use std::sync::Mutex;
 
@@ -11,11 +11,40 @@ const STATE_CLOSED: u8 = 0;
 const STATE_OPEN: u8 = 1;
 const STATE_HALF_OPEN: u8 = 2;
 
+// States of every live breaker, so health/admin views can report them
+static BREAKERS: Mutex<Vec<Weak<AtomicU8>>> = Mutex::new(Vec::new());
+
+/// Worst state across registered breakers, or `None` if the module is not loaded
+pub fn state() -> Option<&'static str> {
+    let mut list = match BREAKERS.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    list.retain(|w| w.strong_count() > 0);
+    let states: Vec<u8> = list.iter().filter_map(|w| w.upgrade()).map(|s| s.load(Ordering::Acquire)).collect();
+    if states.is_empty() {
+        None
+    } else if states.contains(&STATE_OPEN) {
+        Some("open")
+    } else if states.contains(&STATE_HALF_OPEN) {
+        Some("half-open")
+    } else {
+        Some("closed")
+    }
+}
+
+/// Whether the breaker guarding this request has opened since the module let it through
+pub fn open_for(c: &Context) -> bool {
+    c.take::<Arc<AtomicU8>>("_cb_state").is_some_and(|s| s.load(Ordering::Acquire) == STATE_OPEN)
+}
+
 pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(false));
     t.insert("failure_threshold".into(), toml::Value::Integer(5));
     t.insert("recovery_timeout".into(), toml::Value::Integer(30));
+    t.insert("warmup_secs".into(), toml::Value::Integer(0));
+    t.insert("paths".into(), toml::Value::Array(vec![]));
     t
 }
 
@@ -23,40 +52,104 @@ pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "circuit_breaker") { return; }
     let threshold = h::config_u64(ctx.config, "circuit_breaker", "failure_threshold", 5);
     let recovery = h::config_u64(ctx.config, "circuit_breaker", "recovery_timeout", 30);
+    let warmup = h::config_u64(ctx.config, "circuit_breaker", "warmup_secs", 0);
+    let mut paths: Vec<(String, Breaker)> = h::config_vec_str(ctx.config, "circuit_breaker", "paths")
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let b = Breaker::new(&format!("circuit_breaker[{p}]"));
+            (p, b)
+        })
+        .collect();
+    // Longest prefix first, so `/api/slow` wins over `/api`
+    paths.sort_by_key(|p| std::cmp::Reverse(p.0.len()));
     ctx.pipeline.add(Box::new(CircuitBreaker {
         threshold,
         recovery_secs: recovery,
-        failures: Arc::new(AtomicU64::new(0)),
-        state: Arc::new(AtomicU8::new(STATE_CLOSED)),
-        opened_at: Arc::new(Mutex::new(Instant::now())),
+        global: Breaker::new("circuit_breaker"),
+        paths,
+        warm_at: Instant::now() + Duration::from_secs(warmup),
     }));
 }
 
+/// State of one breaker: the global one, or one per configured path prefix
+struct Breaker {
+    label: String,
+    failures: AtomicU64,
+    state: Arc<AtomicU8>,
+    opened_at: Mutex<Instant>,
+}
+
+impl Breaker {
+    fn new(label: &str) -> Self {
+        let state = Arc::new(AtomicU8::new(STATE_CLOSED));
+        match BREAKERS.lock() {
+            Ok(mut g) => g.push(Arc::downgrade(&state)),
+            Err(poisoned) => poisoned.into_inner().push(Arc::downgrade(&state)),
+        }
+        Breaker {
+            label: label.to_string(),
+            failures: AtomicU64::new(0),
+            state,
+            opened_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn trip(&self, from: u8, why: &str) {
+        if self.state.compare_exchange(from, STATE_OPEN, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            match self.opened_at.lock() {
+                Ok(mut t) => *t = Instant::now(),
+                Err(poisoned) => *poisoned.into_inner() = Instant::now(),
+            }
+            crate::metrics::inc_cb_trips();
+            crate::log::warn(&format!("{}: OPEN {why}", self.label));
+        }
+    }
+}
+
 struct CircuitBreaker {
     threshold: u64,
     recovery_secs: u64,
-    failures: Arc<AtomicU64>,
-    state: Arc<AtomicU8>,
-    opened_at: Arc<Mutex<Instant>>,
+    // Covers every request whose path matches none of `paths`
+    global: Breaker,
+    // Independent breakers keyed on path prefix, longest first
+    paths: Vec<(String, Breaker)>,
+    // Failures before this (module start plus `warmup_secs`) are logged but not counted
+    warm_at: Instant,
+}
+
+impl CircuitBreaker {
+    fn breaker(&self, path: &str) -> &Breaker {
+        self.paths.iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map_or(&self.global, |(_, b)| b)
+    }
 }
 
 impl Module for CircuitBreaker {
     fn name(&self) -> &str { "circuit_breaker" }
 
-    fn handle(&self, _: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
-        let state = self.state.load(Ordering::Acquire);
+    fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        // Later modules may rewrite the path; on_response must settle the same breaker
+        if !self.paths.is_empty() {
+            c.set("_cb_path", r.path.clone());
+        }
+        let b = self.breaker(&r.path);
+        // Lets proxy_core stop retrying once other requests have tripped the breaker
+        c.put("_cb_state", Arc::clone(&b.state));
+        let state = b.state.load(Ordering::Acquire);
         match state {
             STATE_OPEN => {
-                let elapsed = match self.opened_at.lock() {
+                let elapsed = match b.opened_at.lock() {
                     Ok(t) => t.elapsed(),
                     Err(poisoned) => poisoned.into_inner().elapsed(),
                 };
                 if elapsed >= Duration::from_secs(self.recovery_secs) {
-                    if self.state.compare_exchange(
+                    if b.state.compare_exchange(
                         STATE_OPEN, STATE_HALF_OPEN,
                         Ordering::AcqRel, Ordering::Acquire,
                     ).is_ok() {
-                        crate::log::info("circuit_breaker: half-open, probing backend");
+                        crate::log::info(&format!("{}: half-open, probing backend", b.label));
                     }
                     None
                 } else {
@@ -69,41 +162,24 @@ impl Module for CircuitBreaker {
         }
     }
 
-    fn on_response(&self, _req: &HttpRequest, resp: &mut HttpResponse, _ctx: &mut Context) {
-        let state = self.state.load(Ordering::Acquire);
-        if resp.status_code >= 500 {
-            let count = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+    fn on_response(&self, req: &HttpRequest, resp: &mut HttpResponse, ctx: &mut Context) {
+        let b = self.breaker(ctx.get("_cb_path").unwrap_or(&req.path));
+        let state = b.state.load(Ordering::Acquire);
+        if resp.status_code >= 500 && Instant::now() < self.warm_at {
+            crate::log::debug(&format!("{}: {} during warmup, not counted", b.label, resp.status_code));
+        } else if resp.status_code >= 500 {
+            let count = b.failures.fetch_add(1, Ordering::Relaxed) + 1;
             if state == STATE_HALF_OPEN {
-                if self.state.compare_exchange(
-                    STATE_HALF_OPEN, STATE_OPEN,
-                    Ordering::AcqRel, Ordering::Acquire,
-                ).is_ok() {
-                    match self.opened_at.lock() {
-                        Ok(mut t) => *t = Instant::now(),
-                        Err(poisoned) => *poisoned.into_inner() = Instant::now(),
-                    }
-                    crate::metrics::inc_cb_trips();
-                    crate::log::warn("circuit_breaker: OPEN (half-open probe failed)");
-                }
+                b.trip(STATE_HALF_OPEN, "(half-open probe failed)");
             } else if count >= self.threshold {
-                if self.state.compare_exchange(
-                    STATE_CLOSED, STATE_OPEN,
-                    Ordering::AcqRel, Ordering::Acquire,
-                ).is_ok() {
-                    match self.opened_at.lock() {
-                        Ok(mut t) => *t = Instant::now(),
-                        Err(poisoned) => *poisoned.into_inner() = Instant::now(),
-                    }
-                    crate::metrics::inc_cb_trips();
-                    crate::log::warn(&format!("circuit_breaker: OPEN after {count} failures"));
-                }
+                b.trip(STATE_CLOSED, &format!("after {count} failures"));
             }
         } else {
             if state != STATE_CLOSED {
-                crate::log::info("circuit_breaker: CLOSED, backend recovered");
+                crate::log::info(&format!("{}: CLOSED, backend recovered", b.label));
             }
-            self.failures.store(0, Ordering::Relaxed);
-            self.state.store(STATE_CLOSED, Ordering::Release);
+            b.failures.store(0, Ordering::Relaxed);
+            b.state.store(STATE_CLOSED, Ordering::Release);
         }
     }
 }
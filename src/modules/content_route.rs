@@ -0,0 +1,93 @@
+// Content-type based backend routing
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("routes".into(), toml::Value::Table(toml::Table::new()));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    if !h::is_enabled(ctx.config, "content_route") { return; }
+    let routes = load_routes(ctx.config);
+    if !routes.is_empty() {
+        ctx.pipeline.add(Box::new(ContentRoute { routes }));
+    }
+}
+
+fn load_routes(c: &HashMap<String, toml::Value>) -> Vec<Route> {
+    let mut routes: Vec<Route> = c.get("content_route")
+        .and_then(|v| v.get("routes"))
+        .and_then(|v| v.as_table())
+        .map(|t| {
+            t.iter().filter_map(|(pattern, v)| {
+                let backends: Vec<String> = match v {
+                    toml::Value::String(s) => vec![s.clone()],
+                    toml::Value::Array(a) => a.iter().filter_map(|b| b.as_str()).map(|b| b.to_string()).collect(),
+                    _ => Vec::new(),
+                };
+                if backends.is_empty() {
+                    crate::log::warn(&format!("content_route: no backends for '{pattern}', skipping"));
+                    return None;
+                }
+                Some(Route { pattern: pattern.to_ascii_lowercase(), backends, idx: AtomicUsize::new(0) })
+            }).collect()
+        })
+        .unwrap_or_default();
+    // Most specific pattern wins
+    routes.sort_by_key(|r| std::cmp::Reverse(r.pattern.len()));
+    routes
+}
+
+struct Route {
+    pattern: String,
+    backends: Vec<String>,
+    idx: AtomicUsize,
+}
+
+impl Route {
+    fn matches(&self, header: &str) -> bool {
+        header.split(',').any(|media| {
+            let media = media.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+            media.starts_with(&self.pattern)
+        })
+    }
+
+    fn pick(&self) -> &str {
+        let len = self.backends.len();
+        let start = self.idx.fetch_add(1, Ordering::Relaxed) % len;
+        for offset in 0..len {
+            let addr = &self.backends[(start + offset) % len];
+            if super::active_health::is_healthy(addr) {
+                return addr;
+            }
+        }
+        &self.backends[start]
+    }
+}
+
+struct ContentRoute {
+    routes: Vec<Route>,
+}
+
+impl Module for ContentRoute {
+    fn name(&self) -> &str { "content_route" }
+    fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        for hdr in ["Content-Type", "Accept"] {
+            let value = match r.get_header(hdr) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some(route) = self.routes.iter().find(|rt| rt.matches(value)) {
+                c.set("_backend_addr", route.pick().to_string());
+                return None;
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,197 @@
+// Client address from Forwarded / X-Forwarded-* headers, and their generation (plus X-Real-IP
+// and optional client protocol / TLS headers)
+use super::{helpers as h, helpers::Cidr, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+use std::net::IpAddr;
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    // On by default so every backend sees X-Forwarded-For, X-Forwarded-Proto and X-Real-IP
+    t.insert("enabled".into(), toml::Value::Boolean(true));
+    t.insert("trusted_proxies".into(), toml::Value::Array(vec![]));
+    t.insert("emit".into(), toml::Value::String("x-forwarded".into()));
+    t.insert("client_ip_header".into(), toml::Value::String(String::new()));
+    t.insert("protocol_header".into(), toml::Value::String(String::new()));
+    t.insert("tls_header".into(), toml::Value::String(String::new()));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    if !h::is_enabled(ctx.config, "forwarded") { return; }
+    let trusted = h::config_vec_str(ctx.config, "forwarded", "trusted_proxies").iter()
+        .filter_map(|s| {
+            let net = Cidr::parse(s);
+            if net.is_none() {
+                crate::log::warn(&format!("forwarded: invalid trusted proxy '{s}', ignoring"));
+            }
+            net
+        })
+        .collect();
+    let emit = match h::config_str(ctx.config, "forwarded", "emit", "x-forwarded").to_lowercase().as_str() {
+        "forwarded" => Emit::Forwarded,
+        "both" => Emit::Both,
+        "none" => Emit::None,
+        "x-forwarded" => Emit::XForwarded,
+        other => {
+            crate::log::warn(&format!("forwarded: emit '{other}' unknown, using x-forwarded"));
+            Emit::XForwarded
+        }
+    };
+    let header = h::config_str(ctx.config, "forwarded", "client_ip_header", "");
+    let header = (!header.trim().is_empty()).then(|| header.trim().to_string());
+    let named = |key: &str| {
+        let name = h::config_str(ctx.config, "forwarded", key, "");
+        (!name.trim().is_empty()).then(|| name.trim().to_string())
+    };
+    let protocol_header = named("protocol_header");
+    let tls_header = named("tls_header");
+    ctx.pipeline.add(Box::new(Forwarded { trusted, emit, header, protocol_header, tls_header }));
+}
+
+enum Emit {
+    XForwarded,
+    Forwarded,
+    Both,
+    None,
+}
+
+/// Node names from the `for=` parameters of a Forwarded header, in hop order.
+/// Quotes, IPv6 brackets and ports are stripped.
+fn forwarded_for(v: &str) -> Vec<String> {
+    v.split(',')
+        .filter_map(|elem| {
+            elem.split(';').find_map(|pair| {
+                let (k, v) = pair.split_once('=')?;
+                k.trim().eq_ignore_ascii_case("for").then(|| node_name(v.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+fn node_name(v: &str) -> String {
+    if let Some(rest) = v.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or("").to_string();
+    }
+    match v.split_once(':') {
+        // IPv4 with a port; a bare IPv6 address has more than one colon
+        Some((host, port)) if !port.contains(':') => host.to_string(),
+        _ => v.to_string(),
+    }
+}
+
+/// `for=` value for `ip`; IPv6 addresses must be bracketed and quoted
+fn node_value(ip: &str) -> String {
+    if ip.contains(':') { format!("\"[{ip}]\"") } else { ip.to_string() }
+}
+
+struct Forwarded {
+    trusted: Vec<Cidr>,
+    emit: Emit,
+    // Only header to read the hop chain from; None means Forwarded, then X-Forwarded-For
+    header: Option<String>,
+    // Headers carrying the client's protocol (h1/h2/h3) and TLS version/cipher, e.g.
+    // X-Forwarded-Protocol and X-Forwarded-TLS; None sends neither
+    protocol_header: Option<String>,
+    tls_header: Option<String>,
+}
+
+impl Forwarded {
+    fn is_trusted(&self, ip: &str) -> bool {
+        ip.parse::<IpAddr>().is_ok_and(|a| self.trusted.iter().any(|n| n.contains(&a)))
+    }
+
+    /// Walk the hop chain from the nearest proxy back, stopping at the first untrusted address
+    fn client_ip(&self, r: &HttpRequest, peer: &str) -> String {
+        let list = |v: &str| v.split(',').map(|s| s.trim().to_string()).collect();
+        let mut hops: Vec<String> = match &self.header {
+            Some(name) if name.eq_ignore_ascii_case("Forwarded") => r.get_header(name).map(forwarded_for).unwrap_or_default(),
+            Some(name) => r.get_header(name).map(list).unwrap_or_default(),
+            None => match r.get_header("Forwarded") {
+                Some(v) => forwarded_for(v),
+                None => r.get_header("X-Forwarded-For").map(list).unwrap_or_default(),
+            },
+        };
+        let mut ip = peer.to_string();
+        while self.is_trusted(&ip) {
+            match hops.pop() {
+                Some(prev) if prev.parse::<IpAddr>().is_ok() => ip = prev,
+                _ => break,
+            }
+        }
+        ip
+    }
+
+    fn set_or_append(r: &mut HttpRequest, name: &str, value: &str, append: bool) {
+        let merged = match r.get_header(name) {
+            Some(old) if append => format!("{old}, {value}"),
+            _ => value.to_string(),
+        };
+        r.set_header(name, &merged);
+    }
+}
+
+impl Module for Forwarded {
+    fn name(&self) -> &str { "forwarded" }
+    fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        let peer = h::client_ip(c);
+        let from_proxy = self.is_trusted(&peer);
+        if from_proxy {
+            c.set("_client_ip", self.client_ip(r, &peer));
+            h::set_fingerprint(r, c);
+        }
+        let https = c.get("_tls_version").is_some() || c.get("_protocol").is_some_and(|p| p != "h1");
+        let proto = if https { "https" } else { "http" };
+        let host = r.get_header("Host").map(|s| s.to_string());
+
+        // Headers from an untrusted client are replaced, never extended
+        if matches!(self.emit, Emit::Forwarded | Emit::Both) {
+            let mut elem = format!("for={};proto={proto}", node_value(&peer));
+            if let Some(host) = &host {
+                elem.push_str(&format!(";host=\"{host}\""));
+            }
+            Self::set_or_append(r, "Forwarded", &elem, from_proxy);
+        } else if !from_proxy {
+            r.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("Forwarded"));
+        }
+        if matches!(self.emit, Emit::XForwarded | Emit::Both) {
+            Self::set_or_append(r, "X-Forwarded-For", &peer, from_proxy);
+            // The resolved client, not the nearest hop
+            r.set_header("X-Real-IP", &h::client_ip(c));
+            if !from_proxy || r.get_header("X-Forwarded-Proto").is_none() {
+                r.set_header("X-Forwarded-Proto", proto);
+            }
+            if let Some(host) = &host {
+                if !from_proxy || r.get_header("X-Forwarded-Host").is_none() {
+                    r.set_header("X-Forwarded-Host", host);
+                }
+            }
+        } else if !from_proxy {
+            r.headers.retain(|(k, _)| {
+                !["X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host", "X-Real-IP"].iter().any(|n| k.eq_ignore_ascii_case(n))
+            });
+        }
+
+        // A trusted proxy's view of its own client wins over ours
+        if let Some(name) = &self.protocol_header {
+            if !from_proxy || r.get_header(name).is_none() {
+                r.set_header(name, c.get("_protocol").unwrap_or("h1"));
+            }
+        }
+        if let Some(name) = &self.tls_header {
+            if !from_proxy || r.get_header(name).is_none() {
+                match c.get("_tls_version") {
+                    Some(version) => {
+                        let value = match c.get("_tls_cipher") {
+                            Some(cipher) => format!("version={version}; cipher={cipher}"),
+                            None => format!("version={version}"),
+                        };
+                        r.set_header(name, &value);
+                    }
+                    None => r.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name)),
+                }
+            }
+        }
+        None
+    }
+}
@@ -7,23 +7,58 @@ pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(true));
     t.insert("endpoint".into(), toml::Value::String("/health".into()));
+    t.insert("verify_config".into(), toml::Value::Boolean(false));
+    t.insert("config_path".into(), toml::Value::String(String::new()));
+    t.insert("fail_on_invalid_config".into(), toml::Value::Boolean(false));
+    t.insert("ready_endpoint".into(), toml::Value::String(String::new()));
     t
 }
 
 pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "health_check") { return; }
     let e = h::config_str(ctx.config, "health_check", "endpoint", "/health");
-    ctx.pipeline.add(Box::new(Health { endpoint: e }));
+    let verify = h::config_bool(ctx.config, "health_check", "verify_config", false).then(|| {
+        match h::config_str(ctx.config, "health_check", "config_path", "") {
+            p if p.is_empty() => crate::config::path(),
+            p => p,
+        }
+    });
+    let fail = h::config_bool(ctx.config, "health_check", "fail_on_invalid_config", false);
+    let ready = h::config_str(ctx.config, "health_check", "ready_endpoint", "");
+    let ready = (!ready.is_empty()).then_some(ready);
+    ctx.pipeline.add(Box::new(Health { endpoint: e, verify, fail, ready }));
 }
 
 struct Health {
     endpoint: String,
+    // Config file re-checked on every probe; empty path means the one the proxy started with
+    verify: Option<String>,
+    // Answer 503 rather than 200 while the config file is invalid
+    fail: bool,
+    // Readiness probe; answers 503 once shutdown begins, while `endpoint` stays 200
+    ready: Option<String>,
 }
 impl Module for Health {
     fn name(&self) -> &str { "health_check" }
     fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        if self.ready.as_ref().is_some_and(|p| *p == r.path) {
+            return Some(if crate::server::draining() {
+                h::json_response(503, r#"{"status":"draining"}"#)
+            } else {
+                h::json_response(200, r#"{"status":"ready"}"#)
+            });
+        }
         if r.path == self.endpoint {
-            Some(h::json_response(200, r#"{"status":"ok"}"#))
+            let Some(path) = &self.verify else {
+                return Some(h::json_response(200, r#"{"status":"ok"}"#));
+            };
+            match crate::config::verify_file(path) {
+                Ok(issues) if issues.is_empty() => Some(h::json_response(200, r#"{"status":"ok","config_valid":true}"#)),
+                _ => {
+                    let code = if self.fail { 503 } else { 200 };
+                    Some(h::json_response(code, r#"{"status":"ok","config_valid":false}"#))
+                }
+            }
         } else {
             None
         }
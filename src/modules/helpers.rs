@@ -1,7 +1,15 @@
 // Shared utilities for modules
 use crate::context::Context;
-use crate::http::HttpResponse;
+use crate::http::{HttpRequest, HttpResponse};
 use std::collections::HashMap;
+use std::net::{IpAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+static JITTER_SEED: AtomicU64 = AtomicU64::new(0);
+static REQUEST_SEQ: AtomicU64 = AtomicU64::new(0);
+static BOUND: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
 pub fn is_enabled(c: &HashMap<String, toml::Value>, m: &str) -> bool {
     config_bool(c, m, "enabled", true)
@@ -23,6 +31,13 @@ pub fn config_usize(c: &HashMap<String, toml::Value>, m: &str, k: &str, d: usize
         .unwrap_or(d)
 }
 
+/// A float setting; integer values like `1` are accepted too
+pub fn config_f64(c: &HashMap<String, toml::Value>, m: &str, k: &str, d: f64) -> f64 {
+    c.get(m).and_then(|v| v.get(k))
+        .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+        .unwrap_or(d)
+}
+
 pub fn config_str(c: &HashMap<String, toml::Value>, m: &str, k: &str, d: &str) -> String {
     c.get(m).and_then(|v| v.get(k)).and_then(|v| v.as_str()).unwrap_or(d).to_string()
 }
@@ -33,10 +48,269 @@ pub fn config_vec_str(c: &HashMap<String, toml::Value>, m: &str, k: &str) -> Vec
     }).unwrap_or_default()
 }
 
+/// Match `s` against a pattern where `*` stands for any run of characters
+pub fn glob_match(s: &str, pattern: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return s == pattern;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !s.starts_with(first) || !s.ends_with(last) || s.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &s[first.len()..s.len() - last.len()];
+    for mid in &parts[1..parts.len() - 1] {
+        match rest.find(mid) {
+            Some(i) => rest = &rest[i + mid.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// An address range like `10.0.0.0/8`; a bare address is a single host
+pub struct Cidr {
+    net: IpAddr,
+    bits: u32,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let (ip, bits) = match s.split_once('/') {
+            Some((ip, b)) => (ip.parse::<IpAddr>().ok()?, Some(b.parse::<u32>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let bits = bits.unwrap_or(max);
+        (bits <= max).then_some(Cidr { net: ip, bits })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.net, ip) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => {
+                let mask = u32::MAX.checked_shl(32 - self.bits).unwrap_or(0);
+                u32::from(n) & mask == u32::from(*a) & mask
+            }
+            (IpAddr::V6(n), IpAddr::V6(a)) => {
+                let mask = u128::MAX.checked_shl(128 - self.bits).unwrap_or(0);
+                u128::from(n) & mask == u128::from(*a) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Bind a module-owned listener once per process. `None` means `addr` is already served,
+/// so a pipeline reload keeps the running listener (and its settings) instead of rebinding.
+pub fn bind_once(addr: &str) -> Option<std::io::Result<TcpListener>> {
+    let mut bound = match BOUND.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if bound.iter().any(|a| a == addr) {
+        return None;
+    }
+    let result = TcpListener::bind(addr);
+    // Ephemeral ports never collide, so they are not remembered
+    if result.is_ok() && !addr.ends_with(":0") {
+        bound.push(addr.to_string());
+    }
+    Some(result)
+}
+
 pub fn client_ip(c: &Context) -> String {
     c.get("_client_ip").unwrap_or("?").to_string()
 }
 
+/// Last probe result for a backend; `checked` is None until the first probe
+#[derive(Clone, Copy)]
+pub struct BackendHealth {
+    pub up: bool,
+    pub checked: Option<std::time::Instant>,
+}
+
+impl BackendHealth {
+    pub const UNCHECKED: BackendHealth = BackendHealth { up: true, checked: None };
+
+    /// Record a probe result, returning the previous up state
+    pub fn record(&mut self, ok: bool) -> bool {
+        self.checked = Some(std::time::Instant::now());
+        std::mem::replace(&mut self.up, ok)
+    }
+}
+
+// Request headers that, with the client IP, make up `_client_fingerprint`
+const FINGERPRINT_HEADERS: &[&str] = &["User-Agent", "Accept", "Accept-Language", "Accept-Encoding"];
+
+/// Set `_client_fingerprint`: an FNV-1a hash of the client IP and a few client-chosen headers,
+/// so clients behind one address can be told apart
+pub fn set_fingerprint(r: &HttpRequest, c: &mut Context) {
+    let ip = client_ip(c);
+    let parts: Vec<&str> = std::iter::once(ip.as_str())
+        .chain(FINGERPRINT_HEADERS.iter().map(|name| r.get_header(name).unwrap_or("")))
+        .collect();
+    c.set("_client_fingerprint", format!("{:016x}", fnv1a(&parts)));
+}
+
+/// FNV-1a over `parts`, each followed by a 0xff separator so ("ab", "c") and ("a", "bc") differ
+pub fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for &b in part.as_bytes().iter().chain(&[0xff]) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Split a backend entry like `10.0.0.5:8080#timeout=60` into its address and timeout override
+pub fn parse_backend(entry: &str) -> (String, Option<u64>) {
+    let (addr, opts) = match entry.split_once('#') {
+        Some(p) => p,
+        None => return (entry.trim().to_string(), None),
+    };
+    let timeout = opts.split('&')
+        .find_map(|kv| kv.trim().strip_prefix("timeout="))
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|&t| t > 0);
+    if timeout.is_none() {
+        crate::log::warn(&format!("backend '{entry}': unrecognised options '{opts}', ignoring"));
+    }
+    (addr.trim().to_string(), timeout)
+}
+
+/// Whether active_health last saw `addr` as healthy; unprobed backends count as healthy
+pub fn backend_healthy(addr: &str) -> bool {
+    super::active_health::is_healthy(addr)
+}
+
+/// Entries and bytes held by the cache module, zero when it is not loaded
+pub fn cache_usage() -> (usize, usize) {
+    super::cache::usage().unwrap_or((0, 0))
+}
+
+/// The circuit breaker covering this request is open
+pub fn breaker_open(c: &Context) -> bool {
+    super::circuit_breaker::open_for(c)
+}
+
+/// Other healthy backends the load balancer would pick next, in order, for retries
+pub fn set_fallbacks(c: &mut Context, backends: Vec<(String, Option<u64>)>) {
+    if !backends.is_empty() {
+        c.put("_backend_fallbacks", backends);
+    }
+}
+
+/// proxy_core's `route_timeouts` entry for `path` as (prefix, seconds)
+pub fn route_timeout(path: &str) -> Option<(String, u64)> {
+    super::proxy_core::route_timeout(path)
+}
+
+/// Route the request to `addr`, with an optional per-backend timeout in seconds
+pub fn set_backend(c: &mut Context, addr: &str, timeout: Option<u64>) {
+    super::load_balancer::retarget(c, addr);
+    c.set("_backend_addr", addr.to_string());
+    if let Some(t) = timeout {
+        c.put("_backend_timeout", (addr.to_string(), t));
+    }
+}
+
+/// Timeout for the selected backend: its override if one was set for that same address
+/// (a later module may have re-routed the request), else `default`
+pub fn backend_timeout(c: &Context, default: u64) -> u64 {
+    match (c.take::<(String, u64)>("_backend_timeout"), c.get("_backend_addr")) {
+        (Some((addr, t)), Some(cur)) if addr == cur => *t,
+        _ => default,
+    }
+}
+
+/// `ttl` seconds plus a random offset of up to `jitter` seconds, so entries stored
+/// together don't all expire together
+pub fn ttl_with_jitter(ttl: u64, jitter: u64) -> Duration {
+    let base = Duration::from_secs(ttl);
+    let range = jitter.saturating_mul(1000);
+    if range == 0 {
+        return base;
+    }
+    base + Duration::from_millis(next_jitter() % (range + 1))
+}
+
+/// True for roughly `rate` (0.0 to 1.0) of calls
+pub fn chance(rate: f64) -> bool {
+    if rate >= 1.0 { return true; }
+    if rate <= 0.0 { return false; }
+    (next_jitter() % 1_000_000) < (rate * 1_000_000.0) as u64
+}
+
+/// Cheap xorshift64; races between threads only perturb the sequence
+pub(crate) fn next_jitter() -> u64 {
+    let mut x = JITTER_SEED.load(Ordering::Relaxed);
+    if x == 0 {
+        let t = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        x = (t.as_nanos() as u64) | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    JITTER_SEED.store(x, Ordering::Relaxed);
+    x
+}
+
+/// Bytes from the TLS provider's CSPRNG, falling back to xorshift if it fails
+fn random_bytes<const N: usize>() -> [u8; N] {
+    static RNG: OnceLock<&'static dyn rustls::crypto::SecureRandom> = OnceLock::new();
+    let mut b = [0u8; N];
+    let rng = RNG.get_or_init(|| rustls::crypto::ring::default_provider().secure_random);
+    if rng.fill(&mut b).is_err() {
+        for chunk in b.chunks_mut(8) {
+            chunk.copy_from_slice(&next_jitter().to_le_bytes()[..chunk.len()]);
+        }
+    }
+    b
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+pub const REQUEST_ID_FORMATS: &[&str] = &["timestamp", "uuid4", "hex", "short"];
+
+/// New request ID in one of `REQUEST_ID_FORMATS`; unknown formats use `timestamp`
+pub fn generate_request_id(format: &str) -> String {
+    match format {
+        "uuid4" => {
+            let mut b = random_bytes::<16>();
+            b[6] = (b[6] & 0x0f) | 0x40;
+            b[8] = (b[8] & 0x3f) | 0x80;
+            format!("{}-{}-{}-{}-{}", hex(&b[..4]), hex(&b[4..6]), hex(&b[6..8]), hex(&b[8..10]), hex(&b[10..]))
+        }
+        "hex" => hex(&random_bytes::<16>()),
+        "short" => hex(&random_bytes::<4>()),
+        _ => {
+            let ts = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+            let seq = REQUEST_SEQ.fetch_add(1, Ordering::Relaxed);
+            format!("{ts:x}-{seq:04x}")
+        }
+    }
+}
+
+/// `s` as a quoted JSON string
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
 pub fn json_response(c: u16, j: &str) -> HttpResponse {
     HttpResponse {
         version: "HTTP/1.1".to_string(),
@@ -109,3 +383,95 @@ fn stream_copy(mut r: std::net::TcpStream, mut w: std::net::TcpStream, buf_size:
     }
     let _ = w.shutdown(std::net::Shutdown::Write);
 }
+
+// Content codings we can produce, in our order of preference when q-values tie
+const ENCODINGS: &[&str] = &["br", "gzip", "deflate"];
+
+/// Best coding for an `Accept-Encoding` header by q-value (RFC 7231 5.3.4); `None` means
+/// identity. `q=0` forbids a coding, `*` covers codings not listed, and an explicit
+/// `identity` weighted above every coding we offer wins. A malformed weight counts as 0.
+pub fn negotiate_encoding(accept: &str) -> Option<&'static str> {
+    let mut q: HashMap<String, f32> = HashMap::new();
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        if name.is_empty() { continue; }
+        let weight = match parts.map(str::trim).find(|p| p.len() >= 2 && p[..2].eq_ignore_ascii_case("q=")) {
+            Some(p) => p[2..].parse::<f32>().ok().filter(|w| (0.0..=1.0).contains(w)).unwrap_or(0.0),
+            None => 1.0,
+        };
+        q.entry(name).or_insert(weight);
+    }
+    let mut best: Option<(&'static str, f32)> = None;
+    for &enc in ENCODINGS {
+        let w = q.get(enc).or_else(|| q.get("*")).copied().unwrap_or(0.0);
+        if w > 0.0 && best.is_none_or(|(_, b)| w > b) {
+            best = Some((enc, w));
+        }
+    }
+    let (enc, w) = best?;
+    match q.get("identity") {
+        Some(&identity) if identity > w => None,
+        _ => Some(enc),
+    }
+}
+
+pub fn is_compressible(ct: &str) -> bool {
+    ct.starts_with("text/")
+        || ct.contains("json")
+        || ct.contains("xml")
+        || ct.contains("javascript")
+        || ct.contains("svg")
+        || ct.contains("css")
+}
+
+/// Encode `resp` with `encoding` (from `negotiate_encoding`) unless it is under `min_size`,
+/// already encoded, not a compressible type, or would not get smaller
+pub fn compress_response(resp: &mut HttpResponse, encoding: &str, min_size: usize) {
+    use std::io::Write;
+    if resp.body.len() < min_size { return; }
+    if resp.get_header("Content-Encoding").is_some() { return; }
+    if !is_compressible(resp.get_header("Content-Type").unwrap_or("")) { return; }
+
+    let compressed = match encoding {
+        "br" => {
+            // Quality 4 with a 4 MiB window: close to gzip's speed at a better ratio
+            let mut enc = brotli::CompressorWriter::new(Vec::new(), 4096, 4, 22);
+            if enc.write_all(&resp.body).is_err() { return; }
+            enc.into_inner()
+        }
+        "gzip" => {
+            let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            if enc.write_all(&resp.body).is_err() { return; }
+            match enc.finish() {
+                Ok(v) => v,
+                Err(_) => return,
+            }
+        }
+        "deflate" => {
+            // HTTP's "deflate" is the zlib format, not raw deflate
+            let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+            if enc.write_all(&resp.body).is_err() { return; }
+            match enc.finish() {
+                Ok(v) => v,
+                Err(_) => return,
+            }
+        }
+        _ => return,
+    };
+    if compressed.len() >= resp.body.len() { return; }
+
+    resp.body = compressed;
+    resp.set_header("Content-Encoding", encoding);
+    resp.set_header("Content-Length", &resp.body.len().to_string());
+    resp.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("Transfer-Encoding"));
+    // Shared caches downstream must not hand this body to clients that cannot decode it
+    let vary = match resp.get_header("Vary") {
+        Some(v) if v.split(',').any(|n| n.trim() == "*" || n.trim().eq_ignore_ascii_case("Accept-Encoding")) => None,
+        Some(v) => Some(format!("{v}, Accept-Encoding")),
+        None => Some("Accept-Encoding".to_string()),
+    };
+    if let Some(v) = vary {
+        resp.set_header("Vary", &v);
+    }
+}
@@ -1,36 +1,108 @@
-// Load balancer with round-robin selection
+// Load balancer with round-robin, least-latency (power of two choices), least-connections
+// or client IP hash selection, steering away from backends whose connection pool is saturated
 use super::{helpers as h, Module};
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Weight of the newest sample in the latency EWMA
+const EWMA_ALPHA: f64 = 0.3;
 
 pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(false));
     t.insert("backends".into(), toml::Value::Array(vec![]));
+    t.insert("strategy".into(), toml::Value::String("round_robin".into()));
+    t.insert("down_status".into(), toml::Value::Integer(503));
+    t.insert("down_body".into(), toml::Value::String("All backends are down".into()));
+    t.insert("down_retry_after".into(), toml::Value::Integer(10));
     t
 }
 
 pub fn register(ctx: &mut super::ModuleContext) {
+    let down = AllDown {
+        status: h::config_u64(ctx.config, "load_balancer", "down_status", 503).clamp(100, 599) as u16,
+        body: h::config_str(ctx.config, "load_balancer", "down_body", "All backends are down"),
+        retry_after: h::config_u64(ctx.config, "load_balancer", "down_retry_after", 10),
+    };
     if !h::is_enabled(ctx.config, "load_balancer") {
-        ctx.pipeline.add(Box::new(Single { addr: ctx.server.backend_addr.clone() }));
+        ctx.pipeline.add(Box::new(Single { addr: ctx.server.backend_addr.clone(), down }));
         return;
     }
-    let bs = h::config_vec_str(ctx.config, "load_balancer", "backends");
+    let (bs, timeouts): (Vec<String>, Vec<Option<u64>>) = h::config_vec_str(ctx.config, "load_balancer", "backends")
+        .iter()
+        .map(|b| h::parse_backend(b))
+        .unzip();
     if bs.is_empty() {
-        ctx.pipeline.add(Box::new(Single { addr: ctx.server.backend_addr.clone() }));
-    } else {
-        ctx.pipeline.add(Box::new(RoundRobin { backends: bs, idx: Arc::new(AtomicUsize::new(0)) }));
+        ctx.pipeline.add(Box::new(Single { addr: ctx.server.backend_addr.clone(), down }));
+        return;
+    }
+    let strategy = h::config_str(ctx.config, "load_balancer", "strategy", "round_robin");
+    match strategy.as_str() {
+        "least_latency" | "p2c" => {
+            let ewma = Mutex::new(vec![0.0; bs.len()]);
+            ctx.pipeline.add(Box::new(LeastLatency { backends: bs, timeouts, ewma, down }));
+        }
+        "least_conn" => {
+            let active = Arc::new((0..bs.len()).map(|_| AtomicUsize::new(0)).collect());
+            ctx.pipeline.add(Box::new(LeastConn { backends: Arc::new(bs), timeouts, active, idx: AtomicUsize::new(0), down }));
+        }
+        "ip_hash" => {
+            ctx.pipeline.add(Box::new(IpHash { backends: bs, timeouts, down }));
+        }
+        other => {
+            if other != "round_robin" {
+                crate::log::warn(&format!("load_balancer: unknown strategy '{other}', using round_robin"));
+            }
+            ctx.pipeline.add(Box::new(RoundRobin { backends: bs, timeouts, idx: Arc::new(AtomicUsize::new(0)), down }));
+        }
+    }
+}
+
+/// Every pooled connection to `addr` is busy; such backends are only picked when all are
+fn saturated(addr: &str) -> bool {
+    crate::resolve::resolve(addr).is_ok_and(|a| crate::pool::global_pool().is_saturated(&a))
+}
+
+/// Healthy backends other than `picked`, in `order`, for proxy_core to retry against
+fn set_fallbacks(c: &mut Context, backends: &[String], timeouts: &[Option<u64>], order: impl Iterator<Item = usize>, picked: usize) {
+    let rest = order
+        .filter(|&i| i != picked && super::active_health::is_healthy(&backends[i]))
+        .map(|i| (backends[i].clone(), timeouts[i]))
+        .collect();
+    h::set_fallbacks(c, rest);
+}
+
+/// Response served when active health checks report every backend down
+struct AllDown {
+    status: u16,
+    body: String,
+    retry_after: u64,
+}
+
+impl AllDown {
+    fn response(&self) -> HttpResponse {
+        crate::log::warn("load_balancer: no healthy backends");
+        let mut resp = HttpResponse::error(self.status, &self.body);
+        if self.retry_after > 0 {
+            resp.set_header("Retry-After", &self.retry_after.to_string());
+        }
+        resp
     }
 }
 
 struct Single {
     addr: String,
+    down: AllDown,
 }
 impl Module for Single {
     fn name(&self) -> &str { "load_balancer" }
     fn handle(&self, _: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        if !super::active_health::is_healthy(&self.addr) {
+            return Some(self.down.response());
+        }
         c.set("_backend_addr", self.addr.clone());
         None
     }
@@ -38,7 +110,10 @@ impl Module for Single {
 
 struct RoundRobin {
     backends: Vec<String>,
+    // Per-backend timeout overrides (`addr#timeout=N`), parallel to `backends`
+    timeouts: Vec<Option<u64>>,
     idx: Arc<AtomicUsize>,
+    down: AllDown,
 }
 impl Module for RoundRobin {
     fn name(&self) -> &str { "load_balancer" }
@@ -49,14 +124,199 @@ impl Module for RoundRobin {
             return Some(HttpResponse::error(503, "No backends available"));
         }
         let start = self.idx.fetch_add(1, Ordering::Relaxed) % len;
-        for offset in 0..len {
-            let addr = &self.backends[(start + offset) % len];
-            if super::active_health::is_healthy(addr) {
-                c.set("_backend_addr", addr.clone());
-                return None;
+        let rotation = || (0..len).map(move |offset| (start + offset) % len);
+        let mut pick = None;
+        for i in rotation() {
+            if !super::active_health::is_healthy(&self.backends[i]) { continue; }
+            if !saturated(&self.backends[i]) {
+                pick = Some(i);
+                break;
+            }
+            pick.get_or_insert(i);
+        }
+        let Some(i) = pick else { return Some(self.down.response()) };
+        h::set_backend(c, &self.backends[i], self.timeouts[i]);
+        set_fallbacks(c, &self.backends, &self.timeouts, rotation(), i);
+        None
+    }
+}
+
+struct LeastLatency {
+    backends: Vec<String>,
+    timeouts: Vec<Option<u64>>,
+    // Smoothed response time per backend in ms; 0 means not yet measured
+    ewma: Mutex<Vec<f64>>,
+    down: AllDown,
+}
+
+/// Random index below `n`
+fn rand(n: usize) -> usize {
+    (h::next_jitter() % n as u64) as usize
+}
+
+impl Module for LeastLatency {
+    fn name(&self) -> &str { "load_balancer" }
+    fn handle(&self, _: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        let healthy: Vec<usize> = (0..self.backends.len())
+            .filter(|&i| super::active_health::is_healthy(&self.backends[i]))
+            .collect();
+        let pick = match healthy.len() {
+            0 => return Some(self.down.response()),
+            1 => healthy[0],
+            n => {
+                let a = rand(n);
+                let b = (a + 1 + rand(n - 1)) % n;
+                let (a, b) = (healthy[a], healthy[b]);
+                match (saturated(&self.backends[a]), saturated(&self.backends[b])) {
+                    (true, false) => b,
+                    (false, true) => a,
+                    _ => {
+                        let ewma = match self.ewma.lock() {
+                            Ok(g) => g,
+                            Err(poisoned) => poisoned.into_inner(),
+                        };
+                        if ewma[b] < ewma[a] { b } else { a }
+                    }
+                }
             }
+        };
+        h::set_backend(c, &self.backends[pick], self.timeouts[pick]);
+        let mut order = healthy;
+        {
+            let ewma = match self.ewma.lock() {
+                Ok(g) => g,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            order.sort_by(|&a, &b| ewma[a].total_cmp(&ewma[b]));
+        }
+        set_fallbacks(c, &self.backends, &self.timeouts, order.into_iter(), pick);
+        c.put("_lb_started", Instant::now());
+        None
+    }
+
+    fn on_response(&self, _req: &HttpRequest, _resp: &mut HttpResponse, ctx: &mut Context) {
+        let started = match ctx.take::<Instant>("_lb_started") {
+            Some(t) => *t,
+            None => return,
+        };
+        let idx = match ctx.get("_backend_addr").and_then(|a| self.backends.iter().position(|b| b == a)) {
+            Some(i) => i,
+            None => return,
+        };
+        let sample = started.elapsed().as_secs_f64() * 1000.0;
+        let mut ewma = match self.ewma.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        ewma[idx] = if ewma[idx] == 0.0 { sample } else { EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * ewma[idx] };
+    }
+}
+
+struct LeastConn {
+    backends: Arc<Vec<String>>,
+    timeouts: Vec<Option<u64>>,
+    // Requests in flight per backend, parallel to `backends`
+    active: Arc<Vec<AtomicUsize>>,
+    // Rotates the scan start so ties are shared round-robin
+    idx: AtomicUsize,
+    down: AllDown,
+}
+
+/// One in-flight request on a backend. Kept in the request context, so the count drops
+/// when the request is finished with on every path: a proxy_core error response, a
+/// timeout, or a streamed body the server relays after the pipeline has returned.
+struct InFlight {
+    backends: Arc<Vec<String>>,
+    active: Arc<Vec<AtomicUsize>>,
+    // Index of the backend being counted; out of range once routed off the balanced set
+    on: AtomicUsize,
+}
+
+impl InFlight {
+    fn new(backends: &Arc<Vec<String>>, active: &Arc<Vec<AtomicUsize>>, i: usize) -> Self {
+        active[i].fetch_add(1, Ordering::AcqRel);
+        InFlight { backends: Arc::clone(backends), active: Arc::clone(active), on: AtomicUsize::new(i) }
+    }
+
+    /// Count the request against `addr` instead, e.g. when proxy_core retries on a fallback
+    fn move_to(&self, addr: &str) {
+        let next = self.backends.iter().position(|b| b == addr).unwrap_or(usize::MAX);
+        let prev = self.on.swap(next, Ordering::AcqRel);
+        if prev == next {
+            return;
         }
-        c.set("_backend_addr", self.backends[start].clone());
+        if let Some(n) = self.active.get(next) {
+            n.fetch_add(1, Ordering::AcqRel);
+        }
+        if let Some(n) = self.active.get(prev) {
+            n.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(n) = self.active.get(*self.on.get_mut()) {
+            n.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Follow a re-routed request with its least-connections in-flight count
+pub(crate) fn retarget(c: &Context, addr: &str) {
+    if let Some(f) = c.take::<InFlight>("_lb_inflight") {
+        f.move_to(addr);
+    }
+}
+
+impl Module for LeastConn {
+    fn name(&self) -> &str { "load_balancer" }
+    fn handle(&self, _: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        let len = self.backends.len();
+        let start = self.idx.fetch_add(1, Ordering::Relaxed) % len;
+        let mut order: Vec<usize> = (0..len)
+            .map(|offset| (start + offset) % len)
+            .filter(|&i| super::active_health::is_healthy(&self.backends[i]))
+            .collect();
+        // A stable sort keeps equals in rotation order
+        order.sort_by_key(|&i| (saturated(&self.backends[i]), self.active[i].load(Ordering::Acquire)));
+        let Some(&i) = order.first() else { return Some(self.down.response()) };
+        c.put("_lb_inflight", InFlight::new(&self.backends, &self.active, i));
+        h::set_backend(c, &self.backends[i], self.timeouts[i]);
+        set_fallbacks(c, &self.backends, &self.timeouts, order.into_iter(), i);
+        None
+    }
+}
+
+/// Sticky routing on `_client_ip` with rendezvous (highest random weight) hashing: each
+/// client ranks every backend by hash(ip, backend) and takes the best healthy one, so a
+/// backend going down only moves the clients that were on it.
+struct IpHash {
+    backends: Vec<String>,
+    timeouts: Vec<Option<u64>>,
+    down: AllDown,
+}
+
+fn hrw_weight(ip: &str, backend: &str) -> u64 {
+    let mut hash = h::fnv1a(&[ip, backend]);
+    // FNV mixes the last bytes poorly; finish with a murmur3 avalanche step
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^ (hash >> 33)
+}
+
+impl Module for IpHash {
+    fn name(&self) -> &str { "load_balancer" }
+    fn handle(&self, _: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        let ip = h::client_ip(c);
+        let mut order: Vec<usize> = (0..self.backends.len())
+            .filter(|&i| super::active_health::is_healthy(&self.backends[i]))
+            .collect();
+        // Best first; a client's fallbacks are as stable as its first choice
+        order.sort_by_key(|&i| std::cmp::Reverse(hrw_weight(&ip, &self.backends[i])));
+        let Some(&i) = order.first() else { return Some(self.down.response()) };
+        h::set_backend(c, &self.backends[i], self.timeouts[i]);
+        set_fallbacks(c, &self.backends, &self.timeouts, order.into_iter(), i);
         None
     }
 }
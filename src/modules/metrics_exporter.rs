@@ -1,29 +1,96 @@
 // Prometheus metrics exporter
 use super::{helpers as h, Module};
 use crate::context::Context;
-use crate::http::{HttpRequest, HttpResponse};
+use crate::http::{HttpRequest, HttpResponse, ReadResult};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const MAX_SCRAPE_CONNECTIONS: usize = 4;
 
 pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(false));
     t.insert("endpoint".into(), toml::Value::String("/metrics".into()));
+    t.insert("listen_addr".into(), toml::Value::String("".into()));
     t
 }
 
 pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "metrics_exporter") { return; }
     let ep = h::config_str(ctx.config, "metrics_exporter", "endpoint", "/metrics");
-    ctx.pipeline.add(Box::new(MetricsExporter { endpoint: ep }));
+    // With a listen_addr, metrics are served only there and never through the pipeline
+    let addr = h::config_str(ctx.config, "metrics_exporter", "listen_addr", "");
+    if addr.is_empty() {
+        ctx.pipeline.add(Box::new(MetricsExporter { endpoint: ep }));
+        return;
+    }
+    let listener = match h::bind_once(&addr) {
+        Some(Ok(l)) => l,
+        Some(Err(e)) => {
+            crate::log::error(&format!("metrics_exporter: {e}"));
+            return;
+        }
+        None => {
+            crate::log::debug(&format!("metrics_exporter: already listening on {addr}"));
+            return;
+        }
+    };
+    // Polled, so the thread notices shutdown and releases the port without a new connection
+    if let Err(e) = listener.set_nonblocking(true) {
+        crate::log::error(&format!("metrics_exporter: {e}"));
+        return;
+    }
+    crate::log::module_loaded(&format!("metrics_exporter ({addr})"));
+    let exporter = Arc::new(MetricsExporter { endpoint: ep });
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        while !crate::server::SHUTDOWN.load(Ordering::Acquire) {
+            let conn = match listener.accept() {
+                Ok((conn, _)) => conn,
+                Err(e) => {
+                    if e.kind() != std::io::ErrorKind::WouldBlock {
+                        crate::log::debug(&format!("metrics_exporter: accept failed: {e}"));
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+            };
+            let _ = conn.set_nonblocking(false);
+            if active.load(Ordering::Acquire) >= MAX_SCRAPE_CONNECTIONS {
+                crate::log::warn("metrics_exporter: connection limit reached, rejecting");
+                reject(conn);
+                continue;
+            }
+            let e = Arc::clone(&exporter);
+            let counter = Arc::clone(&active);
+            counter.fetch_add(1, Ordering::AcqRel);
+            thread::spawn(move || {
+                e.serve(conn);
+                counter.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+        crate::log::info("metrics_exporter: listener stopped");
+    });
+}
+
+/// Tell a scraper over the connection limit to back off rather than just hanging up
+fn reject(mut s: TcpStream) {
+    let _ = s.set_write_timeout(Some(Duration::from_secs(1)));
+    let mut resp = HttpResponse::error(503, "Service Unavailable");
+    resp.set_header("Connection", "close");
+    let _ = s.write_all(&resp.to_bytes());
 }
 
 struct MetricsExporter {
     endpoint: String,
 }
 
-impl Module for MetricsExporter {
-    fn name(&self) -> &str { "metrics_exporter" }
-
-    fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+impl MetricsExporter {
+    fn response(&self, r: &HttpRequest) -> Option<HttpResponse> {
         if r.method != "GET" || r.path != self.endpoint { return None; }
         let body = crate::metrics::snapshot_prometheus();
         Some(HttpResponse {
@@ -37,4 +104,26 @@ impl Module for MetricsExporter {
             body: body.into_bytes(),
         })
     }
+
+    /// One request per connection on the dedicated listener
+    fn serve(&self, mut s: TcpStream) {
+        let _ = s.set_read_timeout(Some(Duration::from_secs(5)));
+        let _ = s.set_write_timeout(Some(Duration::from_secs(5)));
+        let mut resp = match crate::http::read_http_message(&mut s, 4096) {
+            ReadResult::Ok(raw) => HttpRequest::parse(&raw)
+                .map(|r| self.response(&r).unwrap_or_else(|| HttpResponse::error(404, "Not Found")))
+                .unwrap_or_else(|| HttpResponse::error(400, "Bad Request")),
+            _ => return,
+        };
+        resp.set_header("Connection", "close");
+        let _ = s.write_all(&resp.to_bytes());
+    }
+}
+
+impl Module for MetricsExporter {
+    fn name(&self) -> &str { "metrics_exporter" }
+
+    fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        self.response(r)
+    }
 }
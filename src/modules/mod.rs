@@ -1,9 +1,14 @@
 // Auto-generated module registry
 mod active_health;
 mod admin_api;
+mod auth_request;
 mod cache;
+mod capture;
+mod chaos;
 mod circuit_breaker;
 mod compression;
+mod content_route;
+mod forwarded;
 mod health_check;
 mod load_balancer;
 mod metrics_exporter;
@@ -11,6 +16,9 @@ mod proxy_core;
 mod rate_limiter;
 mod raw_tcp;
 mod request_id;
+mod require_headers;
+mod status_remap;
+mod trailing_slash;
 mod url_rewriter;
 
 pub mod helpers;
@@ -25,6 +33,10 @@ pub trait Module: Send + Sync {
     fn overrides(&self) -> &'static [&'static str] { &[] }
     fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse>;
     fn on_response(&self, _req: &HttpRequest, _resp: &mut HttpResponse, _ctx: &mut Context) {}
+    /// Needs the complete response body in on_response, so backend bodies must be buffered
+    fn reads_body(&self) -> bool { false }
+    /// Resolved settings of a script module (Rust modules read theirs from the module config)
+    fn script_config(&self) -> Option<&HashMap<String, String>> { None }
 }
 
 pub trait RawHandler: Send + Sync {
@@ -33,17 +45,25 @@ pub trait RawHandler: Send + Sync {
 
 fn default_priority(name: &str) -> i32 {
     match name {
+        "capture" => 5,
         "active_health" => 10,
+        "forwarded" => 15,
         "request_id" => 20,
+        "chaos" => 25,
         "rate_limiter" => 30,
         "circuit_breaker" => 40,
         "health_check" => 50,
         "metrics_exporter" => 60,
+        "require_headers" => 62,
+        "auth_request" => 65,
         "admin_api" => 70,
+        "trailing_slash" => 75,
         "cache" => 80,
+        "status_remap" => 85,
         "url_rewriter" => 90,
         "compression" => 100,
         "load_balancer" => 110,
+        "content_route" => 115,
         "proxy_core" => 120,
         "raw_tcp" => 130,
         _ => 75,
@@ -55,11 +75,13 @@ pub struct Pipeline {
     raw: Option<Box<dyn RawHandler>>,
     overridden: HashSet<String>,
     to: u64,
+    // Module config the pipeline was built from, kept for `/config/effective`
+    config: HashMap<String, toml::Value>,
 }
 
 impl Pipeline {
     pub fn new(t: u64) -> Self {
-        Pipeline { mods: Vec::new(), raw: None, overridden: HashSet::new(), to: t }
+        Pipeline { mods: Vec::new(), raw: None, overridden: HashSet::new(), to: t, config: HashMap::new() }
     }
     pub fn add(&mut self, m: Box<dyn Module>) {
         let p = default_priority(m.name());
@@ -97,11 +119,20 @@ impl Pipeline {
         self.mods.iter().any(|(_, m)| m.name() == name)
     }
     /// Get names of all loaded modules
-    #[allow(dead_code)]
     pub fn module_names(&self) -> Vec<String> {
         self.mods.iter().map(|(_, m)| m.name().to_string()).collect()
     }
+    /// Priority and name of each loaded module, in execution order once sorted
+    pub fn order(&self) -> Vec<(i32, String)> {
+        self.mods.iter().map(|(p, m)| (*p, m.name().to_string())).collect()
+    }
     pub fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> HttpResponse {
+        if c.get("_client_fingerprint").is_none() {
+            helpers::set_fingerprint(r, c);
+        }
+        if self.mods.iter().any(|(_, m)| m.reads_body()) {
+            c.set_bool("_body_hooks", true);
+        }
         let mut resp_idx = None;
         let mut resp = HttpResponse::error(500, "No handler");
         for (i, (_, m)) in self.mods.iter().enumerate() {
@@ -118,6 +149,17 @@ impl Pipeline {
         resp
     }
     pub fn timeout(&self) -> u64 { self.to }
+    /// Remember the module config this pipeline was built from
+    pub fn set_config(&mut self, mc: &HashMap<String, toml::Value>) {
+        self.config = mc.clone();
+    }
+    pub fn config(&self) -> &HashMap<String, toml::Value> {
+        &self.config
+    }
+    /// Resolved config of the named script module, if one is loaded
+    pub fn script_config(&self, name: &str) -> Option<&HashMap<String, String>> {
+        self.mods.iter().find(|(_, m)| m.name() == name).and_then(|(_, m)| m.script_config())
+    }
 }
 
 pub struct ModuleContext<'a> {
@@ -128,17 +170,25 @@ pub struct ModuleContext<'a> {
 
 pub fn register_all(p: &mut Pipeline, mc: &HashMap<String, toml::Value>, sc: &Srv) {
     let mut ctx = ModuleContext { pipeline: p, config: mc, server: sc };
+    capture::register(&mut ctx);
     active_health::register(&mut ctx);
+    forwarded::register(&mut ctx);
     request_id::register(&mut ctx);
+    chaos::register(&mut ctx);
     rate_limiter::register(&mut ctx);
     circuit_breaker::register(&mut ctx);
     health_check::register(&mut ctx);
     metrics_exporter::register(&mut ctx);
+    require_headers::register(&mut ctx);
+    auth_request::register(&mut ctx);
     admin_api::register(&mut ctx);
+    trailing_slash::register(&mut ctx);
     cache::register(&mut ctx);
+    status_remap::register(&mut ctx);
     url_rewriter::register(&mut ctx);
     compression::register(&mut ctx);
     load_balancer::register(&mut ctx);
+    content_route::register(&mut ctx);
     proxy_core::register(&mut ctx);
     raw_tcp::register(&mut ctx);
 }
@@ -147,9 +197,14 @@ pub fn collect_defaults() -> HashMap<String, toml::Value> {
     let mut d = HashMap::new();
     d.insert("active_health".into(), toml::Value::Table(active_health::default_config()));
     d.insert("admin_api".into(), toml::Value::Table(admin_api::default_config()));
+    d.insert("auth_request".into(), toml::Value::Table(auth_request::default_config()));
     d.insert("cache".into(), toml::Value::Table(cache::default_config()));
+    d.insert("capture".into(), toml::Value::Table(capture::default_config()));
+    d.insert("chaos".into(), toml::Value::Table(chaos::default_config()));
     d.insert("circuit_breaker".into(), toml::Value::Table(circuit_breaker::default_config()));
     d.insert("compression".into(), toml::Value::Table(compression::default_config()));
+    d.insert("content_route".into(), toml::Value::Table(content_route::default_config()));
+    d.insert("forwarded".into(), toml::Value::Table(forwarded::default_config()));
     d.insert("health_check".into(), toml::Value::Table(health_check::default_config()));
     d.insert("load_balancer".into(), toml::Value::Table(load_balancer::default_config()));
     d.insert("metrics_exporter".into(), toml::Value::Table(metrics_exporter::default_config()));
@@ -157,6 +212,9 @@ pub fn collect_defaults() -> HashMap<String, toml::Value> {
     d.insert("rate_limiter".into(), toml::Value::Table(rate_limiter::default_config()));
     d.insert("raw_tcp".into(), toml::Value::Table(raw_tcp::default_config()));
     d.insert("request_id".into(), toml::Value::Table(request_id::default_config()));
+    d.insert("require_headers".into(), toml::Value::Table(require_headers::default_config()));
+    d.insert("status_remap".into(), toml::Value::Table(status_remap::default_config()));
+    d.insert("trailing_slash".into(), toml::Value::Table(trailing_slash::default_config()));
     d.insert("url_rewriter".into(), toml::Value::Table(url_rewriter::default_config()));
     d
 }
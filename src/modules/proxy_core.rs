@@ -2,73 +2,407 @@
 use super::{helpers as h, Module};
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
-use std::io::Write;
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(true));
+    t.insert("no_buffer".into(), toml::Value::Array(Vec::new()));
+    t.insert("stream_threshold_bytes".into(), toml::Value::Integer(0));
+    t.insert("forward_trailers".into(), toml::Value::Boolean(true));
+    t.insert("forward_informational".into(), toml::Value::Boolean(false));
+    t.insert("response_header_denylist".into(), toml::Value::Array(Vec::new()));
+    t.insert("response_header_allowlist".into(), toml::Value::Array(Vec::new()));
+    t.insert("retries".into(), toml::Value::Integer(0));
+    t.insert("route_timeouts".into(), toml::Value::Array(Vec::new()));
+    t.insert("retry_backoff_ms".into(), toml::Value::Integer(25));
+    t.insert("retry_budget_percent".into(), toml::Value::Integer(20));
+    t.insert("retry_budget_min".into(), toml::Value::Integer(3));
+    t.insert("retry_budget_window_secs".into(), toml::Value::Integer(10));
+    t.insert("timeout_status".into(), toml::Value::Integer(504));
+    t.insert("timeout_body".into(), toml::Value::String(String::new()));
+    t.insert("error_status".into(), toml::Value::Integer(502));
+    t.insert("error_body".into(), toml::Value::String(String::new()));
     t
 }
 
+// Message framing the client needs regardless of the allowlist
+const FRAMING_HEADERS: &[&str] = &["Content-Length", "Transfer-Encoding", "Connection", "Trailer"];
+
+// Route timeouts as last registered, for scripts that forward with std.proxy
+static ROUTES: RwLock<Vec<(String, u64)>> = RwLock::new(Vec::new());
+
+/// `route_timeouts` entries (`{ prefix, backend_timeout_secs }`), longest prefix first
+fn route_timeouts(config: &std::collections::HashMap<String, toml::Value>) -> Vec<(String, u64)> {
+    let entries = config.get("proxy_core")
+        .and_then(|v| v.get("route_timeouts"))
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let mut routes: Vec<(String, u64)> = entries.iter()
+        .filter_map(|e| {
+            let prefix = e.get("prefix").and_then(|v| v.as_str()).unwrap_or("");
+            let secs = e.get("backend_timeout_secs").and_then(|v| v.as_integer()).unwrap_or(0);
+            if prefix.is_empty() || secs <= 0 {
+                crate::log::warn(&format!("proxy_core: ignoring route_timeouts entry {e}"));
+                return None;
+            }
+            Some((prefix.to_string(), secs as u64))
+        })
+        .collect();
+    routes.sort_by_key(|r| std::cmp::Reverse(r.0.len()));
+    routes
+}
+
+fn match_route<'a>(routes: &'a [(String, u64)], path: &str) -> Option<&'a (String, u64)> {
+    routes.iter().find(|(prefix, _)| path.starts_with(prefix.as_str()))
+}
+
+/// The `route_timeouts` entry for `path` as (prefix, seconds), longest prefix winning
+pub fn route_timeout(path: &str) -> Option<(String, u64)> {
+    let routes = match ROUTES.read() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    match_route(&routes, path).cloned()
+}
+
 pub fn register(ctx: &mut super::ModuleContext) {
+    // Read even when disabled: a script replacing proxy_core may forward with std.proxy
+    let routes = route_timeouts(ctx.config);
+    match ROUTES.write() {
+        Ok(mut g) => *g = routes.clone(),
+        Err(poisoned) => *poisoned.into_inner() = routes.clone(),
+    }
     if !h::is_enabled(ctx.config, "proxy_core") { return; }
-    ctx.pipeline.add(Box::new(ProxyCore { to: ctx.server.backend_timeout, buf: ctx.server.buffer_size }));
+    let no_buffer = ctx.config.get("proxy_core")
+        .and_then(|v| v.get("no_buffer"))
+        .and_then(|v| v.as_array())
+        .map(|a| a.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect())
+        .unwrap_or_default();
+    let forward_trailers = h::config_bool(ctx.config, "proxy_core", "forward_trailers", true);
+    let forward_informational = h::config_bool(ctx.config, "proxy_core", "forward_informational", false);
+    ctx.pipeline.add(Box::new(ProxyCore {
+        to: ctx.server.backend_timeout,
+        buf: ctx.server.buffer_size,
+        no_buffer,
+        stream_threshold: h::config_u64(ctx.config, "proxy_core", "stream_threshold_bytes", 0),
+        forward_trailers,
+        forward_informational,
+        deny: h::config_vec_str(ctx.config, "proxy_core", "response_header_denylist"),
+        allow: h::config_vec_str(ctx.config, "proxy_core", "response_header_allowlist"),
+        retries: h::config_u64(ctx.config, "proxy_core", "retries", 0),
+        routes,
+        backoff: Duration::from_millis(h::config_u64(ctx.config, "proxy_core", "retry_backoff_ms", 25)),
+        budget: RetryBudget {
+            percent: h::config_u64(ctx.config, "proxy_core", "retry_budget_percent", 20),
+            min: h::config_u64(ctx.config, "proxy_core", "retry_budget_min", 3),
+            window: Duration::from_secs(h::config_u64(ctx.config, "proxy_core", "retry_budget_window_secs", 10).max(1)),
+            state: Mutex::new(BudgetWindow { started: Instant::now(), requests: 0, retries: 0 }),
+        },
+        timeout_status: status(ctx.config, "timeout_status", 504),
+        timeout_body: h::config_str(ctx.config, "proxy_core", "timeout_body", ""),
+        error_status: status(ctx.config, "error_status", 502),
+        error_body: h::config_str(ctx.config, "proxy_core", "error_body", ""),
+    }));
+}
+
+fn status(config: &std::collections::HashMap<String, toml::Value>, key: &str, default: u16) -> u16 {
+    let code = h::config_u64(config, "proxy_core", key, default as u64);
+    if (100..=599).contains(&code) {
+        return code as u16;
+    }
+    crate::log::warn(&format!("proxy_core: {key} {code} is not an HTTP status, using {default}"));
+    default
+}
+
+/// Caps retries at `percent` of the requests seen in the current window (but always allows
+/// `min`), so a failing backend is not hit with several times its normal load
+struct RetryBudget {
+    percent: u64,
+    min: u64,
+    window: Duration,
+    state: Mutex<BudgetWindow>,
+}
+
+struct BudgetWindow {
+    started: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+impl RetryBudget {
+    fn window(&self) -> std::sync::MutexGuard<'_, BudgetWindow> {
+        let mut w = match self.state.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if w.started.elapsed() >= self.window {
+            *w = BudgetWindow { started: Instant::now(), requests: 0, retries: 0 };
+        }
+        w
+    }
+
+    fn record_request(&self) {
+        self.window().requests += 1;
+    }
+
+    /// Take one retry from the budget, if any is left
+    fn try_retry(&self) -> bool {
+        let mut w = self.window();
+        let allowed = (w.requests * self.percent / 100).max(self.min);
+        if w.retries >= allowed {
+            return false;
+        }
+        w.retries += 1;
+        true
+    }
+}
+
+/// Safe to send again after a failure partway through
+fn idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "PUT" | "DELETE" | "TRACE")
 }
 
 struct ProxyCore {
     to: u64,
     buf: usize,
+    // Path prefixes whose responses are relayed as they arrive (SSE, long-poll)
+    no_buffer: Vec<String>,
+    // GET bodies of at least this many bytes (or of unknown length) are relayed in chunks
+    // rather than buffered, unless a module needs the whole body; 0 disables
+    stream_threshold: u64,
+    forward_trailers: bool,
+    // Pass 1xx interim responses (e.g. 103 Early Hints) on to HTTP/1.1 clients
+    forward_informational: bool,
+    // Backend response headers hidden from clients; `X-Internal-*` matches by prefix
+    deny: Vec<String>,
+    // When non-empty, only these (plus framing headers) reach the client
+    allow: Vec<String>,
+    // Extra attempts for idempotent requests whose backend connection failed
+    retries: u64,
+    // Delay before the first retry, doubling for each one after
+    backoff: Duration,
+    // Backend timeouts for path prefixes, longest first
+    routes: Vec<(String, u64)>,
+    budget: RetryBudget,
+    // Responses for a backend that timed out or could not be reached; an empty body keeps the built-in message
+    timeout_status: u16,
+    timeout_body: String,
+    error_status: u16,
+    error_body: String,
 }
 
-impl Module for ProxyCore {
-    fn name(&self) -> &str { "proxy_core" }
-    fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
-        let addr = c.get("_backend_addr")?;
-        let sock_addr: std::net::SocketAddr = match addr.parse() {
-            Ok(a) => a,
-            Err(_) => return Some(HttpResponse::error(502, "Invalid backend address")),
-        };
-        let timeout = Duration::from_secs(self.to);
+fn header_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.get(..prefix.len()).is_some_and(|n| n.eq_ignore_ascii_case(prefix)),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+impl ProxyCore {
+    fn timed_out(&self, c: &Context) -> HttpResponse {
+        if let Some(route) = c.get("_timeout_route") {
+            crate::log::warn(&format!("proxy_core: backend timed out on route {route}"));
+        }
+        let body = if self.timeout_body.is_empty() { "Backend timeout" } else { &self.timeout_body };
+        HttpResponse::error(self.timeout_status, body)
+    }
+
+    fn failed(&self, msg: &str) -> HttpResponse {
+        let body = if self.error_body.is_empty() { msg } else { &self.error_body };
+        HttpResponse::error(self.error_status, body)
+    }
+
+    fn keep_informational(&self, r: &HttpRequest, c: &mut Context, interim: Vec<Vec<u8>>) {
+        if !self.forward_informational || r.version != "HTTP/1.1" || c.get("_protocol") != Some("h1") {
+            return;
+        }
+        // We never relay Expect, so a backend 100 Continue is ours alone
+        let heads: Vec<Vec<u8>> = interim.into_iter()
+            .filter(|h| !h.starts_with(b"HTTP/1.1 100") && !h.starts_with(b"HTTP/1.0 100"))
+            .collect();
+        if !heads.is_empty() {
+            c.put("_informational", heads);
+        }
+    }
+
+    fn keep_header(&self, name: &str) -> bool {
+        if self.deny.iter().any(|p| header_matches(p, name)) {
+            return false;
+        }
+        self.allow.is_empty()
+            || FRAMING_HEADERS.iter().any(|f| f.eq_ignore_ascii_case(name))
+            || self.allow.iter().any(|p| header_matches(p, name))
+    }
+
+    fn streams(&self, r: &HttpRequest, c: &Context) -> bool {
+        c.get("_protocol") == Some("h1") && self.no_buffer.iter().any(|p| r.path.starts_with(p.as_str()))
+    }
+
+    /// Large bodies may be streamed: nothing after us transforms the body
+    fn may_stream(&self, r: &HttpRequest, c: &Context) -> bool {
+        self.stream_threshold > 0 && r.method == "GET" && c.get("_protocol") == Some("h1") && !c.get_bool("_body_hooks")
+    }
+
+    /// Whether a response whose head has been read is worth streaming under the threshold
+    fn large(&self, parsed: &HttpResponse, framing: &crate::http::BodyFraming) -> bool {
+        match framing {
+            crate::http::BodyFraming::Length(n) => n + parsed.body.len() as u64 >= self.stream_threshold,
+            // Trailers can only be dropped from a buffered body
+            crate::http::BodyFraming::Chunked(_) => self.forward_trailers,
+            crate::http::BodyFraming::Close => true,
+        }
+    }
+
+    /// One attempt at the backend. Err carries the response for a connection failure
+    /// that another attempt might get past; timeouts are final.
+    fn forward(&self, r: &HttpRequest, c: &mut Context, sock_addr: SocketAddr, timeout: Duration) -> Result<HttpResponse, HttpResponse> {
         let pool = crate::pool::global_pool();
         let mut s = match pool.get(&sock_addr, timeout) {
             Ok(s) => s,
-            Err(_) => return Some(HttpResponse::error(502, "Backend unavailable")),
+            Err(_) => return Err(self.failed("Backend unavailable")),
         };
+        let lease = pool.lease(sock_addr);
         let _ = s.set_read_timeout(Some(timeout));
         let _ = s.set_write_timeout(Some(timeout));
-        if let Err(e) = s.write_all(&r.to_bytes()) {
+        if let Err(e) = s.write_all(&r.to_backend_bytes()) {
             crate::log::warn(&format!("proxy_core: backend write error: {e}"));
-            return Some(HttpResponse::error(502, "Backend write failed"));
+            return Err(self.failed("Backend write failed"));
         }
-        let raw = crate::http::read_http_message(&mut s, self.buf);
-        let resp = match raw {
+        let no_buffer = self.streams(r, c);
+        let raw = if no_buffer || self.may_stream(r, c) {
+            let (interim, head) = crate::http::read_final_response(&mut s, self.buf, true);
+            self.keep_informational(r, c, interim);
+            let d = match head {
+                crate::http::ReadResult::Ok(d) => d,
+                // Errors and timeouts end up as they would for a buffered read
+                other => return self.settle(other, s, sock_addr, c),
+            };
+            let Some(parsed) = HttpResponse::parse(&d) else { return Ok(self.failed("Parse failed")) };
+            let framing = crate::http::BodyFraming::of(&r.method, &parsed);
+            if no_buffer || self.large(&parsed, &framing) {
+                // The server relays the rest of the body straight from the backend
+                c.set_bool("_no_buffer", true);
+                if matches!(framing, crate::http::BodyFraming::Close) {
+                    // Close-delimited body: frame it for the client instead
+                    c.set_bool("_chunked", true);
+                }
+                c.set_bool("_backend_keep_alive", crate::http::keep_alive(&parsed.version, parsed.get_header("Connection")));
+                c.put("_backend_framing", framing);
+                c.put("_backend_stream", s);
+                // Still busy until the server has relayed the body
+                c.put("_pool_lease", lease);
+                return Ok(parsed);
+            }
+            // Small enough to buffer after all: finish reading it as usual
+            crate::http::read_http_message(&mut std::io::Cursor::new(d).chain(&mut s), self.buf)
+        } else {
+            let (interim, raw) = crate::http::read_final_response(&mut s, self.buf, false);
+            self.keep_informational(r, c, interim);
+            raw
+        };
+        self.settle(raw, s, sock_addr, c)
+    }
+
+    /// Turn a buffered backend read into the response, returning the connection to the pool
+    fn settle(&self, raw: crate::http::ReadResult, s: std::net::TcpStream, sock_addr: SocketAddr, c: &Context) -> Result<HttpResponse, HttpResponse> {
+        match raw {
             crate::http::ReadResult::Ok(d) => {
                 match HttpResponse::parse(&d) {
-                    Some(parsed) => {
-                        let conn_hdr = parsed.get_header("Connection").unwrap_or("");
-                        let keep_alive = if parsed.version == "HTTP/1.0" {
-                            conn_hdr.eq_ignore_ascii_case("keep-alive")
-                        } else {
-                            !conn_hdr.eq_ignore_ascii_case("close")
-                        };
+                    Some(mut parsed) => {
+                        let chunked = parsed.get_header("Transfer-Encoding")
+                            .is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+                        if chunked && !self.forward_trailers {
+                            crate::http::strip_trailers(&mut parsed.body);
+                            parsed.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("Trailer"));
+                        }
+                        let keep_alive = crate::http::keep_alive(&parsed.version, parsed.get_header("Connection"));
                         if keep_alive {
-                            pool.put(sock_addr, s);
+                            crate::pool::global_pool().put(sock_addr, s);
                         }
-                        parsed
+                        Ok(parsed)
                     }
                     None => {
                         crate::log::warn("proxy_core: failed to parse backend response");
-                        HttpResponse::error(502, "Parse failed")
+                        Ok(self.failed("Parse failed"))
                     }
                 }
             }
-            crate::http::ReadResult::TimedOut => HttpResponse::error(504, "Backend timeout"),
+            crate::http::ReadResult::TimedOut | crate::http::ReadResult::BodyTimedOut => Ok(self.timed_out(c)),
             crate::http::ReadResult::Error(e) => {
                 crate::log::warn(&format!("proxy_core: backend error: {e}"));
-                HttpResponse::error(502, "Backend error")
+                Err(self.failed("Backend error"))
             }
-        };
-        Some(resp)
+        }
+    }
+}
+
+impl Module for ProxyCore {
+    fn name(&self) -> &str { "proxy_core" }
+    fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        c.get("_backend_addr")?;
+        let route = match_route(&self.routes, &r.path);
+        if let Some((prefix, _)) = route {
+            c.set("_timeout_route", prefix.clone());
+        }
+        if self.retries > 0 {
+            self.budget.record_request();
+        }
+        let mut fallbacks = c.take::<Vec<(String, Option<u64>)>>("_backend_fallbacks")
+            .cloned()
+            .unwrap_or_default()
+            .into_iter();
+        let mut attempt = 0;
+        loop {
+            let addr = c.get("_backend_addr")?;
+            let sock_addr = match crate::resolve::resolve(addr) {
+                Ok(a) => a,
+                Err(e) => {
+                    crate::log::warn(&format!("proxy_core: cannot resolve backend {addr}: {e}"));
+                    return Some(self.failed("Backend resolution failed"));
+                }
+            };
+            let secs = route.map_or_else(|| h::backend_timeout(c, self.to), |(_, t)| *t);
+            let backend = addr.to_string();
+            let started = Instant::now();
+            let result = self.forward(r, c, sock_addr, Duration::from_secs(secs));
+            let ok = result.as_ref().is_ok_and(|resp| resp.status_code < 500);
+            crate::metrics::record_backend(&backend, ok, started.elapsed().as_millis() as u64);
+            let resp = match result {
+                Ok(resp) => return Some(resp),
+                Err(resp) => resp,
+            };
+            if attempt >= self.retries || !idempotent(&r.method) {
+                return Some(resp);
+            }
+            if h::breaker_open(c) {
+                crate::log::debug("proxy_core: circuit breaker open, not retrying");
+                return Some(resp);
+            }
+            if !self.budget.try_retry() {
+                crate::log::debug("proxy_core: retry budget exhausted, not retrying");
+                return Some(resp);
+            }
+            attempt += 1;
+            crate::metrics::inc_retries();
+            // 1x, 2x, 4x... the base delay, so a restarting backend gets a moment
+            std::thread::sleep(self.backoff.saturating_mul(1 << (attempt - 1).min(10)));
+            // The load balancer's next choice, if it had one; else the same backend again
+            if let Some((next, t)) = fallbacks.next() {
+                h::set_backend(c, &next, t);
+            }
+            crate::log::debug(&format!("proxy_core: retrying {} {} (attempt {})", r.method, r.path, attempt + 1));
+        }
+    }
+
+    fn on_response(&self, _req: &HttpRequest, resp: &mut HttpResponse, _ctx: &mut Context) {
+        if self.deny.is_empty() && self.allow.is_empty() {
+            return;
+        }
+        resp.headers.retain(|(k, _)| self.keep_header(k));
     }
 }
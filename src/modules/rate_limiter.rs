@@ -1,10 +1,10 @@
-// Token-bucket rate limiting by client IP
+// Token-bucket rate limiting and windowed quotas by client IP, fingerprint or key header
 use super::{helpers as h, Module};
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 const MAX_BUCKETS: usize = 50_000;
 const BUCKET_STALE_SECS: f64 = 300.0;
@@ -14,6 +14,9 @@ pub fn default_config() -> toml::Table {
     t.insert("enabled".into(), toml::Value::Boolean(false));
     t.insert("requests_per_second".into(), toml::Value::Integer(10));
     t.insert("burst".into(), toml::Value::Integer(20));
+    t.insert("key".into(), toml::Value::String("ip".into()));
+    t.insert("daily_quota".into(), toml::Value::Integer(0));
+    t.insert("quota_window_secs".into(), toml::Value::Integer(86_400));
     t
 }
 
@@ -21,17 +24,47 @@ pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "rate_limiter") { return; }
     let r = h::config_usize(ctx.config, "rate_limiter", "requests_per_second", 10);
     let b = h::config_usize(ctx.config, "rate_limiter", "burst", r * 2);
+    let key = match h::config_str(ctx.config, "rate_limiter", "key", "ip").as_str() {
+        "ip" => Key::Ip,
+        "fingerprint" => Key::Fingerprint,
+        other => match other.strip_prefix("header:").map(str::trim) {
+            Some(name) if !name.is_empty() => Key::Header(name.to_string()),
+            _ => {
+                crate::log::warn(&format!("rate_limiter: key '{other}' unknown, using ip"));
+                Key::Ip
+            }
+        },
+    };
+    let quota = h::config_u64(ctx.config, "rate_limiter", "daily_quota", 0);
+    let window = h::config_u64(ctx.config, "rate_limiter", "quota_window_secs", 86_400).max(1);
     ctx.pipeline.add(Box::new(RateLimit {
         rps: r,
         burst: b,
+        key,
         buckets: Arc::new(Mutex::new(HashMap::new())),
+        quota,
+        window,
+        quotas: Mutex::new(HashMap::new()),
     }));
 }
 
+enum Key {
+    Ip,
+    // `_client_fingerprint`, set by the pipeline from the client IP and headers
+    Fingerprint,
+    // Value of this request header (an API key), falling back to the client IP
+    Header(String),
+}
+
 struct RateLimit {
     rps: usize,
     burst: usize,
+    key: Key,
     buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    // Requests allowed per key per window; 0 disables the quota
+    quota: u64,
+    window: u64,
+    quotas: Mutex<HashMap<String, Quota>>,
 }
 
 struct Bucket {
@@ -39,10 +72,57 @@ struct Bucket {
     last: Instant,
 }
 
+// Requests counted for one key in the window with this index
+struct Quota {
+    window: u64,
+    used: u64,
+    last: Instant,
+}
+
+impl RateLimit {
+    /// Count a request against the key's quota: Ok(remaining) or Err(seconds until the window resets)
+    fn charge(&self, key: &str) -> Result<u64, u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let window = now / self.window;
+        let mut qs = match self.quotas.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if qs.len() >= MAX_BUCKETS && !qs.contains_key(key) {
+            qs.retain(|_, q| q.window == window);
+            // Rotating key values can fill the map within one window
+            if qs.len() >= MAX_BUCKETS {
+                let mut entries: Vec<(String, Instant)> = qs.iter().map(|(k, q)| (k.clone(), q.last)).collect();
+                entries.sort_by_key(|(_, t)| *t);
+                let to_evict = entries.len() / 4;
+                for (k, _) in entries.into_iter().take(to_evict.max(1)) {
+                    qs.remove(&k);
+                }
+            }
+        }
+        let q = qs.entry(key.to_string()).or_insert(Quota { window, used: 0, last: Instant::now() });
+        q.last = Instant::now();
+        if q.window != window {
+            q.window = window;
+            q.used = 0;
+        }
+        if q.used >= self.quota {
+            return Err((window + 1) * self.window - now);
+        }
+        q.used += 1;
+        Ok(self.quota - q.used)
+    }
+}
+
 impl Module for RateLimit {
     fn name(&self) -> &str { "rate_limiter" }
-    fn handle(&self, _: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
-        let ip = h::client_ip(c);
+    fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
+        let key = match &self.key {
+            Key::Fingerprint => c.get("_client_fingerprint").map(str::to_string),
+            Key::Header(name) => r.get_header(name).map(str::to_string),
+            Key::Ip => None,
+        }
+        .unwrap_or_else(|| h::client_ip(c));
         let mut bs = match self.buckets.lock() {
             Ok(guard) => guard,
             Err(poisoned) => {
@@ -66,15 +146,41 @@ impl Module for RateLimit {
             }
         }
 
-        let b = bs.entry(ip).or_insert(Bucket { tokens: self.burst as f64, last: Instant::now() });
+        let b = bs.entry(key.clone()).or_insert(Bucket { tokens: self.burst as f64, last: Instant::now() });
         let elapsed = b.last.elapsed().as_secs_f64();
         b.tokens = (b.tokens + elapsed * self.rps as f64).min(self.burst as f64);
         b.last = Instant::now();
-        if b.tokens >= 1.0 {
-            b.tokens -= 1.0;
-            None
-        } else {
-            Some(HttpResponse::error(429, "Rate limit"))
+        if b.tokens < 1.0 {
+            crate::metrics::inc_rate_limited();
+            return Some(HttpResponse::error(429, "Rate limit"));
+        }
+        b.tokens -= 1.0;
+        drop(bs);
+
+        if self.quota == 0 {
+            return None;
+        }
+        match self.charge(&key) {
+            Ok(remaining) => {
+                c.set_u64("_quota_remaining", remaining);
+                None
+            }
+            Err(reset) => {
+                crate::metrics::inc_rate_limited();
+                let mut resp = HttpResponse::error(429, "Quota exceeded");
+                resp.set_header("X-Quota-Limit", &self.quota.to_string());
+                resp.set_header("X-Quota-Remaining", "0");
+                resp.set_header("X-Quota-Reset", &reset.to_string());
+                resp.set_header("Retry-After", &reset.to_string());
+                Some(resp)
+            }
+        }
+    }
+
+    fn on_response(&self, _req: &HttpRequest, resp: &mut HttpResponse, ctx: &mut Context) {
+        if let Some(remaining) = ctx.get_u64("_quota_remaining") {
+            resp.set_header("X-Quota-Limit", &self.quota.to_string());
+            resp.set_header("X-Quota-Remaining", &remaining.to_string());
         }
     }
 }
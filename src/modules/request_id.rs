@@ -2,47 +2,53 @@
 use super::{helpers as h, Module};
 use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
-
-static COUNTER: AtomicU64 = AtomicU64::new(0);
 
 const HDR_REQUEST_ID: &str = "X-Request-Id";
 
 pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("header_name".into(), toml::Value::String(HDR_REQUEST_ID.into()));
+    t.insert("format".into(), toml::Value::String("timestamp".into()));
+    t.insert("overwrite".into(), toml::Value::Boolean(false));
     t
 }
 
 pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "request_id") { return; }
-    ctx.pipeline.add(Box::new(RequestId));
+    let header = h::config_str(ctx.config, "request_id", "header_name", HDR_REQUEST_ID);
+    let mut format = h::config_str(ctx.config, "request_id", "format", "timestamp").to_lowercase();
+    if !h::REQUEST_ID_FORMATS.contains(&format.as_str()) {
+        crate::log::warn(&format!("request_id: format '{format}' unknown, using timestamp"));
+        format = "timestamp".into();
+    }
+    let overwrite = h::config_bool(ctx.config, "request_id", "overwrite", false);
+    ctx.pipeline.add(Box::new(RequestId { header, format, overwrite }));
 }
 
-fn generate_id() -> String {
-    let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
-    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
-    format!("{ts:x}-{seq:04x}")
+struct RequestId {
+    header: String,
+    format: String,
+    // Replace a client-supplied ID instead of trusting it
+    overwrite: bool,
 }
 
-struct RequestId;
-
 impl Module for RequestId {
     fn name(&self) -> &str { "request_id" }
 
     fn handle(&self, r: &mut HttpRequest, c: &mut Context) -> Option<HttpResponse> {
-        let id = r.get_header(HDR_REQUEST_ID)
+        let id = r.get_header(&self.header)
+            .filter(|_| !self.overwrite)
             .map(|s| s.to_string())
-            .unwrap_or_else(generate_id);
-        r.set_header(HDR_REQUEST_ID, &id);
+            .unwrap_or_else(|| h::generate_request_id(&self.format));
+        r.set_header(&self.header, &id);
         c.set("_request_id", id);
         None
     }
 
     fn on_response(&self, _req: &HttpRequest, resp: &mut HttpResponse, ctx: &mut Context) {
         if let Some(id) = ctx.get("_request_id") {
-            resp.headers.push((HDR_REQUEST_ID.to_string(), id.to_string()));
+            resp.headers.push((self.header.clone(), id.to_string()));
         }
     }
 }
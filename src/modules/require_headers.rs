@@ -0,0 +1,65 @@
+// Rejects requests that lack headers the backend depends on
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("headers".into(), toml::Value::Array(vec![]));
+    t.insert("patterns".into(), toml::Value::Table(toml::Table::new()));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    if !h::is_enabled(ctx.config, "require_headers") { return; }
+    let mut required: Vec<Required> = h::config_vec_str(ctx.config, "require_headers", "headers").into_iter()
+        .map(|name| Required { name, pattern: None })
+        .collect();
+    let patterns = ctx.config.get("require_headers")
+        .and_then(|v| v.get("patterns"))
+        .and_then(|v| v.as_table());
+    for (name, pat) in patterns.into_iter().flatten() {
+        let Some(pat) = pat.as_str() else { continue };
+        let re = match regex_lite::Regex::new(pat) {
+            Ok(re) => re,
+            Err(e) => {
+                crate::log::warn(&format!("require_headers: invalid pattern for '{name}': {e}, skipping"));
+                continue;
+            }
+        };
+        match required.iter_mut().find(|r| r.name.eq_ignore_ascii_case(name)) {
+            Some(r) => r.pattern = Some(re),
+            None => required.push(Required { name: name.clone(), pattern: Some(re) }),
+        }
+    }
+    if !required.is_empty() {
+        ctx.pipeline.add(Box::new(RequireHeaders { required }));
+    }
+}
+
+struct Required {
+    name: String,
+    // A present value must match this too
+    pattern: Option<regex_lite::Regex>,
+}
+
+struct RequireHeaders {
+    required: Vec<Required>,
+}
+
+impl Module for RequireHeaders {
+    fn name(&self) -> &str { "require_headers" }
+    fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        for req in &self.required {
+            let problem = match r.get_header(&req.name) {
+                None => "missing",
+                Some(v) if req.pattern.as_ref().is_some_and(|p| !p.is_match(v)) => "invalid",
+                Some(_) => continue,
+            };
+            crate::log::debug(&format!("require_headers: {problem} {} on {}", req.name, r.path));
+            return Some(HttpResponse::error(400, &format!("Bad Request: {problem} {} header", req.name)));
+        }
+        None
+    }
+}
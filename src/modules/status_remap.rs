@@ -0,0 +1,78 @@
+// Response status code remapping
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+use std::collections::HashMap;
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("codes".into(), toml::Value::Table(toml::Table::new()));
+    t.insert("paths".into(), toml::Value::Table(toml::Table::new()));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    if !h::is_enabled(ctx.config, "status_remap") { return; }
+    let rules = load_rules(ctx.config);
+    if !rules.is_empty() {
+        ctx.pipeline.add(Box::new(StatusRemap { rules }));
+    }
+}
+
+fn parse_codes(prefix: &str, t: &toml::Table, out: &mut Vec<Rule>) {
+    for (from, to) in t {
+        let from = from.parse::<u16>().ok().filter(|c| (100..=599).contains(c));
+        let to = to.as_integer().and_then(|c| u16::try_from(c).ok()).filter(|c| (100..=599).contains(c));
+        match (from, to) {
+            (Some(from), Some(to)) => out.push(Rule { prefix: prefix.to_string(), from, to }),
+            _ => crate::log::warn(&format!("status_remap: invalid mapping under '{prefix}', skipping")),
+        }
+    }
+}
+
+fn load_rules(c: &HashMap<String, toml::Value>) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    let cfg = match c.get("status_remap") {
+        Some(v) => v,
+        None => return rules,
+    };
+    if let Some(t) = cfg.get("codes").and_then(|v| v.as_table()) {
+        parse_codes("", t, &mut rules);
+    }
+    if let Some(paths) = cfg.get("paths").and_then(|v| v.as_table()) {
+        for (prefix, v) in paths {
+            if let Some(t) = v.as_table() {
+                parse_codes(prefix, t, &mut rules);
+            }
+        }
+    }
+    // Path-specific mappings take precedence over global ones
+    rules.sort_by_key(|r| std::cmp::Reverse(r.prefix.len()));
+    rules
+}
+
+struct Rule {
+    prefix: String,
+    from: u16,
+    to: u16,
+}
+
+struct StatusRemap {
+    rules: Vec<Rule>,
+}
+
+impl Module for StatusRemap {
+    fn name(&self) -> &str { "status_remap" }
+    fn handle(&self, _: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
+        None
+    }
+    fn on_response(&self, req: &HttpRequest, resp: &mut HttpResponse, _: &mut Context) {
+        let rule = self.rules.iter()
+            .find(|r| r.from == resp.status_code && req.path.starts_with(r.prefix.as_str()));
+        if let Some(r) = rule {
+            resp.status_code = r.to;
+            resp.status_text = crate::http::reason_phrase(r.to).unwrap_or("Unknown").to_string();
+        }
+    }
+}
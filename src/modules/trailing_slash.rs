@@ -0,0 +1,60 @@
+// Trailing slash normalization, so `/docs` and `/docs/` share one cache entry and route
+use super::{helpers as h, Module};
+use crate::context::Context;
+use crate::http::{HttpRequest, HttpResponse};
+
+pub fn default_config() -> toml::Table {
+    let mut t = toml::Table::new();
+    t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("normalize_trailing_slash".into(), toml::Value::String("strip".into()));
+    t
+}
+
+pub fn register(ctx: &mut super::ModuleContext) {
+    if !h::is_enabled(ctx.config, "trailing_slash") { return; }
+    let add = match h::config_str(ctx.config, "trailing_slash", "normalize_trailing_slash", "strip").to_lowercase().as_str() {
+        "add" => true,
+        "strip" => false,
+        other => {
+            crate::log::warn(&format!("trailing_slash: mode '{other}' unknown, expected add or strip"));
+            return;
+        }
+    };
+    ctx.pipeline.add(Box::new(TrailingSlash { add }));
+}
+
+struct TrailingSlash {
+    add: bool,
+}
+
+impl TrailingSlash {
+    /// Normalized form of `path`; the query string is kept and `/` is never touched
+    fn normalize(&self, path: &str) -> String {
+        let (base, query) = match path.split_once('?') {
+            Some((b, q)) => (b, Some(q)),
+            None => (path, None),
+        };
+        let base = if self.add {
+            // Leave paths that look like files alone, `/app.js/` would break them
+            let last = base.rsplit('/').next().unwrap_or("");
+            if base.ends_with('/') || last.contains('.') { base.to_string() } else { format!("{base}/") }
+        } else {
+            let stripped = base.trim_end_matches('/');
+            if stripped.is_empty() { "/".to_string() } else { stripped.to_string() }
+        };
+        match query {
+            Some(q) => format!("{base}?{q}"),
+            None => base,
+        }
+    }
+}
+
+impl Module for TrailingSlash {
+    fn name(&self) -> &str { "trailing_slash" }
+    fn handle(&self, r: &mut HttpRequest, _c: &mut Context) -> Option<HttpResponse> {
+        if r.path.starts_with('/') {
+            r.path = self.normalize(&r.path);
+        }
+        None
+    }
+}
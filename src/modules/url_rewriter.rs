@@ -7,36 +7,127 @@ use std::collections::HashMap;
 pub fn default_config() -> toml::Table {
     let mut t = toml::Table::new();
     t.insert("enabled".into(), toml::Value::Boolean(false));
+    t.insert("add_query".into(), toml::Value::Table(toml::Table::new()));
+    t.insert("remove_query".into(), toml::Value::Array(Vec::new()));
+    t.insert("case_insensitive".into(), toml::Value::Boolean(false));
     t
 }
 
 pub fn register(ctx: &mut super::ModuleContext) {
     if !h::is_enabled(ctx.config, "url_rewriter") { return; }
     let r = load_rules(ctx.config);
-    if !r.is_empty() {
-        ctx.pipeline.add(Box::new(Rewrite { rules: r }));
+    let nocase = h::config_bool(ctx.config, "url_rewriter", "case_insensitive", false);
+    let query = QueryEdit {
+        add: ctx.config.get("url_rewriter")
+            .and_then(|v| v.get("add_query"))
+            .and_then(|v| v.as_table())
+            .map(|t| t.iter().filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string()))).collect())
+            .unwrap_or_default(),
+        remove: h::config_vec_str(ctx.config, "url_rewriter", "remove_query"),
+    };
+    if !r.is_empty() || !query.is_empty() {
+        ctx.pipeline.add(Box::new(Rewrite { rules: r, query, nocase }));
     }
 }
 
-fn load_rules(c: &HashMap<String, toml::Value>) -> Vec<(String, String)> {
+/// Rules are `"/from" = "/to"`, or `"/from" = { to = "/to", except = ["/from/skip"], method = "GET" }`
+fn load_rules(c: &HashMap<String, toml::Value>) -> Vec<Rule> {
     c.get("url_rewriter").and_then(|v| v.get("rules")).and_then(|v| v.as_table()).map(|t| {
-        t.iter().filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string()))).collect()
+        t.iter().filter_map(|(k, v)| match v {
+            toml::Value::String(to) => Some(Rule { from: k.clone(), to: to.clone(), except: Vec::new(), method: None }),
+            toml::Value::Table(rt) => {
+                let to = match rt.get("to").and_then(|v| v.as_str()) {
+                    Some(to) => to.to_string(),
+                    None => {
+                        crate::log::warn(&format!("url_rewriter: rule '{k}' has no 'to', skipping"));
+                        return None;
+                    }
+                };
+                let except = rt.get("except").and_then(|v| v.as_array())
+                    .map(|a| a.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect())
+                    .unwrap_or_default();
+                let method = rt.get("method").and_then(|v| v.as_str()).map(|m| m.to_ascii_uppercase());
+                Some(Rule { from: k.clone(), to, except, method })
+            }
+            _ => None,
+        }).collect()
     }).unwrap_or_default()
 }
 
+struct Rule {
+    from: String,
+    to: String,
+    // Prefixes under `from` that this rule leaves alone
+    except: Vec<String>,
+    // Method sent to the backend for matching requests, e.g. HEAD -> GET so it can be cached
+    method: Option<String>,
+}
+
+fn has_prefix(path: &str, prefix: &str, nocase: bool) -> bool {
+    if nocase {
+        path.get(..prefix.len()).is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+    } else {
+        path.starts_with(prefix)
+    }
+}
+
+impl Rule {
+    fn applies(&self, path: &str, nocase: bool) -> bool {
+        has_prefix(path, &self.from, nocase) && !self.except.iter().any(|e| has_prefix(path, e, nocase))
+    }
+}
+
+/// Query string edits applied after path rules
+struct QueryEdit {
+    // Parameters set on every request, replacing any existing value
+    add: Vec<(String, String)>,
+    // Parameter names to drop; `*` globs, e.g. `utm_*`
+    remove: Vec<String>,
+}
+
+impl QueryEdit {
+    fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+
+    fn apply(&self, path: &str) -> String {
+        let (base, query) = path.split_once('?').unwrap_or((path, ""));
+        let mut pairs: Vec<String> = query.split('&')
+            .filter(|p| !p.is_empty())
+            .filter(|p| {
+                let name = crate::http::url_decode(p.split_once('=').map_or(*p, |(k, _)| k));
+                !self.remove.iter().any(|g| h::glob_match(&name, g)) && !self.add.iter().any(|(k, _)| *k == name)
+            })
+            .map(|p| p.to_string())
+            .collect();
+        pairs.extend(self.add.iter().map(|(k, v)| format!("{k}={v}")));
+        if pairs.is_empty() {
+            base.to_string()
+        } else {
+            format!("{base}?{}", pairs.join("&"))
+        }
+    }
+}
+
 struct Rewrite {
-    rules: Vec<(String, String)>,
+    rules: Vec<Rule>,
+    query: QueryEdit,
+    // Match rule prefixes ignoring ASCII case; the unmatched rest of the path keeps its case
+    nocase: bool,
 }
 
 impl Module for Rewrite {
     fn name(&self) -> &str { "url_rewriter" }
     fn handle(&self, r: &mut HttpRequest, _: &mut Context) -> Option<HttpResponse> {
-        for (p, repl) in &self.rules {
-            if r.path.starts_with(p) {
-                r.path = r.path.replacen(p, repl, 1);
-                break;
+        if let Some(rule) = self.rules.iter().find(|rule| rule.applies(&r.path, self.nocase)) {
+            r.path = format!("{}{}", rule.to, &r.path[rule.from.len()..]);
+            if let Some(m) = &rule.method {
+                r.method = m.clone();
             }
         }
+        if !self.query.is_empty() {
+            r.path = self.query.apply(&r.path);
+        }
         None
     }
 }
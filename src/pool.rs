@@ -1,11 +1,13 @@
 // Connection pool for backend TCP connections
 use std::collections::HashMap;
 use std::net::{TcpStream, SocketAddr};
-use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 
-const MAX_IDLE_PER_HOST: usize = 8;
-const MAX_IDLE_AGE: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_IDLE_PER_BACKEND: usize = 32;
+const DEFAULT_MAX_TOTAL_IDLE: usize = 1024;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
 
 static GLOBAL_POOL: OnceLock<ConnPool> = OnceLock::new();
 
@@ -14,19 +16,90 @@ pub fn global_pool() -> &'static ConnPool {
     GLOBAL_POOL.get_or_init(ConnPool::new)
 }
 
+/// Prune idle connections of the global pool past their idle timeout, every `interval`
+pub fn start_sweeper(interval: Duration) {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            if crate::server::SHUTDOWN.load(Ordering::Acquire) { break; }
+            let evicted = global_pool().sweep();
+            if evicted > 0 {
+                crate::log::debug(&format!("pool: closed {evicted} idle connections past the idle timeout"));
+            }
+        }
+    });
+}
+
 struct Pooled {
     stream: TcpStream,
-    created: Instant,
+    // When it was last returned by `put`
+    returned: Instant,
+    born: Instant,
+}
+
+/// Marks a backend connection as in use until dropped
+pub struct Lease(Arc<AtomicUsize>);
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 pub struct ConnPool {
     idle: Mutex<HashMap<SocketAddr, Vec<Pooled>>>,
+    // Connections currently handed out per backend, counted by live leases
+    busy: Mutex<HashMap<SocketAddr, Arc<AtomicUsize>>>,
+    // Connect time of live connections, keyed by local address; only tracked with a lifetime set
+    born: Mutex<HashMap<SocketAddr, Instant>>,
+    max_lifetime_ms: AtomicU64,
+    idle_timeout_ms: AtomicU64,
+    max_idle_per_backend: AtomicUsize,
+    max_total_idle: AtomicUsize,
 }
 
 impl ConnPool {
     pub fn new() -> Self {
         ConnPool {
             idle: Mutex::new(HashMap::new()),
+            busy: Mutex::new(HashMap::new()),
+            born: Mutex::new(HashMap::new()),
+            max_lifetime_ms: AtomicU64::new(0),
+            idle_timeout_ms: AtomicU64::new(DEFAULT_IDLE_TIMEOUT.as_millis() as u64),
+            max_idle_per_backend: AtomicUsize::new(DEFAULT_MAX_IDLE_PER_BACKEND),
+            max_total_idle: AtomicUsize::new(DEFAULT_MAX_TOTAL_IDLE),
+        }
+    }
+
+    /// Keep at most `per_backend` idle connections to one backend and `total` across all of them;
+    /// connections returned past either cap are closed
+    pub fn set_idle_limits(&self, per_backend: usize, total: usize) {
+        self.max_idle_per_backend.store(per_backend, Ordering::Relaxed);
+        self.max_total_idle.store(total, Ordering::Relaxed);
+    }
+
+    pub fn idle_limits(&self) -> (usize, usize) {
+        (self.max_idle_per_backend.load(Ordering::Relaxed), self.max_total_idle.load(Ordering::Relaxed))
+    }
+
+    /// Close connections left idle for `d` instead of reusing them
+    pub fn set_idle_timeout(&self, d: Duration) {
+        self.idle_timeout_ms.store(d.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.idle_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    /// Refuse to reuse connections older than `d` since connect; zero disables
+    pub fn set_max_lifetime(&self, d: Duration) {
+        self.max_lifetime_ms.store(d.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn max_lifetime(&self) -> Option<Duration> {
+        match self.max_lifetime_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
         }
     }
 
@@ -37,16 +110,24 @@ impl ConnPool {
                 crate::log::warn("pool: mutex recovered after panic, purging stale connections");
                 let mut inner = poisoned.into_inner();
                 let now = Instant::now();
+                let idle_timeout = self.idle_timeout();
                 for conns in inner.values_mut() {
-                    conns.retain(|p| p.created <= now && p.created.elapsed() < MAX_IDLE_AGE);
+                    conns.retain(|p| p.returned <= now && p.returned.elapsed() < idle_timeout);
                 }
                 inner.retain(|_, v| !v.is_empty());
                 inner
             }
         };
+        let lifetime = self.max_lifetime();
+        let idle_timeout = self.idle_timeout();
         if let Some(conns) = map.get_mut(addr) {
+            // The backend may be about to close connections idle this long
+            let before = conns.len();
+            conns.retain(|p| p.returned.elapsed() < idle_timeout);
+            crate::metrics::add_pool_evicted((before - conns.len()) as u64);
             while let Some(pooled) = conns.pop() {
-                if pooled.created.elapsed() > MAX_IDLE_AGE {
+                if lifetime.is_some_and(|l| pooled.born.elapsed() >= l) {
+                    crate::log::debug(&format!("pool: closing {addr} connection past max lifetime"));
                     continue;
                 }
                 let stream = pooled.stream;
@@ -66,6 +147,11 @@ impl ConnPool {
                             continue;
                         }
                         crate::metrics::inc_pool_hits();
+                        if lifetime.is_some() {
+                            if let (Ok(local), Ok(mut b)) = (stream.local_addr(), self.born.lock()) {
+                                b.insert(local, pooled.born);
+                            }
+                        }
                         return Ok(stream);
                     }
                     Ok(0) => {
@@ -80,10 +166,29 @@ impl ConnPool {
         crate::metrics::inc_pool_misses();
         let stream = TcpStream::connect_timeout(addr, timeout)?;
         let _ = stream.set_nodelay(true);
+        if lifetime.is_some() {
+            if let (Ok(local), Ok(mut b)) = (stream.local_addr(), self.born.lock()) {
+                b.insert(local, Instant::now());
+            }
+        }
         Ok(stream)
     }
 
     pub fn put(&self, addr: SocketAddr, stream: TcpStream) {
+        let now = Instant::now();
+        let mut born = now;
+        if let Some(lifetime) = self.max_lifetime() {
+            if let Ok(mut b) = self.born.lock() {
+                if let Some(at) = stream.local_addr().ok().and_then(|l| b.remove(&l)) {
+                    born = at;
+                }
+                // Connections dropped without being returned leave entries behind
+                b.retain(|_, at| at.elapsed() < lifetime);
+            }
+            if born.elapsed() >= lifetime {
+                return;
+            }
+        }
         let mut map = match self.idle.lock() {
             Ok(g) => g,
             Err(poisoned) => {
@@ -93,18 +198,113 @@ impl ConnPool {
                 inner
             }
         };
+        let idle_timeout = self.idle_timeout();
+        let (per_backend, total) = self.idle_limits();
+        let held: usize = map.iter().filter(|(a, _)| **a != addr).map(|(_, c)| c.len()).sum();
         let conns = map.entry(addr).or_insert_with(Vec::new);
-        conns.retain(|p| p.created.elapsed() < MAX_IDLE_AGE);
-        if conns.len() < MAX_IDLE_PER_HOST {
-            conns.push(Pooled { stream, created: Instant::now() });
+        let before = conns.len();
+        conns.retain(|p| p.returned.elapsed() < idle_timeout);
+        crate::metrics::add_pool_evicted((before - conns.len()) as u64);
+        // Full: drop the returned connection, closing it
+        if conns.len() < per_backend && held + conns.len() < total {
+            conns.push(Pooled { stream, returned: now, born });
+        }
+    }
+
+    /// Count a connection to `addr` as in use for as long as the lease lives
+    pub fn lease(&self, addr: SocketAddr) -> Lease {
+        let n = match self.busy.lock() {
+            Ok(mut g) => Arc::clone(g.entry(addr).or_default()),
+            Err(poisoned) => Arc::clone(poisoned.into_inner().entry(addr).or_default()),
+        };
+        n.fetch_add(1, Ordering::AcqRel);
+        Lease(n)
+    }
+
+    /// Connections to `addr` in use and sitting idle
+    pub fn usage(&self, addr: &SocketAddr) -> (usize, usize) {
+        let in_use = match self.busy.lock() {
+            Ok(g) => g.get(addr).map_or(0, |n| n.load(Ordering::Acquire)),
+            Err(poisoned) => poisoned.into_inner().get(addr).map_or(0, |n| n.load(Ordering::Acquire)),
+        };
+        let idle = match self.idle.lock() {
+            Ok(g) => g.get(addr).map_or(0, |c| c.len()),
+            Err(poisoned) => poisoned.into_inner().get(addr).map_or(0, |c| c.len()),
+        };
+        (in_use, idle)
+    }
+
+    /// No idle connection left and at least as many in use as the pool keeps per backend;
+    /// another request there means a fresh connect behind a busy backend
+    pub fn is_saturated(&self, addr: &SocketAddr) -> bool {
+        let (in_use, idle) = self.usage(addr);
+        idle == 0 && in_use >= self.max_idle_per_backend.load(Ordering::Relaxed)
+    }
+
+    /// Drop all idle connections to `addr`, returning how many were closed
+    pub fn evict(&self, addr: &SocketAddr) -> usize {
+        let gone = match self.idle.lock() {
+            Ok(mut g) => g.remove(addr),
+            Err(poisoned) => poisoned.into_inner().remove(addr),
+        };
+        gone.map_or(0, |c| c.len())
+    }
+
+    /// Drop every idle connection, returning how many were closed
+    pub fn clear(&self) -> usize {
+        let mut map = match self.idle.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let n = map.values().map(|c| c.len()).sum();
+        map.clear();
+        n
+    }
+
+    /// Close idle connections past the idle timeout, returning how many were closed
+    pub fn sweep(&self) -> usize {
+        let idle_timeout = self.idle_timeout();
+        let mut map = match self.idle.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut evicted = 0;
+        for conns in map.values_mut() {
+            let before = conns.len();
+            conns.retain(|p| p.returned.elapsed() < idle_timeout);
+            evicted += before - conns.len();
         }
+        map.retain(|_, c| !c.is_empty());
+        crate::metrics::add_pool_evicted(evicted as u64);
+        evicted
     }
 
-    #[allow(dead_code)]
-    pub fn clear(&self) {
+    /// Idle connections held across all backends
+    pub fn idle_count(&self) -> usize {
         match self.idle.lock() {
-            Ok(mut g) => g.clear(),
-            Err(poisoned) => poisoned.into_inner().clear(),
+            Ok(g) => g.values().map(|c| c.len()).sum(),
+            Err(poisoned) => poisoned.into_inner().values().map(|c| c.len()).sum(),
+        }
+    }
+
+    /// (backend, in use, idle) for every backend with a connection either way, by address
+    pub fn stats(&self) -> Vec<(SocketAddr, usize, usize)> {
+        let mut out: Vec<(SocketAddr, usize, usize)> = match self.busy.lock() {
+            Ok(g) => g.iter().map(|(a, n)| (*a, n.load(Ordering::Acquire), 0)).collect(),
+            Err(poisoned) => poisoned.into_inner().iter().map(|(a, n)| (*a, n.load(Ordering::Acquire), 0)).collect(),
         };
+        let idle = match self.idle.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (addr, conns) in idle.iter() {
+            match out.iter_mut().find(|(a, _, _)| a == addr) {
+                Some(e) => e.2 = conns.len(),
+                None => out.push((*addr, 0, conns.len())),
+            }
+        }
+        out.retain(|&(_, in_use, idle)| in_use > 0 || idle > 0);
+        out.sort_by_key(|&(a, _, _)| a);
+        out
     }
 }
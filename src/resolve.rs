@@ -0,0 +1,137 @@
+// Backend address resolution with a short-lived DNS cache
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const DNS_TTL: Duration = Duration::from_secs(30);
+const MAX_CACHED: usize = 1024;
+
+const PREFER_ANY: u8 = 0;
+const PREFER_IPV4: u8 = 1;
+const PREFER_IPV6: u8 = 2;
+
+static PREFER: AtomicU8 = AtomicU8::new(PREFER_IPV4);
+static CACHE: OnceLock<Mutex<HashMap<String, (SocketAddr, Instant)>>> = OnceLock::new();
+
+fn cache() -> &'static Mutex<HashMap<String, (SocketAddr, Instant)>> {
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn set_prefer(policy: &str) {
+    let p = match policy.to_lowercase().as_str() {
+        "ipv4" | "prefer_ipv4" => PREFER_IPV4,
+        "ipv6" | "prefer_ipv6" => PREFER_IPV6,
+        "any" => PREFER_ANY,
+        other => {
+            crate::log::warn(&format!("dns_prefer '{other}' unknown, using ipv4"));
+            PREFER_IPV4
+        }
+    };
+    PREFER.store(p, Ordering::Relaxed);
+}
+
+/// True if `addr` looks like `ip:port` or `host:port`
+pub fn is_valid_addr(addr: &str) -> bool {
+    if addr.parse::<SocketAddr>().is_ok() { return true; }
+    match addr.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && !host.contains('/') && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+/// Resolve a backend address, honouring the configured address-family preference.
+/// Literal IPs bypass DNS; hostnames are cached for a short TTL.
+pub fn resolve(addr: &str) -> std::io::Result<SocketAddr> {
+    resolve_with(addr, system_lookup)
+}
+
+pub fn resolve_with(
+    addr: &str,
+    lookup: impl Fn(&str) -> std::io::Result<Vec<SocketAddr>>,
+) -> std::io::Result<SocketAddr> {
+    if let Ok(sa) = addr.parse::<SocketAddr>() {
+        return Ok(sa);
+    }
+
+    if let Ok(m) = cache().lock() {
+        if let Some((sa, at)) = m.get(addr) {
+            if at.elapsed() < DNS_TTL {
+                return Ok(*sa);
+            }
+        }
+    }
+
+    let candidates = lookup(addr)?;
+    let picked = pick(&candidates, PREFER.load(Ordering::Relaxed)).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, format!("no addresses for {addr}"))
+    })?;
+
+    let mut m = match cache().lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if m.len() >= MAX_CACHED {
+        m.retain(|_, (_, at)| at.elapsed() < DNS_TTL);
+    }
+    if let Some((old, _)) = m.insert(addr.to_string(), (picked, Instant::now())) {
+        if old != picked {
+            changed(addr, old, picked);
+        }
+    }
+    Ok(picked)
+}
+
+fn system_lookup(addr: &str) -> std::io::Result<Vec<SocketAddr>> {
+    Ok(addr.to_socket_addrs()?.collect())
+}
+
+fn pick(candidates: &[SocketAddr], prefer: u8) -> Option<SocketAddr> {
+    let preferred = match prefer {
+        PREFER_IPV4 => candidates.iter().find(|a| a.is_ipv4()),
+        PREFER_IPV6 => candidates.iter().find(|a| a.is_ipv6()),
+        _ => None,
+    };
+    preferred.or_else(|| candidates.first()).copied()
+}
+
+fn changed(host: &str, old: SocketAddr, new: SocketAddr) {
+    crate::log::info(&format!("dns: {host} moved {old} -> {new}"));
+    crate::pool::global_pool().evict(&old);
+}
+
+/// Re-resolve every cached hostname, dropping pooled connections to addresses that moved
+pub fn refresh_with(lookup: impl Fn(&str) -> std::io::Result<Vec<SocketAddr>>) {
+    let hosts: Vec<(String, SocketAddr)> = match cache().lock() {
+        Ok(m) => m.iter().map(|(k, (sa, _))| (k.clone(), *sa)).collect(),
+        Err(_) => return,
+    };
+    let prefer = PREFER.load(Ordering::Relaxed);
+    for (host, old) in hosts {
+        let picked = match lookup(&host).ok().and_then(|c| pick(&c, prefer)) {
+            Some(sa) => sa,
+            None => {
+                crate::log::warn(&format!("dns: refresh failed for {host}, keeping {old}"));
+                continue;
+            }
+        };
+        if let Ok(mut m) = cache().lock() {
+            m.insert(host.clone(), (picked, Instant::now()));
+        }
+        if picked != old {
+            changed(&host, old, picked);
+        }
+    }
+}
+
+/// Background re-resolution every `interval` seconds until shutdown
+pub fn start_refresh(interval: u64) {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(Duration::from_secs(interval));
+            if crate::server::SHUTDOWN.load(Ordering::Acquire) { break; }
+            refresh_with(system_lookup);
+        }
+    });
+}
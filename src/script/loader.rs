@@ -59,43 +59,25 @@ pub fn load_script_modules(
     config: &HashMap<String, toml::Value>,
     server: &Srv,
 ) {
-    let mods_dir = Path::new("mods");
+    load_script_modules_from(Path::new("mods"), pipeline, config, server);
+}
+
+/// Load script modules from `mods_dir`, stopping once `max_script_modules` are loaded (0 = no limit)
+pub fn load_script_modules_from(
+    mods_dir: &Path,
+    pipeline: &mut Pipeline,
+    config: &HashMap<String, toml::Value>,
+    server: &Srv,
+) {
     if !mods_dir.exists() { return; }
 
-    let mut defs: Vec<(ScriptDef, std::path::PathBuf)> = Vec::new();
-
-    if let Ok(entries) = std::fs::read_dir(mods_dir) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if !path.is_file() { continue; }
-            if path.extension().map(|e| e == "pcmod").unwrap_or(false) {
-                match std::fs::metadata(&path) {
-                    Ok(meta) if meta.len() > 1_048_576 => {
-                        crate::log::warn(&format!("{}: file too large (>1MB), skipping", path.display()));
-                        continue;
-                    }
-                    Err(_) => continue,
-                    _ => {}
-                }
-                if let Ok(src) = std::fs::read_to_string(&path) {
-                    match parser::parse(&src) {
-                        Ok(def) => defs.push((def, path)),
-                        Err(e) => {
-                            crate::log::warn(&format!(
-                                "Failed to parse {}: {e}",
-                                path.display()
-                            ));
-                        }
-                    }
-                }
-            }
-        }
-    }
+    let mut defs = read_defs(mods_dir);
 
     // Sort by priority
     defs.sort_by_key(|(d, _)| d.priority);
 
     let mut cache_eviction_started = false;
+    let mut loaded = 0usize;
 
     for (def, path) in defs {
         let resolved = parser::resolve_config(&def, config);
@@ -114,6 +96,15 @@ pub fn load_script_modules(
             continue;
         }
 
+        if server.max_script_modules > 0 && loaded >= server.max_script_modules {
+            crate::log::warn(&format!(
+                "script: {} skipped, max_script_modules ({}) reached",
+                def.name, server.max_script_modules
+            ));
+            continue;
+        }
+        loaded += 1;
+
         crate::log::info(&format!(
             "script: loading {} v{} from {}",
             def.name, def.version, path.display()
@@ -158,6 +149,40 @@ pub fn load_script_modules(
     }
 }
 
+fn read_defs(mods_dir: &Path) -> Vec<(ScriptDef, std::path::PathBuf)> {
+    let mut defs: Vec<(ScriptDef, std::path::PathBuf)> = Vec::new();
+
+    if let Ok(entries) = std::fs::read_dir(mods_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.is_file() { continue; }
+            if path.extension().map(|e| e == "pcmod").unwrap_or(false) {
+                match std::fs::metadata(&path) {
+                    Ok(meta) if meta.len() > 1_048_576 => {
+                        crate::log::warn(&format!("{}: file too large (>1MB), skipping", path.display()));
+                        continue;
+                    }
+                    Err(_) => continue,
+                    _ => {}
+                }
+                if let Ok(src) = std::fs::read_to_string(&path) {
+                    match parser::parse(&src) {
+                        Ok(def) => defs.push((def, path)),
+                        Err(e) => {
+                            crate::log::warn(&format!(
+                                "Failed to parse {}: {e}",
+                                path.display()
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    defs
+}
+
 fn resolve_init_arg(arg: &str, config: &HashMap<String, String>) -> String {
     if let Some(key) = arg.strip_prefix('$') {
         config.get(key).cloned().unwrap_or_default()
@@ -189,4 +214,21 @@ impl Module for ScriptModule {
     fn on_response(&self, req: &HttpRequest, resp: &mut HttpResponse, ctx: &mut Context) {
         runtime::exec_response(&self.on_response, req, resp, ctx, &self.config);
     }
+
+    fn reads_body(&self) -> bool {
+        calls_stdlib(&self.on_response)
+    }
+
+    fn script_config(&self) -> Option<&HashMap<String, String>> {
+        Some(&self.config)
+    }
+}
+
+/// Stdlib functions (compression, caching) may read or rewrite the response body
+fn calls_stdlib(cmds: &[Command]) -> bool {
+    cmds.iter().any(|c| match c {
+        Command::StdCall { .. } => true,
+        Command::If { body, .. } => calls_stdlib(body),
+        _ => false,
+    })
 }
@@ -38,8 +38,11 @@ pub enum Command {
     Respond { code: u16, content_type: String, body: String },
     SetHeader { name: String, value: String },
     Log { level: String, msg: String },
+    // Emits about one in `rate` runs; the counter is per call site
+    LogSampled { level: String, rate: u64, msg: String, count: std::sync::Arc<std::sync::atomic::AtomicU64> },
     SetCtx { key: String, value: String },
     StdCall { func: String, args: Vec<String> },
+    RewriteRegex { re: regex_lite::Regex, replacement: String },
 }
 
 impl FieldValue {
@@ -233,6 +236,40 @@ fn parse_command(line: &str, lines: &[&str], i: &mut usize) -> Option<Command> {
         }
     }
 
+    // std.log_sampled <level> <rate> <msg>
+    if let Some(rest) = line.strip_prefix("std.log_sampled ") {
+        let parts: Vec<&str> = rest.splitn(3, ' ').collect();
+        let rate = parts.get(1).and_then(|r| r.parse::<u64>().ok());
+        return match (parts.len(), rate) {
+            (3, Some(rate)) => Some(Command::LogSampled {
+                level: parts[0].to_string(),
+                rate: rate.max(1),
+                msg: parts[2].to_string(),
+                count: Default::default(),
+            }),
+            _ => {
+                crate::log::warn(&format!("std.log_sampled needs <level> <rate> <msg>: '{line}'"));
+                None
+            }
+        };
+    }
+
+    // std.rewrite_regex <pattern> <replacement> — compiled here, once
+    if let Some(rest) = line.strip_prefix("std.rewrite_regex ") {
+        let parts: Vec<&str> = rest.split_whitespace().collect();
+        if parts.len() != 2 {
+            crate::log::warn(&format!("std.rewrite_regex needs <pattern> <replacement>: '{line}'"));
+            return None;
+        }
+        return match regex_lite::Regex::new(parts[0]) {
+            Ok(re) => Some(Command::RewriteRegex { re, replacement: parts[1].to_string() }),
+            Err(e) => {
+                crate::log::warn(&format!("std.rewrite_regex: invalid pattern '{}': {e}", parts[0]));
+                None
+            }
+        };
+    }
+
     // std.<func> [args...]
     if line.starts_with("std.") {
         let parts: Vec<&str> = line.split_whitespace().collect();
@@ -244,8 +281,17 @@ fn parse_command(line: &str, lines: &[&str], i: &mut usize) -> Option<Command> {
     None
 }
 
+const CONDITION_OPS: &[&str] = &["==", "!=", "contains", "matches"];
+
 fn parse_condition(s: &str) -> Option<(String, String, String)> {
-    for op in &["==", "!=", "contains"] {
+    // Prefer `<field> <op> <value>` so operators inside the value are left alone
+    let mut parts = s.splitn(3, char::is_whitespace);
+    if let (Some(field), Some(op), Some(value)) = (parts.next(), parts.next(), parts.next()) {
+        if CONDITION_OPS.contains(&op) {
+            return Some((field.to_string(), op.to_string(), value.trim().trim_matches('"').to_string()));
+        }
+    }
+    for op in CONDITION_OPS {
         if let Some(idx) = s.find(op) {
             let field = s[..idx].trim().to_string();
             let value = s[idx + op.len()..].trim().trim_matches('"').to_string();
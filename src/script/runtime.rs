@@ -4,6 +4,9 @@ use crate::context::Context;
 use crate::http::{HttpRequest, HttpResponse};
 use std::collections::HashMap;
 
+// Upper bound on how much of a request body `body` conditions look at
+const MAX_BODY_MATCH: usize = 64 * 1024;
+
 /// Evaluate on_request commands, return Some(response) to short-circuit pipeline
 pub fn exec_request(
     cmds: &[Command],
@@ -51,7 +54,7 @@ fn exec_request_cmd(
             let resp = HttpResponse {
                 version: "HTTP/1.1".to_string(),
                 status_code: *code,
-                status_text: status_text(*code).to_string(),
+                status_text: crate::http::reason_phrase(*code).unwrap_or("OK").to_string(),
                 headers: vec![
                     ("Content-Type".to_string(), content_type.clone()),
                     ("Content-Length".to_string(), resolved_body.len().to_string()),
@@ -65,13 +68,11 @@ fn exec_request_cmd(
             req.set_header(name, &val);
         }
         Command::Log { level, msg } => {
-            let resolved = resolve_value(msg, config);
-            match level.as_str() {
-                "debug" => crate::log::debug(&resolved),
-                "info" => crate::log::info(&resolved),
-                "warn" => crate::log::warn(&resolved),
-                "error" => crate::log::error(&resolved),
-                _ => crate::log::info(&resolved),
+            log_at(level, &resolve_value(msg, config));
+        }
+        Command::LogSampled { level, rate, msg, count } => {
+            if sample(count, *rate) {
+                log_at(level, &log_message(msg, req, ctx, config));
             }
         }
         Command::SetCtx { key, value } => {
@@ -84,6 +85,11 @@ fn exec_request_cmd(
                 .collect();
             return super::stdlib::call_request(func, &resolved_args, req, ctx, config);
         }
+        Command::RewriteRegex { re, replacement } => {
+            if let std::borrow::Cow::Owned(path) = re.replace(&req.path, replacement.as_str()) {
+                req.path = path;
+            }
+        }
     }
     None
 }
@@ -108,13 +114,10 @@ fn exec_response_cmd(
             resp.set_header(name, &val);
         }
         Command::Log { level, msg } => {
-            let resolved = resolve_value(msg, config);
-            match level.as_str() {
-                "debug" => crate::log::debug(&resolved),
-                "warn" => crate::log::warn(&resolved),
-                "error" => crate::log::error(&resolved),
-                _ => crate::log::info(&resolved),
-            }
+            log_at(level, &resolve_value(msg, config));
+        }
+        Command::LogSampled { level, rate, msg, count } if sample(count, *rate) => {
+            log_at(level, &log_message(msg, req, ctx, config));
         }
         Command::SetCtx { key, value } => {
             let val = resolve_value(value, config);
@@ -143,10 +146,20 @@ fn resolve_field(
         "version" => req.version.clone(),
         "status" => resp.map(|r| r.status_code.to_string()).unwrap_or_default(),
         "client_ip" => ctx.get("_client_ip").unwrap_or("?").to_string(),
+        "tls_version" => ctx.get("_tls_version").unwrap_or("").to_string(),
+        "tls_cipher" => ctx.get("_tls_cipher").unwrap_or("").to_string(),
+        "alpn" => ctx.get("_alpn").unwrap_or("").to_string(),
+        "body" => {
+            let end = req.body.len().min(MAX_BODY_MATCH);
+            String::from_utf8_lossy(&req.body[..end]).into_owned()
+        }
         f if f.starts_with("header.") => {
             let name = &f[7..];
             req.get_header(name).unwrap_or("").to_string()
         }
+        f if f.starts_with("query.") => {
+            crate::http::query_param(&req.path, &f[6..]).unwrap_or_default()
+        }
         f if f.starts_with('$') => {
             let key = &f[1..];
             config.get(key).cloned().unwrap_or_default()
@@ -155,6 +168,55 @@ fn resolve_field(
     }
 }
 
+/// Replace each `${field}` in `template` with the request field it names, using the
+/// same fields as script conditions (`method`, `path`, `header.X`, `query.q`, ...)
+pub fn interpolate(template: &str, req: &HttpRequest, ctx: &Context) -> String {
+    let none = HashMap::new();
+    let mut out = String::with_capacity(template.len() + req.path.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else { break };
+        out.push_str(&rest[..start]);
+        out.push_str(&resolve_field(&rest[start + 2..start + 2 + len], req, None, ctx, &none));
+        rest = &rest[start + 3 + len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn log_at(level: &str, msg: &str) {
+    match level {
+        "debug" => crate::log::debug(msg),
+        "warn" => crate::log::warn(msg),
+        "error" => crate::log::error(msg),
+        _ => crate::log::info(msg),
+    }
+}
+
+/// True for the first of every `rate` calls on this counter
+pub(crate) fn sample(count: &std::sync::atomic::AtomicU64, rate: u64) -> bool {
+    count.fetch_add(1, std::sync::atomic::Ordering::Relaxed).is_multiple_of(rate)
+}
+
+/// Log text with `$name` config values and `${field}` request fields filled in
+pub(crate) fn log_message(msg: &str, req: &HttpRequest, ctx: &Context, config: &HashMap<String, String>) -> String {
+    let msg = msg.trim_matches('"');
+    let mut out = String::with_capacity(msg.len());
+    let mut rest = msg;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i + 1]);
+        rest = &rest[i + 1..];
+        let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        if end > 0 {
+            out.pop();
+            out.push_str(config.get(&rest[..end]).map_or("", |v| v.as_str()));
+            rest = &rest[end..];
+        }
+    }
+    out.push_str(rest);
+    interpolate(&out, req, ctx)
+}
+
 fn resolve_value(value: &str, config: &HashMap<String, String>) -> String {
     if let Some(key) = value.strip_prefix('$') {
         config.get(key).cloned().unwrap_or_default()
@@ -168,22 +230,7 @@ fn eval_cond(left: &str, op: &str, right: &str) -> bool {
         "==" => left == right,
         "!=" => left != right,
         "contains" => left.contains(right),
+        "matches" => crate::modules::helpers::glob_match(left, right),
         _ => false,
     }
 }
-
-fn status_text(code: u16) -> &'static str {
-    match code {
-        200 => "OK",
-        304 => "Not Modified",
-        400 => "Bad Request",
-        403 => "Forbidden",
-        404 => "Not Found",
-        429 => "Too Many Requests",
-        500 => "Internal Server Error",
-        502 => "Bad Gateway",
-        503 => "Service Unavailable",
-        504 => "Gateway Timeout",
-        _ => "OK",
-    }
-}
@@ -42,14 +42,20 @@ fn cb_opened() -> &'static Mutex<Instant> {
     CB_OPENED.get_or_init(|| Mutex::new(Instant::now()))
 }
 
-static REQ_ID_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static HEALTH_MAP: OnceLock<Arc<RwLock<HashMap<String, h::BackendHealth>>>> = OnceLock::new();
 
-static HEALTH_MAP: OnceLock<Arc<RwLock<HashMap<String, bool>>>> = OnceLock::new();
-
-fn health_map() -> &'static Arc<RwLock<HashMap<String, bool>>> {
+fn health_map() -> &'static Arc<RwLock<HashMap<String, h::BackendHealth>>> {
     HEALTH_MAP.get_or_init(|| Arc::new(RwLock::new(HashMap::new())))
 }
 
+/// Backends watched by std.active_health, with their last probe result
+pub fn backend_health() -> Vec<(String, h::BackendHealth)> {
+    HEALTH_MAP.get()
+        .and_then(|m| m.read().ok())
+        .map(|m| m.iter().map(|(addr, s)| (addr.clone(), *s)).collect())
+        .unwrap_or_default()
+}
+
 pub fn call_request(
     func: &str,
     args: &[String],
@@ -62,9 +68,10 @@ pub fn call_request(
         "cache.check" => std_cache_check(args, req),
         "circuit_breaker.check" => std_cb_check(args),
         "compress.check" => { std_compress_check(req, ctx); None }
-        "request_id.inject" => { std_request_id_inject(req, ctx); None }
+        "request_id.inject" => { std_request_id_inject(args, req, ctx); None }
         "url_rewrite" => { std_url_rewrite(args, req, _config); None }
         "load_balance" => { std_load_balance(args, ctx, _config); None }
+        "use_upstream" => { std_use_upstream(args, ctx); None }
         "set_backend" => {
             if let Some(addr) = args.first() {
                 ctx.set("_backend_addr", addr.clone());
@@ -74,6 +81,8 @@ pub fn call_request(
         "proxy.forward" => std_proxy_forward(args, req, ctx),
         "metrics.prometheus" => std_metrics_prometheus(),
         "health_response" => std_health_response(args),
+        "deny_ip" => std_deny_ip(args, ctx),
+        "allow_ip" => { std_allow_ip(args, ctx); None }
         _ => {
             crate::log::warn(&format!("std: unknown request function '{func}'"));
             None
@@ -93,7 +102,7 @@ pub fn call_response(
         "cache.store" => std_cache_store(args, req, resp),
         "circuit_breaker.record" => std_cb_record(args, resp),
         "compress.apply" => std_compress_apply(args, resp, ctx),
-        "request_id.propagate" => std_request_id_propagate(resp, ctx),
+        "request_id.propagate" => std_request_id_propagate(args, resp, ctx),
         _ => {
             crate::log::warn(&format!("std: unknown response function '{func}'"));
         }
@@ -183,6 +192,7 @@ fn std_cache_check(args: &[String], req: &HttpRequest) -> Option<HttpResponse> {
 fn std_cache_store(args: &[String], req: &HttpRequest, resp: &mut HttpResponse) {
     if resp.get_header("X-Cache").is_some() || resp.status_code != 200 { return; }
     let ttl: u64 = args.first().and_then(|s| s.parse().ok()).unwrap_or(300);
+    let jitter: u64 = args.get(1).and_then(|s| s.parse().ok()).unwrap_or(0);
     let key = req.path.clone();
 
     let mut m = match cache().lock() {
@@ -192,7 +202,7 @@ fn std_cache_store(args: &[String], req: &HttpRequest, resp: &mut HttpResponse)
 
     m.insert(key, CacheEntry {
         resp: resp.clone(),
-        exp: Instant::now() + Duration::from_secs(ttl),
+        exp: Instant::now() + h::ttl_with_jitter(ttl, jitter),
     });
 }
 
@@ -279,63 +289,37 @@ fn std_cb_record(args: &[String], resp: &mut HttpResponse) {
 }
 
 fn std_compress_check(req: &HttpRequest, ctx: &mut Context) {
-    if let Some(ae) = req.get_header("Accept-Encoding") {
-        if ae.contains("gzip") {
-            ctx.set("_accepts_gzip", "1".to_string());
-        }
+    if let Some(enc) = req.get_header("Accept-Encoding").and_then(h::negotiate_encoding) {
+        ctx.set("_accept_encoding", enc.to_string());
     }
 }
 
 fn std_compress_apply(args: &[String], resp: &mut HttpResponse, ctx: &Context) {
-    if ctx.get("_accepts_gzip").is_none() { return; }
     let min_size: usize = args.first().and_then(|s| s.parse().ok()).unwrap_or(256);
-    if resp.body.len() < min_size { return; }
-    if resp.get_header("Content-Encoding").is_some() { return; }
-
-    let ct = resp.get_header("Content-Type").unwrap_or("");
-    if !is_compressible(ct) { return; }
-
-    use flate2::write::GzEncoder;
-    use flate2::Compression;
-    use std::io::Write;
-
-    let mut enc = GzEncoder::new(Vec::new(), Compression::fast());
-    if enc.write_all(&resp.body).is_err() { return; }
-    let compressed = match enc.finish() {
-        Ok(v) => v,
-        Err(_) => return,
-    };
-    if compressed.len() >= resp.body.len() { return; }
-
-    resp.body = compressed;
-    resp.set_header("Content-Encoding", "gzip");
-    resp.set_header("Content-Length", &resp.body.len().to_string());
-    resp.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("Transfer-Encoding"));
-}
-
-fn is_compressible(ct: &str) -> bool {
-    ct.starts_with("text/") || ct.contains("json") || ct.contains("xml")
-        || ct.contains("javascript") || ct.contains("svg") || ct.contains("css")
+    if let Some(enc) = ctx.get("_accept_encoding") {
+        h::compress_response(resp, enc, min_size);
+    }
 }
 
-fn std_request_id_inject(req: &mut HttpRequest, ctx: &mut Context) {
-    let id = req.get_header("X-Request-Id")
+/// `std.request_id.inject [header] [format]`; format is one of `helpers::REQUEST_ID_FORMATS`
+fn std_request_id_inject(args: &[String], req: &mut HttpRequest, ctx: &mut Context) {
+    let header = args.first().map(|s| s.as_str()).unwrap_or("X-Request-Id");
+    let format = args.get(1).map(|s| s.as_str()).unwrap_or("timestamp");
+    let id = req.get_header(header)
         .map(|s| s.to_string())
-        .unwrap_or_else(|| {
-            let ts = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_micros() as u64;
-            let seq = REQ_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            format!("{ts:x}-{seq:04x}")
-        });
-    req.set_header("X-Request-Id", &id);
+        .unwrap_or_else(|| crate::modules::helpers::generate_request_id(format));
+    req.set_header(header, &id);
     ctx.set("_request_id", id);
+    ctx.set("_request_id_header", header.to_string());
 }
 
-fn std_request_id_propagate(resp: &mut HttpResponse, ctx: &Context) {
+/// `std.request_id.propagate [header]`, defaulting to the header `inject` used
+fn std_request_id_propagate(args: &[String], resp: &mut HttpResponse, ctx: &Context) {
+    let header = args.first().map(|s| s.as_str())
+        .or_else(|| ctx.get("_request_id_header"))
+        .unwrap_or("X-Request-Id");
     if let Some(id) = ctx.get("_request_id") {
-        resp.headers.push(("X-Request-Id".to_string(), id.to_string()));
+        resp.headers.push((header.to_string(), id.to_string()));
     }
 }
 
@@ -381,18 +365,27 @@ fn std_load_balance(args: &[String], ctx: &mut Context, config: &HashMap<String,
     let start = LB_INDEX.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % len;
 
     for offset in 0..len {
-        let addr = backends[(start + offset) % len];
-        if is_backend_healthy(addr) {
-            ctx.set("_backend_addr", addr.to_string());
+        let (addr, timeout) = h::parse_backend(backends[(start + offset) % len]);
+        if is_backend_healthy(&addr) {
+            h::set_backend(ctx, &addr, timeout);
             return;
         }
     }
-    ctx.set("_backend_addr", backends[start % len].to_string());
+    let (addr, timeout) = h::parse_backend(backends[start % len]);
+    h::set_backend(ctx, &addr, timeout);
+}
+
+fn std_use_upstream(args: &[String], ctx: &mut Context) {
+    let Some(name) = args.first() else { return };
+    match crate::upstream::select(name) {
+        Some((addr, timeout)) => h::set_backend(ctx, &addr, timeout),
+        None => crate::log::warn(&format!("std.use_upstream: unknown upstream '{name}'")),
+    }
 }
 
 fn is_backend_healthy(addr: &str) -> bool {
     health_map().read().ok()
-        .and_then(|m| m.get(addr).copied())
+        .and_then(|m| m.get(addr).map(|s| s.up))
         .unwrap_or(true)
 }
 
@@ -401,51 +394,67 @@ fn std_proxy_forward(
     req: &mut HttpRequest,
     ctx: &mut Context,
 ) -> Option<HttpResponse> {
-    let addr = ctx.get("_backend_addr")?;
-    let sock_addr: std::net::SocketAddr = match addr.parse() {
+    let addr = ctx.get("_backend_addr")?.to_string();
+    let started = Instant::now();
+    let resp = forward_to(&addr, req, ctx);
+    crate::metrics::record_backend(&addr, resp.status_code < 500, started.elapsed().as_millis() as u64);
+    Some(resp)
+}
+
+fn forward_to(addr: &str, req: &HttpRequest, ctx: &Context) -> HttpResponse {
+    let sock_addr = match crate::resolve::resolve(addr) {
         Ok(a) => a,
-        Err(_) => return Some(HttpResponse::error(502, "Invalid backend address")),
+        Err(e) => {
+            crate::log::warn(&format!("std.proxy: cannot resolve backend {addr}: {e}"));
+            return HttpResponse::error(502, "Backend resolution failed");
+        }
     };
 
-    let timeout = Duration::from_secs(30);
+    let route = h::route_timeout(&req.path);
+    let secs = match &route {
+        Some((_, t)) => *t,
+        None => h::backend_timeout(ctx, 30),
+    };
+    let timeout = Duration::from_secs(secs);
     let pool = crate::pool::global_pool();
 
     let mut s = match pool.get(&sock_addr, timeout) {
         Ok(s) => s,
-        Err(_) => return Some(HttpResponse::error(502, "Backend unavailable")),
+        Err(_) => return HttpResponse::error(502, "Backend unavailable"),
     };
+    let _lease = pool.lease(sock_addr);
 
     let _ = s.set_read_timeout(Some(timeout));
     let _ = s.set_write_timeout(Some(timeout));
 
     use std::io::Write;
-    if let Err(e) = s.write_all(&req.to_bytes()) {
+    if let Err(e) = s.write_all(&req.to_backend_bytes()) {
         crate::log::warn(&format!("std.proxy: backend write error: {e}"));
-        return Some(HttpResponse::error(502, "Backend write failed"));
+        return HttpResponse::error(502, "Backend write failed");
     }
 
-    match crate::http::read_http_message(&mut s, 8192) {
+    match crate::http::read_final_response(&mut s, 8192, false).1 {
         crate::http::ReadResult::Ok(d) => {
             match HttpResponse::parse(&d) {
                 Some(parsed) => {
-                    let conn_hdr = parsed.get_header("Connection").unwrap_or("");
-                    let keep_alive = if parsed.version == "HTTP/1.0" {
-                        conn_hdr.eq_ignore_ascii_case("keep-alive")
-                    } else {
-                        !conn_hdr.eq_ignore_ascii_case("close")
-                    };
+                    let keep_alive = crate::http::keep_alive(&parsed.version, parsed.get_header("Connection"));
                     if keep_alive {
                         pool.put(sock_addr, s);
                     }
-                    Some(parsed)
+                    parsed
                 }
-                None => Some(HttpResponse::error(502, "Parse failed")),
+                None => HttpResponse::error(502, "Parse failed"),
             }
         }
-        crate::http::ReadResult::TimedOut => Some(HttpResponse::error(504, "Backend timeout")),
+        crate::http::ReadResult::TimedOut | crate::http::ReadResult::BodyTimedOut => {
+            if let Some((prefix, _)) = route {
+                crate::log::warn(&format!("std.proxy: backend timed out on route {prefix}"));
+            }
+            HttpResponse::error(504, "Backend timeout")
+        }
         crate::http::ReadResult::Error(e) => {
             crate::log::warn(&format!("std.proxy: backend error: {e}"));
-            Some(HttpResponse::error(502, "Backend error"))
+            HttpResponse::error(502, "Backend error")
         }
     }
 }
@@ -464,6 +473,39 @@ fn std_metrics_prometheus() -> Option<HttpResponse> {
     })
 }
 
+/// Whether the client address falls in any of the ranges; a `$config` list arrives
+/// comma-joined, so each argument may hold several
+fn client_in(args: &[String], ctx: &Context) -> bool {
+    let Ok(ip) = h::client_ip(ctx).parse::<std::net::IpAddr>() else { return false };
+    args.iter()
+        .flat_map(|a| a.split(','))
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .any(|s| match h::Cidr::parse(s) {
+            Some(net) => net.contains(&ip),
+            None => {
+                crate::log::warn(&format!("std: invalid address range '{s}'"));
+                false
+            }
+        })
+}
+
+// std.deny_ip <cidr...> — 403 for matching clients, unless an earlier allow_ip matched
+fn std_deny_ip(args: &[String], ctx: &Context) -> Option<HttpResponse> {
+    if ctx.get_bool("_ip_allowed") || !client_in(args, ctx) {
+        return None;
+    }
+    crate::log::debug(&format!("std.deny_ip: blocked {}", h::client_ip(ctx)));
+    Some(HttpResponse::error(403, "Forbidden"))
+}
+
+// std.allow_ip <cidr...> — matching clients skip the deny_ip checks that follow
+fn std_allow_ip(args: &[String], ctx: &mut Context) {
+    if client_in(args, ctx) {
+        ctx.set_bool("_ip_allowed", true);
+    }
+}
+
 fn std_health_response(args: &[String]) -> Option<HttpResponse> {
     let body = args.first().cloned().unwrap_or_else(|| r#"{"status":"ok"}"#.to_string());
     Some(h::json_response(200, &body))
@@ -482,7 +524,7 @@ fn std_active_health_start(args: &[String], config: &HashMap<String, String>, se
         backends.push(server.backend_addr.clone());
     }
 
-    let map: HashMap<String, bool> = backends.iter().map(|b| (b.clone(), true)).collect();
+    let map: HashMap<String, h::BackendHealth> = backends.iter().map(|b| (b.clone(), h::BackendHealth::UNCHECKED)).collect();
     let health = health_map();
     if let Ok(mut m) = health.write() {
         *m = map;
@@ -494,17 +536,16 @@ fn std_active_health_start(args: &[String], config: &HashMap<String, String>, se
             std::thread::sleep(Duration::from_secs(interval));
             if crate::server::SHUTDOWN.load(std::sync::atomic::Ordering::Acquire) { break; }
             if let Ok(mut m) = health.write() {
-                for (addr, up) in m.iter_mut() {
-                    let ok = std::net::TcpStream::connect_timeout(
-                        &addr.parse().unwrap_or_else(|_| ([127, 0, 0, 1], 80).into()),
-                        Duration::from_secs(timeout),
-                    ).is_ok();
-                    if *up && !ok {
+                for (addr, state) in m.iter_mut() {
+                    let ok = crate::resolve::resolve(addr).map(|sa| {
+                        std::net::TcpStream::connect_timeout(&sa, Duration::from_secs(timeout)).is_ok()
+                    }).unwrap_or(false);
+                    let was_up = state.record(ok);
+                    if was_up && !ok {
                         crate::log::warn(&format!("std.active_health: {addr} DOWN"));
-                    } else if !*up && ok {
+                    } else if !was_up && ok {
                         crate::log::info(&format!("std.active_health: {addr} UP"));
                     }
-                    *up = ok;
                 }
             }
         }
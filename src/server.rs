@@ -1,10 +1,11 @@
 // TCP/TLS server with HTTP/1.1, HTTP/2 (ALPN), and HTTP/3 (QUIC) support
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream, Shutdown};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, RwLock, mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::config::Srv;
 use crate::context::Context;
@@ -12,7 +13,131 @@ use crate::http::{HttpRequest, HttpResponse, ReadResult};
 use crate::modules::Pipeline;
 
 pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+// Shutdown requested: readiness fails from here, SHUTDOWN follows after the pre-drain delay
+pub static DRAINING: AtomicBool = AtomicBool::new(false);
+static PRE_DRAIN_DELAY_SECS: AtomicU64 = AtomicU64::new(0);
 static ACTIVE_CONNS: AtomicUsize = AtomicUsize::new(0);
+// Connections accepted into the worker channel but not yet picked up
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static LIVE: OnceLock<PipelineHandle> = OnceLock::new();
+
+/// Swappable handle to the live pipeline; each connection keeps the snapshot it started with
+#[derive(Clone)]
+pub struct PipelineHandle(Arc<RwLock<Arc<Pipeline>>>);
+
+impl PipelineHandle {
+    pub fn new(p: Pipeline) -> Self {
+        PipelineHandle(Arc::new(RwLock::new(Arc::new(p))))
+    }
+
+    pub fn load(&self) -> Arc<Pipeline> {
+        match self.0.read() {
+            Ok(g) => Arc::clone(&g),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+
+    /// Install `p` for new connections and return the previous pipeline
+    pub fn swap(&self, p: Pipeline) -> Arc<Pipeline> {
+        let mut g = match self.0.write() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        std::mem::replace(&mut *g, Arc::new(p))
+    }
+}
+
+/// The running server's pipeline, if one has been started
+pub fn live_pipeline() -> Option<&'static PipelineHandle> {
+    LIVE.get()
+}
+
+/// Register every Rust and script module from `mc` into a fresh, sorted pipeline
+pub fn build_pipeline(mc: &HashMap<String, toml::Value>, sc: &Srv) -> Pipeline {
+    let mut p = Pipeline::new(sc.client_timeout);
+    crate::modules::register_all(&mut p, mc, sc);
+    crate::script::load_script_modules(&mut p, mc, sc);
+    p.set_config(mc);
+    p.sort();
+    crate::log::info("Pipeline order:");
+    for (priority, name) in p.order() {
+        crate::log::info(&format!("  {priority:>5}  {name}"));
+    }
+    p
+}
+
+/// Effective settings worth checking at a glance: modules in execution order, backends,
+/// TLS/ALPN and limits. One JSON object when `log_format = "json"`, else indented lines.
+pub fn startup_summary(p: &Pipeline, mc: &HashMap<String, toml::Value>, sc: &Srv) -> String {
+    use crate::modules::helpers as h;
+    let modules = p.order();
+    let mut backends: Vec<String> = if h::is_enabled(mc, "load_balancer") {
+        h::config_vec_str(mc, "load_balancer", "backends").iter().map(|b| h::parse_backend(b).0).collect()
+    } else {
+        Vec::new()
+    };
+    if backends.is_empty() {
+        backends.push(sc.backend_addr.clone());
+    }
+    let tls = !sc.tls_cert.is_empty() && !sc.tls_key.is_empty();
+    let alpn = if sc.alpn_protocols.is_empty() { "default".to_string() } else { sc.alpn_protocols.join(",") };
+    let limits = [
+        ("max_connections", sc.max_connections as u64),
+        ("max_header_size", sc.max_header_size as u64),
+        ("max_header_count", sc.max_header_count as u64),
+        ("max_body_size", sc.max_body_size as u64),
+        ("max_bytes_per_connection", sc.max_bytes_per_connection),
+        ("client_timeout", sc.client_timeout),
+        ("backend_timeout", sc.backend_timeout),
+        ("keep_alive_requests", sc.keep_alive_requests as u64),
+        ("max_request_duration_secs", sc.max_request_duration_secs),
+    ];
+    if sc.log_format.eq_ignore_ascii_case("json") {
+        let list = |v: Vec<String>| v.join(",");
+        return format!(
+            r#"{{"listen":{},"modules":[{}],"backends":[{}],"tls":{{"enabled":{tls},"http2":{},"http3":{},"alpn":{}}},"limits":{{{}}}}}"#,
+            h::json_str(&sc.listen_addr),
+            list(modules.iter().map(|(pr, n)| format!(r#"{{"name":{},"priority":{pr}}}"#, h::json_str(n))).collect()),
+            list(backends.iter().map(|b| h::json_str(b)).collect()),
+            tls && sc.http2, tls && sc.http3, h::json_str(&alpn),
+            list(limits.iter().map(|(k, v)| format!(r#""{k}":{v}"#)).collect()),
+        );
+    }
+    let mut out = vec![format!("Startup summary for {}", sc.listen_addr)];
+    out.push(format!(
+        "  modules:  {}",
+        modules.iter().map(|(pr, n)| format!("{n}({pr})")).collect::<Vec<_>>().join(", ")
+    ));
+    out.push(format!("  backends: {}", backends.join(", ")));
+    out.push(if tls {
+        format!("  tls:      on, http2={} http3={} alpn={alpn}", sc.http2, sc.http3)
+    } else {
+        "  tls:      off".to_string()
+    });
+    out.push(format!(
+        "  limits:   {}",
+        limits.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join(" ")
+    ));
+    out.join("\n")
+}
+
+/// Rebuild the pipeline from `mc` and hot-swap it into `live`.
+/// Returns a JSON summary of which modules were added and removed.
+pub fn reload_pipeline(live: &PipelineHandle, mc: &HashMap<String, toml::Value>, sc: &Srv) -> String {
+    let next = build_pipeline(mc, sc);
+    let after = next.module_names();
+    let before = live.swap(next).module_names();
+    let added: Vec<&String> = after.iter().filter(|n| !before.contains(n)).collect();
+    let removed: Vec<&String> = before.iter().filter(|n| !after.contains(n)).collect();
+    let list = |v: &[&String]| v.iter().map(|n| crate::modules::helpers::json_str(n)).collect::<Vec<_>>().join(",");
+    crate::log::info(&format!(
+        "Pipeline reloaded: {} module(s), +{} -{}", after.len(), added.len(), removed.len()
+    ));
+    format!(
+        r#"{{"action":"reloaded","modules":{},"added":[{}],"removed":[{}]}}"#,
+        after.len(), list(&added), list(&removed),
+    )
+}
 
 pub enum ClientStream {
     Plain(TcpStream),
@@ -62,18 +187,125 @@ impl ClientStream {
         }
     }
 
-    pub fn tls_version(&self) -> Option<&'static str> {
+    pub fn tls_info(&self) -> Option<TlsInfo> {
         match self {
-            ClientStream::Tls(s) => s.conn.protocol_version().map(|v| match v {
-                rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2",
-                rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3",
-                _ => "unknown",
-            }),
+            ClientStream::Tls(s) => Some(TlsInfo::from_conn(&s.conn)),
             _ => None,
         }
     }
 }
 
+/// Negotiated TLS parameters, exposed to modules and scripts as `_tls_version`,
+/// `_tls_cipher` and `_alpn`
+#[derive(Clone)]
+pub(crate) struct TlsInfo {
+    version: &'static str,
+    cipher: String,
+    alpn: Option<String>,
+}
+
+impl TlsInfo {
+    pub(crate) fn from_conn(conn: &rustls::ServerConnection) -> TlsInfo {
+        TlsInfo {
+            version: conn.protocol_version().map(version_name).unwrap_or("unknown"),
+            cipher: conn.negotiated_cipher_suite()
+                .map(|s| format!("{:?}", s.suite()))
+                .unwrap_or_else(|| "unknown".into()),
+            alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+        }
+    }
+
+    pub(crate) fn apply(&self, ctx: &mut Context) {
+        ctx.set("_tls_version", self.version.to_string());
+        ctx.set("_tls_cipher", self.cipher.clone());
+        if let Some(alpn) = &self.alpn {
+            ctx.set("_alpn", alpn.clone());
+        }
+    }
+}
+
+fn version_name(v: rustls::ProtocolVersion) -> &'static str {
+    match v {
+        rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2",
+        rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3",
+        _ => "unknown",
+    }
+}
+
+/// Route a handshaken TLS connection by its negotiated ALPN. Only a genuine `h2` goes to
+/// the h2 handler (when `h2` carries its in-flight limit); anything else, including no
+/// ALPN at all, is handed to the HTTP/1.1 worker pool. `normalize_method` and
+/// `abort_on_panic` apply to h2; pooled HTTP/1.1 connections use the pool's own limits.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve_tls(
+    tls: tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+    h2: Option<usize>,
+    normalize_method: bool,
+    abort_on_panic: bool,
+    pipeline: Arc<Pipeline>,
+    sender: Option<mpsc::SyncSender<ClientStream>>,
+    peer_ip: String,
+    alt: Option<String>,
+) {
+    let alpn = tls.get_ref().1.alpn_protocol().map(|p| p.to_vec());
+    if let (Some(max_inflight), Some(b"h2")) = (h2, alpn.as_deref()) {
+        ACTIVE_CONNS.fetch_add(1, Ordering::AcqRel);
+        crate::metrics::inc_connections();
+        let info = TlsInfo::from_conn(tls.get_ref().1);
+        crate::h2_handler::handle_connection(tls, pipeline, peer_ip, alt, max_inflight, normalize_method, abort_on_panic, Some(info)).await;
+        ACTIVE_CONNS.fetch_sub(1, Ordering::AcqRel);
+        return;
+    }
+    if let Some(p) = alpn.as_deref().filter(|p| *p != b"http/1.1") {
+        crate::log::debug(&format!("TLS from {peer_ip} negotiated ALPN '{}', serving as HTTP/1.1", String::from_utf8_lossy(p)));
+    }
+    let (tokio_tcp, server_conn) = tls.into_inner();
+    match tokio_tcp.into_std() {
+        Ok(std_tcp) => {
+            let _ = std_tcp.set_nonblocking(false);
+            let sync_stream = rustls::StreamOwned::new(server_conn, std_tcp);
+            if let Some(ref s) = sender {
+                if enqueue(s, ClientStream::Tls(sync_stream)).is_err() {
+                    crate::log::warn("Thread pool full, dropping TLS connection");
+                }
+            }
+        }
+        Err(e) => {
+            crate::log::warn(&format!("TcpStream conversion failed: {e}"));
+        }
+    }
+}
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Complete the TLS handshake on an accepted connection, counting failures and
+/// the negotiated protocol version.
+pub(crate) async fn tls_handshake(
+    acceptor: &tokio_rustls::TlsAcceptor,
+    tcp: tokio::net::TcpStream,
+    peer_ip: &str,
+    timeout: Duration,
+) -> Option<tokio_rustls::server::TlsStream<tokio::net::TcpStream>> {
+    match tokio::time::timeout(timeout, acceptor.accept(tcp)).await {
+        Ok(Ok(tls)) => {
+            let ver = tls.get_ref().1.protocol_version().map(version_name).unwrap_or("unknown");
+            crate::metrics::inc_tls_handshakes(ver);
+            crate::log::debug(&format!("TLS handshake with {peer_ip} negotiated {ver}"));
+            Some(tls)
+        }
+        Ok(Err(e)) => {
+            crate::metrics::inc_tls_failures();
+            crate::log::debug(&format!("TLS handshake failed from {peer_ip}: {e}"));
+            None
+        }
+        Err(_) => {
+            crate::metrics::inc_tls_failures();
+            crate::log::debug(&format!("TLS handshake timeout from {peer_ip}"));
+            None
+        }
+    }
+}
+
 impl Read for ClientStream {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self {
@@ -103,31 +335,154 @@ pub fn active_connections() -> usize {
     ACTIVE_CONNS.load(Ordering::Acquire)
 }
 
+/// Begin shutdown. With `pre_drain_delay_secs` set, connections keep being accepted and
+/// served for that long while readiness fails, so load balancers can deregister us first.
+/// Only touches atomics, so signal handlers may call it.
 pub fn request_shutdown() {
-    SHUTDOWN.store(true, Ordering::Release);
+    DRAINING.store(true, Ordering::Release);
+    if PRE_DRAIN_DELAY_SECS.load(Ordering::Acquire) == 0 {
+        SHUTDOWN.store(true, Ordering::Release);
+    }
+}
+
+/// Whether shutdown has begun, including the pre-drain window
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Acquire) || SHUTDOWN.load(Ordering::Acquire)
+}
+
+/// Reads under a short first-byte timeout, then switches the stream to the full read timeout
+struct FirstByte<'a> {
+    inner: &'a mut ClientStream,
+    rest: Option<Duration>,
+    started: bool,
+    // Whole-request cap, counted from the first byte
+    cap: Option<Duration>,
+    until: Option<Instant>,
+    // Bytes the connection may still send, shared across its requests (None = no cap)
+    budget: Option<&'a std::cell::Cell<u64>>,
+    // Already-read bytes of a pipelined request, served before the stream
+    pending: Vec<u8>,
+}
+
+const CONN_BYTES_EXCEEDED: &str = "connection byte limit exceeded";
+
+impl FirstByte<'_> {
+    fn expired(&self) -> bool {
+        self.until.is_some_and(|u| Instant::now() >= u)
+    }
+}
+
+impl Read for FirstByte<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(until) = self.until {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            let _ = self.inner.set_read_timeout(Some(self.rest.map_or(left, |r| r.min(left))));
+        }
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            if !self.started {
+                self.started = true;
+                self.until = self.cap.map(|c| Instant::now() + c);
+            }
+            return Ok(n);
+        }
+        let want = match self.budget {
+            Some(b) if b.get() == 0 => return Err(std::io::Error::other(CONN_BYTES_EXCEEDED)),
+            Some(b) => buf.len().min(usize::try_from(b.get()).unwrap_or(usize::MAX)),
+            None => buf.len(),
+        };
+        let n = self.inner.read(&mut buf[..want])?;
+        if let Some(b) = self.budget {
+            b.set(b.get() - n as u64);
+        }
+        if !self.started && n > 0 {
+            self.started = true;
+            self.until = self.cap.map(|c| Instant::now() + c);
+            let _ = self.inner.set_read_timeout(self.rest);
+        }
+        Ok(n)
+    }
+}
+
+/// Log line for a handler panic, including the panic message when it is a string
+pub fn panic_report(payload: &(dyn std::any::Any + Send)) -> String {
+    let msg = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()));
+    match msg {
+        Some(m) => format!("Panic in handler (recovered): {m}"),
+        None => "Panic in handler (recovered): <non-string payload>".to_string(),
+    }
+}
+
+/// Log a caught handler panic; aborts the process when `abort` (`abort_on_panic`) is set
+pub fn report_panic(payload: Box<dyn std::any::Any + Send>, abort: bool) {
+    crate::log::error(&panic_report(payload.as_ref()));
+    if abort {
+        crate::log::error("abort_on_panic is set, aborting");
+        std::process::abort();
+    }
+}
+
+/// Per-connection HTTP/1.x limits applied by pool workers
+#[derive(Clone, Copy, Default)]
+pub(crate) struct H1Limits {
+    /// Requests served per connection; 0 or 1 closes after the first
+    pub(crate) keep_alive: usize,
+    /// Seconds a kept-alive connection may sit idle before the next request (0 = client timeout)
+    pub(crate) idle_timeout: u64,
+    /// Seconds allowed for reading plus handling one request (0 = no cap)
+    pub(crate) max_request_duration: u64,
+    /// Answer 503 while the memory sampler reports pressure
+    pub(crate) shed_on_low_memory: bool,
+    /// Bytes a client may send over one connection before it is closed with 413 (0 = no cap)
+    pub(crate) max_bytes_per_connection: u64,
+    /// Seconds a new connection gets to send its first byte (0 = client timeout)
+    pub(crate) first_byte_timeout: u64,
+    /// Answer a stalled request body with 408 instead of closing silently
+    pub(crate) body_timeout_408: bool,
+    /// Uppercase request methods (`get` -> `GET`) instead of rejecting them with 400
+    pub(crate) normalize_method_case: bool,
+    /// Abort the process after logging a handler panic instead of recovering
+    pub(crate) abort_on_panic: bool,
+    /// Bytes a chunked request body may reach before 413 (0 = max_body_size)
+    pub(crate) max_chunked_body_size: usize,
+    /// Seconds a chunked request body has to reach its zero chunk (0 = no cap)
+    pub(crate) chunked_body_timeout: u64,
 }
 
-struct ThreadPool {
+pub(crate) struct ThreadPool {
     sender: Option<mpsc::SyncSender<ClientStream>>,
     workers: Vec<thread::JoinHandle<()>>,
+    deadline: Arc<OnceLock<Instant>>,
+    unserved: Arc<AtomicUsize>,
 }
 
 impl ThreadPool {
-    fn new(
+    pub(crate) fn new(
         size: usize,
-        pipe: Arc<Pipeline>,
+        pipe: PipelineHandle,
         buf_size: usize,
         write_timeout: u64,
         alt_svc: Option<String>,
+        limits: H1Limits,
     ) -> Self {
         let (tx, rx) = mpsc::sync_channel::<ClientStream>(size * 2);
         let rx = Arc::new(Mutex::new(rx));
         let mut workers = Vec::with_capacity(size);
+        let deadline = Arc::new(OnceLock::new());
+        let unserved = Arc::new(AtomicUsize::new(0));
 
         for _ in 0..size {
             let rx = Arc::clone(&rx);
-            let pipe = Arc::clone(&pipe);
+            let pipe = pipe.clone();
             let alt = alt_svc.clone();
+            let deadline = Arc::clone(&deadline);
+            let unserved = Arc::clone(&unserved);
             workers.push(thread::spawn(move || {
                 loop {
                     let stream = {
@@ -139,12 +494,18 @@ impl ThreadPool {
                     };
                     match stream {
                         Ok(s) => {
+                            QUEUED.fetch_sub(1, Ordering::AcqRel);
+                            if deadline.get().is_some_and(|d| Instant::now() >= *d) {
+                                unserved.fetch_add(1, Ordering::AcqRel);
+                                continue;
+                            }
                             let _guard = ConnGuard::new();
+                            let pipe = pipe.load();
                             let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                                handle_h1(s, &pipe, buf_size, write_timeout, alt.as_deref());
+                                handle_h1(s, &pipe, buf_size, write_timeout, alt.as_deref(), limits);
                             }));
-                            if result.is_err() {
-                                crate::log::error("Panic in handler (recovered)");
+                            if let Err(payload) = result {
+                                report_panic(payload, limits.abort_on_panic);
                             }
                         }
                         Err(_) => break,
@@ -153,27 +514,107 @@ impl ThreadPool {
             }));
         }
 
-        ThreadPool { sender: Some(tx), workers }
+        ThreadPool { sender: Some(tx), workers, deadline, unserved }
     }
 
-    fn dispatch(&self, stream: ClientStream) -> Result<(), ClientStream> {
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn dispatch(&self, stream: ClientStream) -> Result<(), ClientStream> {
         match &self.sender {
-            Some(tx) => tx.try_send(stream).map_err(|e| match e {
-                mpsc::TrySendError::Full(s) | mpsc::TrySendError::Disconnected(s) => s,
-            }),
+            Some(tx) => enqueue(tx, stream),
             None => Err(stream),
         }
     }
 
-    fn clone_sender(&self) -> Option<mpsc::SyncSender<ClientStream>> {
+    pub(crate) fn clone_sender(&self) -> Option<mpsc::SyncSender<ClientStream>> {
         self.sender.as_ref().cloned()
     }
 
-    fn shutdown(&mut self) {
+    /// Stop accepting work, serve whatever is still queued until `deadline`, then join
+    pub(crate) fn shutdown(&mut self, deadline: Instant) {
+        let _ = self.deadline.set(deadline);
+        let queued = QUEUED.load(Ordering::Acquire);
+        if queued > 0 {
+            crate::log::info(&format!("Serving {queued} queued connection(s)..."));
+        }
         self.sender.take();
         for w in self.workers.drain(..) {
             let _ = w.join();
         }
+        let unserved = self.unserved.load(Ordering::Acquire);
+        if unserved > 0 {
+            crate::log::warn(&format!("Dropped {unserved} queued connection(s) unserved (shutdown timeout)"));
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn enqueue(tx: &mpsc::SyncSender<ClientStream>, stream: ClientStream) -> Result<(), ClientStream> {
+    QUEUED.fetch_add(1, Ordering::AcqRel);
+    tx.try_send(stream).map_err(|e| {
+        QUEUED.fetch_sub(1, Ordering::AcqRel);
+        match e {
+            mpsc::TrySendError::Full(s) | mpsc::TrySendError::Disconnected(s) => s,
+        }
+    })
+}
+
+// Pause after running out of file descriptors, giving in-flight connections time to close
+const FD_EXHAUSTED_BACKOFF: Duration = Duration::from_millis(500);
+
+/// EMFILE / ENFILE: the process or system is out of file descriptors
+pub(crate) fn is_fd_exhausted(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    { matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)) }
+    #[cfg(not(unix))]
+    { e.raw_os_error() == Some(10024) } // WSAEMFILE
+}
+
+/// How long the accept loop waits after `e` before accepting again
+pub(crate) fn accept_backoff(e: &std::io::Error) -> Duration {
+    use std::io::ErrorKind;
+    match e.kind() {
+        // Signal or a client that hung up before we got to it: just retry
+        ErrorKind::Interrupted | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset => Duration::ZERO,
+        _ if is_fd_exhausted(e) => FD_EXHAUSTED_BACKOFF,
+        _ => Duration::from_millis(50),
+    }
+}
+
+fn log_accept_error(e: &std::io::Error, wait: Duration) {
+    if is_fd_exhausted(e) {
+        crate::log::warn(&format!("Accept failed, out of file descriptors ({e}); backing off {}ms", wait.as_millis()));
+    } else if wait.is_zero() {
+        crate::log::debug(&format!("Accept interrupted: {e}"));
+    } else {
+        crate::log::error(&format!("Accept error: {e}"));
+    }
+}
+
+/// Token bucket on the accept path; `rate` new connections per second, bursting to `rate`
+pub(crate) struct AcceptLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl AcceptLimiter {
+    pub(crate) fn new(rate: u64) -> Self {
+        AcceptLimiter { rate: rate as f64, tokens: rate as f64, last: Instant::now() }
+    }
+
+    /// Whether to serve a newly accepted connection; counts the drop when not
+    pub(crate) fn admit(&mut self) -> bool {
+        if self.rate <= 0.0 { return true; }
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            crate::metrics::inc_accept_dropped();
+            false
+        }
     }
 }
 
@@ -193,20 +634,22 @@ impl Drop for ConnGuard {
     }
 }
 
-struct TlsAssets {
-    config: Arc<rustls::ServerConfig>,
-    certs: Vec<rustls::pki_types::CertificateDer<'static>>,
-    key: rustls::pki_types::PrivateKeyDer<'static>,
+pub(crate) struct TlsAssets {
+    pub(crate) config: Arc<rustls::ServerConfig>,
+    pub(crate) certs: Vec<rustls::pki_types::CertificateDer<'static>>,
+    pub(crate) key: rustls::pki_types::PrivateKeyDer<'static>,
 }
 
 pub struct Server {
     cfg: Srv,
-    pipe: Arc<Pipeline>,
+    pipe: PipelineHandle,
 }
 
 impl Server {
     pub fn new(c: Srv, p: Pipeline) -> Self {
-        Server { cfg: c, pipe: Arc::new(p) }
+        let pipe = PipelineHandle::new(p);
+        let _ = LIVE.set(pipe.clone());
+        Server { cfg: c, pipe }
     }
 
     pub fn run(&self) -> std::io::Result<()> {
@@ -242,13 +685,31 @@ impl Server {
         crate::log::info(&format!("Workers: {num_workers} | Max connections: {}", self.cfg.max_connections));
         crate::log::separator();
 
+        if self.cfg.min_free_memory_mb > 0 {
+            crate::memory::start_sampler(self.cfg.min_free_memory_mb);
+        }
+        PRE_DRAIN_DELAY_SECS.store(self.cfg.pre_drain_delay_secs, Ordering::Release);
+        crate::http::set_limits(self.cfg.max_header_size, self.cfg.max_body_size, self.cfg.max_header_count);
         install_shutdown_handler(&self.cfg.listen_addr);
         let mut pool = ThreadPool::new(
             num_workers,
-            Arc::clone(&self.pipe),
+            self.pipe.clone(),
             self.cfg.buffer_size,
             self.cfg.client_timeout,
             alt_svc.clone(),
+            H1Limits {
+                keep_alive: self.cfg.keep_alive_requests,
+                idle_timeout: self.cfg.keep_alive_idle_timeout,
+                max_request_duration: self.cfg.max_request_duration_secs,
+                shed_on_low_memory: self.cfg.min_free_memory_mb > 0,
+                max_bytes_per_connection: self.cfg.max_bytes_per_connection,
+                first_byte_timeout: self.cfg.first_byte_timeout,
+                body_timeout_408: self.cfg.body_timeout_408,
+                normalize_method_case: self.cfg.normalize_method_case,
+                abort_on_panic: self.cfg.abort_on_panic,
+                max_chunked_body_size: self.cfg.max_chunked_body_size,
+                chunked_body_timeout: self.cfg.chunked_body_timeout_secs,
+            },
         );
 
         let max_conns = self.cfg.max_connections;
@@ -261,16 +722,16 @@ impl Server {
 
         crate::log::info("Shutting down...");
         let timeout_secs = self.cfg.shutdown_timeout;
-        let deadline = std::time::Instant::now() + Duration::from_secs(timeout_secs);
+        let deadline = Instant::now() + Duration::from_secs(timeout_secs);
         crate::log::info("Draining connections...");
         let mut last_logged = 0usize;
         loop {
-            let active = ACTIVE_CONNS.load(Ordering::Acquire);
+            let active = ACTIVE_CONNS.load(Ordering::Acquire) + QUEUED.load(Ordering::Acquire);
             if active == 0 {
                 crate::log::info("All connections drained");
                 break;
             }
-            if std::time::Instant::now() > deadline {
+            if Instant::now() > deadline {
                 crate::log::warn(&format!("Forcing shutdown with {active} active connections (timeout {timeout_secs}s)"));
                 break;
             }
@@ -280,7 +741,7 @@ impl Server {
             }
             thread::sleep(Duration::from_millis(100));
         }
-        pool.shutdown();
+        pool.shutdown(deadline);
         crate::log::info("Server stopped.");
         Ok(())
     }
@@ -288,13 +749,18 @@ impl Server {
     fn run_plain(&self, pool: &ThreadPool, max_conns: usize) -> std::io::Result<()> {
         let listener = TcpListener::bind(&self.cfg.listen_addr)?;
         listener.set_nonblocking(true)?;
+        let mut limiter = AcceptLimiter::new(self.cfg.accept_rate_limit);
 
         loop {
             if SHUTDOWN.load(Ordering::Acquire) { break; }
 
             match listener.accept() {
                 Ok((stream, _)) => {
-                    if ACTIVE_CONNS.load(Ordering::Acquire) >= max_conns {
+                    if !limiter.admit() {
+                        drop(stream);
+                        continue;
+                    }
+                    if ACTIVE_CONNS.load(Ordering::Acquire) >= max_conns || crate::memory::shedding() {
                         reject_overloaded(ClientStream::Plain(stream));
                         continue;
                     }
@@ -307,10 +773,12 @@ impl Server {
                     thread::sleep(Duration::from_millis(50));
                 }
                 Err(e) => {
-                    if !SHUTDOWN.load(Ordering::Acquire) {
-                        crate::log::error(&format!("Accept error: {e}"));
+                    if SHUTDOWN.load(Ordering::Acquire) { break; }
+                    let wait = accept_backoff(&e);
+                    log_accept_error(&e, wait);
+                    if !wait.is_zero() {
+                        thread::sleep(wait);
                     }
-                    thread::sleep(Duration::from_millis(50));
                 }
             }
         }
@@ -331,15 +799,19 @@ impl Server {
             .build()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
-        let pipeline = Arc::clone(&self.pipe);
+        let pipeline = self.pipe.clone();
         let pool_sender = pool.clone_sender();
         let listen_addr = self.cfg.listen_addr.clone();
         let http2_enabled = self.cfg.http2;
+        let h2_max_inflight = self.cfg.h2_max_inflight_streams;
+        let normalize_method = self.cfg.normalize_method_case;
+        let abort_on_panic = self.cfg.abort_on_panic;
         let http3_enabled = self.cfg.http3;
         let h3_port = self.cfg.h3_port;
         let _buf_size = self.cfg.buffer_size;
         let _write_timeout = self.cfg.client_timeout;
         let tls_config = assets.config.clone();
+        let mut limiter = AcceptLimiter::new(self.cfg.accept_rate_limit);
 
         rt.block_on(async move {
             let listener = tokio::net::TcpListener::bind(&listen_addr).await?;
@@ -348,9 +820,9 @@ impl Server {
             if http3_enabled {
                 match build_h3_endpoint(&assets.certs, &assets.key, &listen_addr, h3_port) {
                     Ok(endpoint) => {
-                        let h3_pipe = Arc::clone(&pipeline);
+                        let h3_pipe = pipeline.clone();
                         tokio::spawn(async move {
-                            crate::h3_handler::run_h3_server(endpoint, h3_pipe).await;
+                            crate::h3_handler::run_h3_server(endpoint, h3_pipe, normalize_method, abort_on_panic).await;
                         });
                     }
                     Err(e) => {
@@ -367,65 +839,41 @@ impl Server {
                         let (tcp, addr) = match result {
                             Ok(r) => r,
                             Err(e) => {
-                                if !SHUTDOWN.load(Ordering::Acquire) {
-                                    crate::log::error(&format!("Accept error: {e}"));
+                                if SHUTDOWN.load(Ordering::Acquire) { break; }
+                                let wait = accept_backoff(&e);
+                                log_accept_error(&e, wait);
+                                if !wait.is_zero() {
+                                    tokio::time::sleep(wait).await;
                                 }
                                 continue;
                             }
                         };
 
-                        if ACTIVE_CONNS.load(Ordering::Acquire) >= max_conns {
+                        if !limiter.admit() {
+                            drop(tcp);
+                            continue;
+                        }
+
+                        if ACTIVE_CONNS.load(Ordering::Acquire) >= max_conns || crate::memory::shedding() {
                             drop(tcp);
                             crate::metrics::inc_requests_err();
                             continue;
                         }
 
                         let acceptor = acceptor.clone();
-                        let pipeline = Arc::clone(&pipeline);
+                        let pipeline = pipeline.load();
                         let sender = pool_sender.clone();
                         let peer_ip = addr.ip().to_string();
                         let alt = alt_svc.clone();
 
                         tokio::spawn(async move {
-                            let tls = match tokio::time::timeout(
-                                Duration::from_secs(10),
-                                acceptor.accept(tcp),
-                            ).await {
-                                Ok(Ok(tls)) => tls,
-                                Ok(Err(e)) => {
-                                    crate::log::debug(&format!("TLS handshake failed from {peer_ip}: {e}"));
-                                    return;
-                                }
-                                Err(_) => {
-                                    crate::log::debug(&format!("TLS handshake timeout from {peer_ip}"));
-                                    return;
-                                }
+                            let tls = match tls_handshake(&acceptor, tcp, &peer_ip, TLS_HANDSHAKE_TIMEOUT).await {
+                                Some(tls) => tls,
+                                None => return,
                             };
 
-                            let alpn = tls.get_ref().1.alpn_protocol().map(|p| p.to_vec());
-
-                            if http2_enabled && alpn.as_deref() == Some(b"h2") {
-                                ACTIVE_CONNS.fetch_add(1, Ordering::AcqRel);
-                                crate::metrics::inc_connections();
-                                crate::h2_handler::handle_connection(tls, pipeline, peer_ip, alt).await;
-                                ACTIVE_CONNS.fetch_sub(1, Ordering::AcqRel);
-                            } else {
-                                let (tokio_tcp, server_conn) = tls.into_inner();
-                                match tokio_tcp.into_std() {
-                                    Ok(std_tcp) => {
-                                        let _ = std_tcp.set_nonblocking(false);
-                                        let sync_stream = rustls::StreamOwned::new(server_conn, std_tcp);
-                                        if let Some(ref s) = sender {
-                                            if s.try_send(ClientStream::Tls(sync_stream)).is_err() {
-                                                crate::log::warn("Thread pool full, dropping TLS connection");
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        crate::log::warn(&format!("TcpStream conversion failed: {e}"));
-                                    }
-                                }
-                            }
+                            let h2 = http2_enabled.then_some(h2_max_inflight);
+                            serve_tls(tls, h2, normalize_method, abort_on_panic, pipeline, sender, peer_ip, alt).await;
                         });
                     }
                     _ = tokio::time::sleep(Duration::from_millis(100)) => {}
@@ -446,7 +894,9 @@ fn reject_overloaded(mut s: ClientStream) {
     let _ = s.shutdown(Shutdown::Both);
 }
 
-fn handle_h1(mut c: ClientStream, p: &Pipeline, buf_size: usize, write_timeout: u64, alt_svc: Option<&str>) {
+/// Serve a client connection. With `keep_alive` > 1 up to that many requests are read
+/// from one connection, as long as the client and each response allow it.
+fn handle_h1(mut c: ClientStream, p: &Pipeline, buf_size: usize, write_timeout: u64, alt_svc: Option<&str>, limits: H1Limits) {
     crate::metrics::inc_connections();
 
     if let Some(rh) = p.raw_handler() {
@@ -463,58 +913,164 @@ fn handle_h1(mut c: ClientStream, p: &Pipeline, buf_size: usize, write_timeout:
     }
 
     let ip = c.peer_addr().map(|a| a.ip().to_string()).unwrap_or_else(|_| "?".into());
-    let tls_ver = c.tls_version();
+    let tls_info = c.tls_info();
 
     let timeout = Some(Duration::from_secs(p.timeout()));
-    let _ = c.set_read_timeout(timeout);
+    let first_byte = match limits.first_byte_timeout {
+        0 => timeout,
+        secs => Some(Duration::from_secs(secs)),
+    };
     let _ = c.set_write_timeout(Some(Duration::from_secs(write_timeout)));
     let _ = c.set_nodelay(true);
 
-    let raw = match crate::http::read_http_message(&mut c, buf_size) {
-        ReadResult::Ok(d) => d,
-        ReadResult::TimedOut => return,
+    let cap = (limits.max_request_duration > 0).then(|| Duration::from_secs(limits.max_request_duration));
+    let max = limits.keep_alive.max(1);
+    let idle = match limits.idle_timeout {
+        0 => first_byte,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let budget = std::cell::Cell::new(limits.max_bytes_per_connection);
+    let budget = (limits.max_bytes_per_connection > 0).then_some(&budget);
+    // Bytes read past the previous request: the start of a pipelined one
+    let mut carry = Vec::new();
+    for n in 1..=max {
+        // A request that ended exactly on the cap leaves nothing to answer
+        if n > 1 && carry.is_empty() && budget.is_some_and(|b| b.get() == 0) { break; }
+        let _ = c.set_read_timeout(if n > 1 { idle } else { first_byte });
+        let pending = std::mem::take(&mut carry);
+        let reader = FirstByte { inner: &mut c, rest: timeout, started: false, cap, until: None, budget, pending };
+        if !serve_h1_request(reader, p, buf_size, alt_svc, &ip, tls_info.as_ref(), n > 1, n < max, limits, &mut carry) {
+            break;
+        }
+        if SHUTDOWN.load(Ordering::Acquire) { break; }
+    }
+    let _ = c.shutdown(Shutdown::Write);
+    crate::log::separator();
+}
+
+/// Read, handle and answer one request. `more` says whether the connection may carry
+/// another one; returns true if it should. Bytes read past the request are left in `carry`.
+#[allow(clippy::too_many_arguments)]
+fn serve_h1_request(
+    mut reader: FirstByte,
+    p: &Pipeline,
+    buf_size: usize,
+    alt_svc: Option<&str>,
+    ip: &str,
+    tls_info: Option<&TlsInfo>,
+    reused: bool,
+    more: bool,
+    limits: H1Limits,
+    carry: &mut Vec<u8>,
+) -> bool {
+    let chunked_cap = match limits.max_chunked_body_size {
+        0 => crate::http::max_body_size(),
+        n => n,
+    };
+    let chunked_timeout = (limits.chunked_body_timeout > 0).then(|| Duration::from_secs(limits.chunked_body_timeout));
+    let read = crate::http::read_message_capped(&mut reader, buf_size, chunked_cap, chunked_timeout);
+    let until = reader.until;
+    let unread = std::mem::take(&mut reader.pending);
+    if reader.expired() && !matches!(read, ReadResult::Ok(_)) {
+        crate::log::debug(&format!("Request from {ip} exceeded max_request_duration while reading"));
+        crate::metrics::inc_duration_aborts();
+        crate::metrics::inc_requests_err();
+        let _ = reader.inner.write_all(&HttpResponse::error(408, "Request Timeout").to_bytes());
+        return false;
+    }
+    let c = reader.inner;
+    let raw = match read {
+        ReadResult::Ok(mut d) => {
+            if let Some(end) = crate::http::message_len(&d).filter(|&end| end < d.len()) {
+                *carry = d.split_off(end);
+            }
+            carry.extend_from_slice(&unread);
+            d
+        }
+        ReadResult::TimedOut => return false,
+        // An idle keep-alive connection closing is the normal way for it to end
+        ReadResult::Error(e) if reused && e == "connection closed" => return false,
+        ReadResult::BodyTimedOut => {
+            crate::log::debug(&format!("Request body timed out from {ip}"));
+            if limits.body_timeout_408 {
+                let _ = c.write_all(&HttpResponse::error(408, "Request Timeout").to_bytes());
+            }
+            crate::metrics::inc_requests_err();
+            return false;
+        }
         ReadResult::Error(e) => {
             if e == "headers too large" {
                 let _ = c.write_all(&HttpResponse::error(431, "Request Header Fields Too Large").to_bytes());
-            } else if e == "body too large" {
+            } else if e == "body too large" || e == crate::http::CHUNKED_TOO_SLOW {
+                if e == crate::http::CHUNKED_TOO_SLOW {
+                    crate::log::warn(&format!("Closing connection from {ip}: {e}"));
+                }
+                let _ = c.write_all(&HttpResponse::error(413, "Payload Too Large").to_bytes());
+            } else if e == CONN_BYTES_EXCEEDED {
+                crate::log::warn(&format!("Closing connection from {ip}: {e}"));
+                crate::metrics::inc_conn_bytes_exceeded();
                 let _ = c.write_all(&HttpResponse::error(413, "Payload Too Large").to_bytes());
             } else {
+                if e != "connection closed" {
+                    crate::log::warn(&format!("Bad request from {ip}: {e}"));
+                }
                 let _ = c.write_all(&HttpResponse::error(400, "Bad Request").to_bytes());
             }
             crate::metrics::inc_requests_err();
-            return;
+            return false;
         }
     };
 
     crate::metrics::add_bytes_in(raw.len() as u64);
     crate::metrics::inc_requests();
-    let mut req = match HttpRequest::parse(&raw) {
-        Some(r) => r,
-        None => {
+    let mut req = match HttpRequest::parse_checked_with(&raw, limits.normalize_method_case) {
+        Ok(r) => r,
+        Err(reason) => {
+            crate::log::warn(&format!("Bad request from {ip}: {reason}"));
             let _ = c.write_all(&HttpResponse::error(400, "Bad Request").to_bytes());
             crate::metrics::inc_requests_err();
-            return;
+            return false;
         }
     };
 
+    if let Some(resp) = crate::http::check_request_limits(&req.headers) {
+        let _ = c.write_all(&resp.to_bytes());
+        crate::metrics::inc_requests_err();
+        return false;
+    }
+
     if matches!(req.method.as_str(), "POST" | "PUT" | "PATCH")
         && req.get_header("Content-Length").is_none()
         && req.get_header("Transfer-Encoding").is_none()
     {
         let _ = c.write_all(&HttpResponse::error(411, "Length Required").to_bytes());
         crate::metrics::inc_requests_err();
-        return;
+        return false;
     }
 
-    crate::log::request(&req.method, &req.path, &ip);
+    crate::log::request(&req.method, &req.path, ip);
+
+    if limits.shed_on_low_memory && crate::memory::shedding() {
+        let mut resp = HttpResponse::error(503, "Service Unavailable");
+        resp.set_header("Retry-After", "1");
+        resp.set_header("Connection", "close");
+        let _ = c.write_all(&resp.to_bytes());
+        crate::metrics::inc_requests_err();
+        return false;
+    }
 
     let mut ctx = Context::new();
-    ctx.set("_client_ip", ip);
+    ctx.set("_client_ip", ip.to_string());
     ctx.set("_protocol", "h1".to_string());
-    if let Some(ver) = tls_ver {
-        ctx.set("_tls_version", ver.to_string());
+    if let Some(info) = tls_info {
+        info.apply(&mut ctx);
     }
     let mut resp = p.handle(&mut req, &mut ctx);
+    if until.is_some_and(|u| Instant::now() >= u) {
+        crate::log::debug(&format!("Request from {ip} exceeded max_request_duration while processing"));
+        crate::metrics::inc_duration_aborts();
+        resp = HttpResponse::error(504, "Gateway Timeout");
+    }
     let latency = ctx.elapsed_ms() as u64;
     crate::metrics::record_latency(latency);
     if resp.status_code < 400 {
@@ -530,16 +1086,101 @@ fn handle_h1(mut c: ClientStream, p: &Pipeline, buf_size: usize, write_timeout:
     let is_cache_hit = resp.get_header("X-Cache").map(|v| v == "HIT").unwrap_or(false);
     crate::log::response(resp.status_code, ctx.elapsed_ms(), is_cache_hit);
 
-    let out = resp.to_bytes();
-    crate::metrics::add_bytes_out(out.len() as u64);
-    if c.write_all(&out).is_err() {
+    let chunked = ctx.get_bool("_chunked") && req.version == "HTTP/1.1";
+    // A body without a length or chunking is delimited by closing the connection
+    let delimited = chunked || ctx.take::<TcpStream>("_backend_stream").is_none()
+        || resp.get_header("Content-Length").is_some()
+        || resp.get_header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    let keep = more
+        && delimited
+        && crate::http::keep_alive(&req.version, req.get_header("Connection"))
+        && !resp.get_header("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+    if more || reused {
+        resp.set_header("Connection", if keep { "keep-alive" } else { "close" });
+    }
+    if write_response(c, &mut resp, &ctx, chunked, buf_size).is_err() {
         crate::log::warn("Failed to write response to client");
+        return false;
     }
-    let _ = c.shutdown(Shutdown::Write);
-    crate::log::separator();
+    let _ = c.flush();
+    keep
 }
 
-fn build_tls_assets(cfg: &Srv) -> Option<TlsAssets> {
+/// Write `resp` to the client, followed by any unbuffered backend body. With `chunked`
+/// the body is sent with `Transfer-Encoding: chunked` instead of a Content-Length.
+pub fn write_response(
+    out: &mut impl Write,
+    resp: &mut HttpResponse,
+    ctx: &Context,
+    chunked: bool,
+    buf_size: usize,
+) -> std::io::Result<()> {
+    if let Some(heads) = ctx.take::<Vec<Vec<u8>>>("_informational") {
+        for head in heads {
+            out.write_all(head)?;
+            crate::metrics::add_bytes_out(head.len() as u64);
+        }
+    }
+    if !chunked {
+        let bytes = resp.to_bytes();
+        out.write_all(&bytes)?;
+        crate::metrics::add_bytes_out(bytes.len() as u64);
+        let relayed = relay_backend_stream(ctx, out, buf_size)?;
+        crate::metrics::record_response_size(resp.body.len() as u64 + relayed);
+        return Ok(());
+    }
+    resp.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("Content-Length"));
+    resp.set_header("Transfer-Encoding", "chunked");
+    let head = resp.head_bytes();
+    out.write_all(&head)?;
+    crate::metrics::add_bytes_out((head.len() + resp.body.len()) as u64);
+    let mut w = crate::http::ChunkedWriter::new(out);
+    w.write_all(&resp.body)?;
+    let relayed = relay_backend_stream(ctx, &mut w, buf_size)?;
+    crate::metrics::record_response_size(resp.body.len() as u64 + relayed);
+    w.finish()?;
+    Ok(())
+}
+
+/// Relay the remainder of an unbuffered backend response, flushing each read, up to the
+/// end of its framing. Returns the number of body bytes relayed, or an error when the
+/// body was cut short and the client cannot be sent a complete message.
+fn relay_backend_stream(ctx: &Context, out: &mut impl Write, buf_size: usize) -> std::io::Result<u64> {
+    let Some(mut backend) = ctx.take::<TcpStream>("_backend_stream") else { return Ok(0) };
+    let mut framing = ctx.take::<crate::http::BodyFraming>("_backend_framing")
+        .cloned()
+        .unwrap_or(crate::http::BodyFraming::Close);
+    let mut b = vec![0u8; buf_size];
+    let mut total = 0;
+    let mut sent = true;
+    while !framing.ended() {
+        match backend.read(&mut b) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                let n = framing.advance(&b[..n]);
+                if out.write_all(&b[..n]).and_then(|_| out.flush()).is_err() {
+                    sent = false;
+                    break;
+                }
+                crate::metrics::add_bytes_out(n as u64);
+                total += n as u64;
+            }
+        }
+    }
+    // A fully relayed keep-alive response leaves the connection ready for the next request
+    let reusable = framing.complete() && ctx.get_bool("_backend_keep_alive");
+    match (reusable, backend.peer_addr(), backend.try_clone()) {
+        (true, Ok(addr), Ok(s)) => crate::pool::global_pool().put(addr, s),
+        _ => { let _ = backend.shutdown(Shutdown::Both); }
+    }
+    if sent && (framing.complete() || matches!(framing, crate::http::BodyFraming::Close)) {
+        Ok(total)
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "backend body cut short"))
+    }
+}
+
+pub(crate) fn build_tls_assets(cfg: &Srv) -> Option<TlsAssets> {
     if cfg.tls_cert.is_empty() || cfg.tls_key.is_empty() {
         return None;
     }
@@ -562,11 +1203,7 @@ fn build_tls_assets(cfg: &Srv) -> Option<TlsAssets> {
         }
     };
 
-    if cfg.http2 {
-        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
-    } else {
-        config.alpn_protocols = vec![b"http/1.1".to_vec()];
-    }
+    config.alpn_protocols = cfg.alpn_list();
 
     config.session_storage = rustls::server::ServerSessionMemoryCache::new(2048);
 
@@ -635,7 +1272,7 @@ fn load_private_key(path: &str) -> Option<rustls::pki_types::PrivateKeyDer<'stat
     None
 }
 
-fn build_h3_endpoint(
+pub(crate) fn build_h3_endpoint(
     certs: &[rustls::pki_types::CertificateDer<'static>],
     key: &rustls::pki_types::PrivateKeyDer<'static>,
     listen_addr: &str,
@@ -668,6 +1305,12 @@ fn install_shutdown_handler(listen_addr: &str) {
     thread::spawn(move || {
         loop {
             thread::sleep(Duration::from_millis(200));
+            if DRAINING.load(Ordering::Acquire) && !SHUTDOWN.load(Ordering::Acquire) {
+                let delay = PRE_DRAIN_DELAY_SECS.load(Ordering::Acquire);
+                crate::log::info(&format!("Shutdown requested: failing readiness for {delay}s before draining"));
+                thread::sleep(Duration::from_secs(delay));
+                SHUTDOWN.store(true, Ordering::Release);
+            }
             if SHUTDOWN.load(Ordering::Acquire) {
                 if let Ok(sa) = addr.parse::<std::net::SocketAddr>() {
                     let _ = TcpStream::connect_timeout(&sa, Duration::from_millis(100));
@@ -680,7 +1323,7 @@ fn install_shutdown_handler(listen_addr: &str) {
     #[cfg(windows)]
     {
         extern "system" fn ctrl_handler(_ctrl_type: u32) -> i32 {
-            SHUTDOWN.store(true, Ordering::Release);
+            request_shutdown();
             1
         }
         extern "system" {
@@ -699,7 +1342,7 @@ fn install_shutdown_handler(listen_addr: &str) {
         static PIPE_WR: AtomicI32 = AtomicI32::new(-1);
 
         extern "C" fn sig_handler(_sig: libc::c_int) {
-            SHUTDOWN.store(true, Ordering::Release);
+            request_shutdown();
             let fd = PIPE_WR.load(Ordering::Relaxed);
             if fd >= 0 {
                 unsafe { libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1); }
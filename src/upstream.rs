@@ -0,0 +1,86 @@
+// Named upstream groups from `[upstreams.<name>]`, selected by scripts with std.use_upstream
+use crate::modules::helpers as h;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+static REGISTRY: OnceLock<RwLock<HashMap<String, Arc<Upstream>>>> = OnceLock::new();
+
+fn registry() -> &'static RwLock<HashMap<String, Arc<Upstream>>> {
+    REGISTRY.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+enum Strategy {
+    RoundRobin,
+    // Fewest backend connections currently in use, from the connection pool
+    LeastBusy,
+}
+
+struct Upstream {
+    // Address and per-backend timeout override, as in load_balancer backends
+    backends: Vec<(String, Option<u64>)>,
+    strategy: Strategy,
+    idx: AtomicUsize,
+}
+
+impl Upstream {
+    fn parse(name: &str, v: &toml::Value) -> Option<Upstream> {
+        let backends: Vec<(String, Option<u64>)> = v.get("backends").and_then(|b| b.as_array())
+            .map(|a| a.iter().filter_map(|b| b.as_str()).map(h::parse_backend).collect())
+            .unwrap_or_default();
+        if backends.is_empty() {
+            crate::log::warn(&format!("upstreams: '{name}' has no backends, skipping"));
+            return None;
+        }
+        let strategy = match v.get("strategy").and_then(|s| s.as_str()).unwrap_or("round_robin") {
+            "round_robin" => Strategy::RoundRobin,
+            "least_busy" => Strategy::LeastBusy,
+            other => {
+                crate::log::warn(&format!("upstreams: '{name}' strategy '{other}' unknown, using round_robin"));
+                Strategy::RoundRobin
+            }
+        };
+        Some(Upstream { backends, strategy, idx: AtomicUsize::new(0) })
+    }
+
+    /// A healthy backend by the group's strategy; with none healthy, the next in turn
+    fn pick(&self) -> &(String, Option<u64>) {
+        let len = self.backends.len();
+        let start = self.idx.fetch_add(1, Ordering::Relaxed) % len;
+        let mut healthy = (0..len)
+            .map(|offset| &self.backends[(start + offset) % len])
+            .filter(|(addr, _)| h::backend_healthy(addr));
+        let chosen = match self.strategy {
+            Strategy::RoundRobin => healthy.next(),
+            Strategy::LeastBusy => healthy.min_by_key(|(addr, _)| in_use(addr)),
+        };
+        chosen.unwrap_or(&self.backends[start])
+    }
+}
+
+fn in_use(addr: &str) -> usize {
+    crate::resolve::resolve(addr).map_or(0, |a| crate::pool::global_pool().usage(&a).0)
+}
+
+/// Replace the registry with the groups in `upstreams`
+pub fn configure(upstreams: &HashMap<String, toml::Value>) {
+    let parsed: HashMap<String, Arc<Upstream>> = upstreams.iter()
+        .filter_map(|(name, v)| Upstream::parse(name, v).map(|u| (name.clone(), Arc::new(u))))
+        .collect();
+    if !parsed.is_empty() {
+        crate::log::info(&format!("Upstreams: {} group(s)", parsed.len()));
+    }
+    match registry().write() {
+        Ok(mut g) => *g = parsed,
+        Err(poisoned) => *poisoned.into_inner() = parsed,
+    }
+}
+
+/// Backend address and timeout override chosen from the named group
+pub fn select(name: &str) -> Option<(String, Option<u64>)> {
+    let up = match registry().read() {
+        Ok(g) => g.get(name).cloned(),
+        Err(poisoned) => poisoned.into_inner().get(name).cloned(),
+    }?;
+    Some(up.pick().clone())
+}